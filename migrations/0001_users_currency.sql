@@ -0,0 +1,2 @@
+-- default display currency of user
+ALTER TABLE users ADD COLUMN currency VARCHAR(8) NOT NULL DEFAULT 'CNY';
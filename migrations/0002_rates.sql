@@ -0,0 +1,7 @@
+-- daily exchange rates, units of currency per 1 USD
+CREATE TABLE rates (
+  currency VARCHAR(8) NOT NULL,
+  day BIGINT NOT NULL,
+  rate DOUBLE NOT NULL,
+  PRIMARY KEY (currency, day)
+);
@@ -0,0 +1,13 @@
+-- buy and sell records with the price paid, used for cost basis and profit
+CREATE TABLE transactions (
+  id BIGINT NOT NULL AUTO_INCREMENT,
+  user_id BIGINT NOT NULL,
+  coin_id VARCHAR(64) NOT NULL,
+  kind VARCHAR(16) NOT NULL,
+  amount DOUBLE NOT NULL,
+  price_usd DOUBLE NOT NULL DEFAULT 0,
+  fee_usd DOUBLE NOT NULL DEFAULT 0,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (id),
+  KEY user_coin_created (user_id, coin_id, created)
+);
@@ -0,0 +1,4 @@
+-- wallets of ledger transactions
+ALTER TABLE transactions
+  ADD COLUMN wallet VARCHAR(64) NOT NULL DEFAULT '' AFTER fee_usd,
+  ADD COLUMN to_wallet VARCHAR(64) NOT NULL DEFAULT '' AFTER wallet;
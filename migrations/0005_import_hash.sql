@@ -0,0 +1,4 @@
+-- fingerprint of imported csv rows, to detect re-imports
+ALTER TABLE transactions
+  ADD COLUMN import_hash CHAR(40) NULL,
+  ADD UNIQUE KEY user_import_hash (user_id, import_hash);
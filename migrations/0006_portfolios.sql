@@ -0,0 +1,13 @@
+-- named portfolios, rows with portfolio_id 0 belong to the user's default portfolio
+CREATE TABLE portfolios (
+  id BIGINT NOT NULL AUTO_INCREMENT,
+  user_id BIGINT NOT NULL,
+  name VARCHAR(64) NOT NULL,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (id),
+  KEY user_id (user_id)
+);
+
+ALTER TABLE states ADD COLUMN portfolio_id BIGINT NOT NULL DEFAULT 0 AFTER user_id;
+ALTER TABLE balance ADD COLUMN portfolio_id BIGINT NOT NULL DEFAULT 0 AFTER user_id;
+ALTER TABLE transactions ADD COLUMN portfolio_id BIGINT NOT NULL DEFAULT 0 AFTER user_id;
@@ -0,0 +1,9 @@
+-- users invited to view or edit a portfolio, rows of the portfolio still belong to its owner
+CREATE TABLE portfolio_members (
+  portfolio_id BIGINT NOT NULL,
+  user_id BIGINT NOT NULL,
+  role VARCHAR(8) NOT NULL,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (portfolio_id, user_id),
+  KEY user_id (user_id)
+);
@@ -0,0 +1,16 @@
+-- alert rules evaluated after every coins refresh
+CREATE TABLE alerts (
+  id BIGINT NOT NULL AUTO_INCREMENT,
+  user_id BIGINT NOT NULL,
+  kind VARCHAR(16) NOT NULL,
+  coin_id VARCHAR(64) NOT NULL DEFAULT '',
+  portfolio_id BIGINT NULL,
+  currency CHAR(3) NOT NULL,
+  threshold DOUBLE NOT NULL,
+  cooldown BIGINT NOT NULL DEFAULT 3600,
+  armed TINYINT NOT NULL DEFAULT 1,
+  last_fired BIGINT NOT NULL DEFAULT 0,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (id),
+  KEY user_id (user_id)
+);
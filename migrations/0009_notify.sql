@@ -0,0 +1,4 @@
+-- where notifications of a user go, sms to the mobile is the fallback
+ALTER TABLE users ADD COLUMN email VARCHAR(128) NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN webhook_url VARCHAR(255) NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN notify_channel VARCHAR(8) NOT NULL DEFAULT 'sms';
@@ -0,0 +1,3 @@
+-- opt-in scheduled digest: off, daily or weekly
+ALTER TABLE users ADD COLUMN digest VARCHAR(8) NOT NULL DEFAULT 'off';
+ALTER TABLE users ADD COLUMN digest_sent BIGINT NOT NULL DEFAULT 0;
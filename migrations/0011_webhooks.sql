@@ -0,0 +1,31 @@
+-- outgoing webhook subscriptions, events is a comma separated list
+CREATE TABLE webhooks (
+  id BIGINT NOT NULL AUTO_INCREMENT,
+  user_id BIGINT NOT NULL,
+  url VARCHAR(255) NOT NULL,
+  secret VARCHAR(64) NOT NULL,
+  events VARCHAR(255) NOT NULL,
+  active TINYINT NOT NULL DEFAULT 1,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (id),
+  KEY user_id (user_id)
+);
+
+-- one row per event per subscription, status is pending, delivered or failed
+CREATE TABLE webhook_deliveries (
+  id BIGINT NOT NULL AUTO_INCREMENT,
+  webhook_id BIGINT NOT NULL,
+  user_id BIGINT NOT NULL,
+  event VARCHAR(32) NOT NULL,
+  payload TEXT NOT NULL,
+  status VARCHAR(16) NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  next_attempt BIGINT NOT NULL,
+  response_code INT NOT NULL DEFAULT 0,
+  response_body TEXT NOT NULL,
+  delivered BIGINT NOT NULL DEFAULT 0,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (id),
+  KEY webhook_id (webhook_id),
+  KEY status (status, next_attempt)
+);
@@ -0,0 +1,5 @@
+-- client info of sessions for listing them
+ALTER TABLE _session ADD COLUMN user_agent VARCHAR(255) NOT NULL DEFAULT '';
+ALTER TABLE _session ADD COLUMN ip VARCHAR(64) NOT NULL DEFAULT '';
+ALTER TABLE _session ADD KEY user_id (user_id);
+ALTER TABLE _session ADD KEY accessed (accessed);
@@ -0,0 +1,12 @@
+-- rotating refresh tokens, only the sha256 of a token is kept
+CREATE TABLE refresh_tokens (
+  id BIGINT NOT NULL AUTO_INCREMENT,
+  session_id VARCHAR(64) NOT NULL,
+  token_hash CHAR(64) NOT NULL,
+  used TINYINT NOT NULL DEFAULT 0,
+  created BIGINT NOT NULL,
+  expires BIGINT NOT NULL,
+  PRIMARY KEY (id),
+  UNIQUE KEY token_hash (token_hash),
+  KEY session_id (session_id)
+);
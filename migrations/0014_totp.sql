@@ -0,0 +1,22 @@
+-- totp authenticators, pending until the first code is confirmed
+CREATE TABLE totp (
+  user_id BIGINT NOT NULL,
+  secret VARCHAR(64) NOT NULL,
+  enabled TINYINT NOT NULL DEFAULT 0,
+  last_step BIGINT NOT NULL DEFAULT 0,
+  err_times INT NOT NULL DEFAULT 0,
+  failed BIGINT NOT NULL DEFAULT 0,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (user_id)
+);
+-- one-time recovery codes, only the sha256 of a code is kept
+CREATE TABLE recovery_codes (
+  id BIGINT NOT NULL AUTO_INCREMENT,
+  user_id BIGINT NOT NULL,
+  code_hash CHAR(64) NOT NULL,
+  used TINYINT NOT NULL DEFAULT 0,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (id),
+  KEY user_id (user_id)
+);
+ALTER TABLE _session ADD COLUMN stepped_up BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,16 @@
+-- email and password logins, user_id is 0 until an email signing up is verified
+CREATE TABLE email_logins (
+  email VARCHAR(128) NOT NULL,
+  user_id BIGINT NOT NULL DEFAULT 0,
+  name VARCHAR(64) NOT NULL DEFAULT '',
+  password_hash VARCHAR(255) NOT NULL,
+  verified TINYINT NOT NULL DEFAULT 0,
+  verify_hash CHAR(64) NOT NULL DEFAULT '',
+  verify_expires BIGINT NOT NULL DEFAULT 0,
+  err_times INT NOT NULL DEFAULT 0,
+  failed BIGINT NOT NULL DEFAULT 0,
+  created BIGINT NOT NULL,
+  PRIMARY KEY (email),
+  KEY user_id (user_id),
+  KEY verify_hash (verify_hash)
+);
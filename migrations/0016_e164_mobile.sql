@@ -0,0 +1,7 @@
+-- mobile numbers are stored in E.164, the existing ones are all of mainland china
+ALTER TABLE users MODIFY mobile VARCHAR(16) NOT NULL;
+ALTER TABLE sms MODIFY mobile VARCHAR(16) NOT NULL;
+ALTER TABLE _session MODIFY mobile VARCHAR(16) NOT NULL;
+UPDATE users SET mobile=CONCAT('+86',mobile) WHERE mobile REGEXP '^1[0-9]{10}$';
+UPDATE sms SET mobile=CONCAT('+86',mobile) WHERE mobile REGEXP '^1[0-9]{10}$';
+UPDATE _session SET mobile=CONCAT('+86',mobile) WHERE mobile REGEXP '^1[0-9]{10}$';
@@ -0,0 +1,2 @@
+-- states merged into the ledger as opening, deposit and withdrawal transactions
+ALTER TABLE states ADD COLUMN migrated TINYINT NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- every ledger entry gets a random fingerprint, it is exported and restored with the entry so
+-- that restoring an export, even into another account or instance, doesn't duplicate it
+UPDATE transactions SET import_hash=SHA1(CONCAT(UUID(),'|',id)) WHERE import_hash IS NULL;
@@ -0,0 +1,2 @@
+-- responses of webhook receivers are not kept, only their status codes
+ALTER TABLE webhook_deliveries DROP COLUMN response_body;
@@ -1,3 +1,4 @@
+use std::error::Error;
 use time;
 use std::collections::BTreeMap;
 use uuid::Uuid;
@@ -15,7 +16,7 @@ pub struct SmsBody {
     pub out_id: String,
 }
 
-pub fn sms_api(sms: SmsBody) {
+pub fn sms_api(sms: SmsBody) -> Result<(), Box<Error>> {
     let SmsBody {
         key_id,
         key_secret,
@@ -75,8 +76,12 @@ pub fn sms_api(sms: SmsBody) {
         query_string = query_string
     );
 
-    let ret = utils::request_json(url, None).unwrap();
-    println!("{:?}", ret);
+    let ret = utils::request_json(url, None)?;
+    debug!("Sms api: {} {}", ret["Code"], ret["RequestId"]);
+    match ret["Code"].as_str() {
+        Some("OK") => Ok(()),
+        _ => Err(From::from(format!("sms api error: {}", ret))),
+    }
 }
 
 fn sign(secret: &str, query_string: &str) -> String {
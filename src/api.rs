@@ -3,23 +3,96 @@ use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use time;
 use mysql::Pool;
-use rocket::State;
-use rocket::response::status;
-use rocket_contrib::{Json, Value};
-use regex::Regex;
+use rocket::{Config, State};
+use rocket::request::Form;
+use rocket::response::{status, content, Redirect};
+use rocket::http::{ContentType, Cookie, Cookies, SameSite};
+use rocket_contrib::{Json, Template, Value};
 
 use worker;
-use models::{self, QueryString, Session, SmsFactory};
-use error::E;
+use models::{self, Alert, AlertKind, ClientInfo, EmailLogin, QueryString, Role, Session,
+             SmsFactory, Transaction, TxKind, User};
+use phone::Phone;
+use pnl::{self, Pnl};
+use import::{self, Layout, Mapping};
+use notify::{Channel, Message, Recipient};
+use digest::Period;
+use webhook;
+use token::TokenKeys;
+use totp;
+use utils;
+use csv;
+use error::{self, Locale, E};
+
+/// pick the display currency from `currency` in query string, or the user's default one,
+/// and return it with its rate to usd
+fn currency_rate(
+    qs: &QueryString,
+    user: &User,
+    worker_state: &worker::State,
+) -> Result<(String, f64), E> {
+    let currency = match qs.get("currency") {
+        Some(currency) => currency.to_uppercase(),
+        None => user.currency.clone(),
+    };
+    let rate = worker_state.rate(&currency).ok_or(E::CurrencyNotSupported)?;
+
+    Ok((currency, rate))
+}
+
+/// `portfolio` id in query string, None means all portfolios of the user
+fn portfolio_param(qs: &QueryString, mysql_pool: &Pool, user: &User) -> Result<Option<i64>, E> {
+    match qs.get("portfolio") {
+        Some(id) => {
+            let id = id.parse::<i64>().map_err(|_| E::PortfolioNotFound)?;
+            user.check_portfolio(mysql_pool, id)?;
+            Ok(Some(id))
+        }
+        None => Ok(None),
+    }
+}
+
+/// `mobile` in body as a phone number, national numbers are of `country_code` in body or
+/// `default_country_code` in Rocket.toml
+fn phone_param(data: &Value, config: &Config) -> Result<Phone, E> {
+    let mobile = data["mobile"].as_str().ok_or(E::SmsMobileInvalid)?;
+    let country_code = match data["country_code"].as_u64() {
+        Some(code) => code as u16,
+        None => config.get_int("default_country_code").unwrap_or(86) as u16,
+    };
+
+    Phone::parse(mobile, country_code)
+}
+
+/// required string field of json body
+fn str_field<'a>(data: &'a Value, field: &str) -> Result<&'a str, E> {
+    data[field]
+        .as_str()
+        .ok_or_else(|| E::ParamInvalid(field.to_string()))
+}
+
+/// required integer field of json body
+fn i64_field(data: &Value, field: &str) -> Result<i64, E> {
+    data[field]
+        .as_i64()
+        .ok_or_else(|| E::ParamInvalid(field.to_string()))
+}
+
+/// required number field of json body
+fn f64_field(data: &Value, field: &str) -> Result<f64, E> {
+    data[field]
+        .as_f64()
+        .ok_or_else(|| E::ParamInvalid(field.to_string()))
+}
 
 #[error(502)]
 fn bad_gateway() -> E {
-    E::Unknown
+    E::Upstream("bad gateway".to_string())
 }
 
 #[error(500)]
 fn internal_server_error() -> E {
-    E::Unknown
+    E::Internal("internal server error".to_string())
 }
 
 #[error(400)]
@@ -27,13 +100,20 @@ fn bad_request() -> E {
     E::Unknown
 }
 
+/// the Session guard failed and saved the reason
+#[error(401)]
+fn unauthorized() -> E {
+    error::take_session_error().unwrap_or(E::AccessTokenInvalid)
+}
+
 /// ### send authorization sms
 /// - /api/sms
 /// - Content-Type: application/json
 /// - post
 /// ```js
 /// {
-///     "mobile": "1xxxxxxxxxx"
+///     "mobile": "+447700900123", //E.164, or a national number like "13800000000"
+///     "country_code": 44 //optional, country of a national number, 86 by default
 /// }
 /// ```
 /// - http 200:
@@ -42,25 +122,25 @@ fn bad_request() -> E {
 ///     "interval": 123
 /// }
 /// ```
-/// - http 400:
+/// - http 4xx or 5xx, the status depends on the error, e.g. 429 for sending too often:
 /// ```js
 /// {
 ///     "err": 123,
-///     "msg": "error message"
+///     "msg": "error message", //in the language of Accept-Language
+///     "request_id": "xxxx", //also in X-Request-Id header
+///     "details": {"retry_after": 60} //optional, like the invalid field of body
 /// }
 /// ```
 #[post("/sms", format = "application/json", data = "<data>")]
 fn sms(
     data: Json<Value>,
     mysql_pool: State<Pool>,
+    config: State<Config>,
     sms_fac_lock: State<Mutex<SmsFactory>>,
 ) -> Result<Json<Value>, E> {
     let sms_fac = sms_fac_lock.lock()?;
-    let mobile = data["mobile"].as_str()?;
-    if !Regex::new(r"^1\d{10}$")?.is_match(mobile) {
-        return Err(E::SmsMobileInvalid);
-    }
-    let interval = sms_fac.gen_code(&mysql_pool, mobile)?;
+    let phone = phone_param(&data, &config)?;
+    let interval = sms_fac.gen_code(&mysql_pool, &phone)?;
 
     Ok(Json(json!({
         "interval": interval,
@@ -71,9 +151,12 @@ fn sms(
 /// - /api/sms/auth
 /// - Content-Type: application/json
 /// - post
+/// - the access token is also set in a private cookie. Other apis accept it from
+///   `Authorization: Bearer {access_token}` header, the cookie, or `access_token` in query
+///   string, in that order.
 /// ```js
 /// {
-///     "mobile": "1xxxxxxxxxx",
+///     "mobile": "+8613800000000", //same as /api/sms
 ///     "code": 1234
 /// }
 /// ```
@@ -85,7 +168,9 @@ fn sms(
 ///         "name": "user name",
 ///         "is_signup": true
 ///     },
-///     "access_token": "xxxx"
+///     "access_token": "xxxx", //short-lived, renew it by /api/token/refresh
+///     "expires_in": 900, //seconds
+///     "refresh_token": "xxxx"
 /// }
 /// ```
 /// - http 400:
@@ -99,280 +184,260 @@ fn sms(
 fn sms_auth(
     data: Json<Value>,
     mysql_pool: State<Pool>,
+    token_keys: State<TokenKeys>,
+    config: State<Config>,
+    mut cookies: Cookies,
+    client: ClientInfo,
     sms_fac_lock: State<Mutex<SmsFactory>>,
 ) -> Result<Json<Value>, E> {
     let sms_fac = sms_fac_lock.lock()?;
-    let mobile = data["mobile"].as_str()?;
-    let code = data["code"].as_i64()?;
-    if !Regex::new(r"^1\d{10}$")?.is_match(mobile) {
-        return Err(E::SmsMobileInvalid);
-    }
+    let mobile = phone_param(&data, &config)?.e164();
+    let code = i64_field(&data, "code")?;
 
     sms_fac
-        .check_code(&mysql_pool, mobile, code as u32)
+        .check_code(&mysql_pool, &mobile, code as u32)
         .and_then(|_| {
             // create session
-            let sess = Session::new(&mysql_pool, mobile)?;
-            Ok(Json(json!({
-            "access_token": Session::id_to_access_token(&sess.id)?
-        })))
+            let sess = Session::new(&mysql_pool, &mobile, &client)?;
+            grant(&sess, &mysql_pool, &token_keys, &config, &mut cookies).map(Json)
         })
 }
 
-/// ### fetch session owner's info
-/// - /api/me?access_token={access_token}
+/// mail the link verifying an email login
+fn send_verify(sms_fac: &SmsFactory, config: &Config, email: &str, token: &str) -> Result<(), E> {
+    let link = format!(
+        "{}/api/email/verify?token={}",
+        config
+            .get_str("public_url")
+            .unwrap_or("http://localhost:8000")
+            .trim_right_matches('/'),
+        utils::query_quote(token)
+    );
+
+    sms_fac.send(
+        vec![Recipient::new(Channel::Email, email)],
+        Message::Verify { link: link },
+    )
+}
+
+/// ### sign up by email and password
+/// - /api/email/signup
 /// - Content-Type: application/json
-/// - get
-/// - http 200:
+/// - post
+/// - a verification link is mailed, login works once it's opened. Signing up again before
+///   that replaces the password and sends a new link.
 /// ```js
 /// {
-///     "id": 123,
-///     "name": "abc",
-///     "created": 123
+///     "email": "abc@example.com",
+///     "password": "at least 8 chars",
+///     "name": "abc"
 /// }
 /// ```
-/// - http 400:
+/// - http 200:
 /// ```js
 /// {
-///     "err": 123,
-///     "msg": "error message"
+///     "email": "abc@example.com"
 /// }
 /// ```
-#[get("/me")]
-fn me_get(
-    qs: QueryString,
+#[post("/email/signup", format = "application/json", data = "<data>")]
+fn email_signup(
+    data: Json<Value>,
     mysql_pool: State<Pool>,
-    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    config: State<Config>,
+    sms_fac_lock: State<Mutex<SmsFactory>>,
 ) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let worker_state = &*(worker_state_lock.read().unwrap());
+    let email = EmailLogin::normalize(str_field(&data, "email")?)?;
+    let password = str_field(&data, "password")?;
+    let name = match data["name"].as_str() {
+        Some(name) if !name.trim().is_empty() => name.trim(),
+        _ => email.split('@').next()?,
+    };
+    let token = EmailLogin::register(&mysql_pool, &email, password, name, 0)?;
+    send_verify(&*sms_fac_lock.lock()?, &config, &email, &token)?;
+
     Ok(Json(json!({
-        "id": user.id,
-        "name": user.name,
-        "created": user.created,
-        "usd2cny_rate": worker_state.usd2cny_rate
+        "email": email,
     })))
 }
 
-/// ### register session owner
-/// - /api/me?access_token={access_token}
+/// ### add email login to the session owner, who signed up by sms
+/// - /api/me/email
 /// - Content-Type: application/json
 /// - post
+/// - a verification link is mailed, same as /api/email/signup
 /// ```js
 /// {
-///     "name": "abc"
-/// }
-/// ```
-/// - http 200:
-/// ```js
-/// {
-///     "id": 123,
-///     "name": "abc",
-///     "created": 123
-/// }
-/// ```
-/// - http 400:
-/// ```js
-/// {
-///     "err": 123,
-///     "msg": "error message"
+///     "email": "abc@example.com",
+///     "password": "at least 8 chars"
 /// }
 /// ```
-#[post("/me", data = "<data>")]
-fn me_post(qs: QueryString, mysql_pool: State<Pool>, data: Json<Value>) -> Result<Json<Value>, E> {
-    let mut sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let name = data["name"].as_str()?;
-    sess.signup(&mysql_pool, name)?;
+#[post("/me/email", format = "application/json", data = "<data>")]
+fn me_email(
+    sess: Session,
+    data: Json<Value>,
+    mysql_pool: State<Pool>,
+    config: State<Config>,
+    sms_fac_lock: State<Mutex<SmsFactory>>,
+) -> Result<Json<Value>, E> {
     let user = sess.user()?;
+    let email = EmailLogin::normalize(str_field(&data, "email")?)?;
+    let password = str_field(&data, "password")?;
+    let token = EmailLogin::register(&mysql_pool, &email, password, &user.name, user.id)?;
+    send_verify(&*sms_fac_lock.lock()?, &config, &email, &token)?;
+
     Ok(Json(json!({
-        "id": user.id,
-        "name": user.name,
-        "created": user.created
+        "email": email,
     })))
 }
 
-/// ### user current coins states
-/// - /api/states?access_token={access_token}
-/// - Content-Type: application/json
+/// ### verify email by the mailed link
+/// - /api/email/verify?token={token}
 /// - get
 /// - http 200:
 /// ```js
 /// {
-///     "balance": 123,
-///     "states":
-///     [
-///       {
-///         "coin_id": "abc",
-///         "amount": 12.3,
-///         "created": 123,
-///         "value_cny": 12.3, //invalid state if this is None
-///         "coin": { //invalid state if this is None
-///             "id": "abc",
-///             "name": "abc",
-///             "symbol": "abc",
-///             "price_usd": 12.3,
-///             "volume_usd": 12.3,
-///             "market_cap_usd": 12.3,
-///             "percent_change_24h": 12.3, //percent
-///             "rank": 123
-///         }
-///       },
-///       ...
-///     ]
-/// }
-/// ```
-/// - http 400:
-/// ```js
-/// {
-///     "err": 123,
-///     "msg": "error message"
+///     "email": "abc@example.com",
+///     "user_id": 123
 /// }
 /// ```
-#[get("/states")]
-fn states(
-    qs: QueryString,
-    mysql_pool: State<Pool>,
-    worker_state_lock: State<Arc<RwLock<worker::State>>>,
-) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let worker_state = &*(worker_state_lock.read().unwrap());
-    let balance = user.balance(&mysql_pool)?;
-    let mut user_states = user.states(&mysql_pool, worker_state, None)?;
-    user_states.reverse();
-
-    let mut rt_states_list = vec![];
-    let mut got_coins = vec![];
-    for state in user_states {
-        if !got_coins.contains(&state.coin_id) {
-            got_coins.push(state.coin_id.clone());
-            let coin = state.coin.unwrap();
-            let coin_json = json!({
-                "id": coin.id.clone(),
-                "name": coin.name.clone(),
-                "symbol": coin.symbol.clone(),
-                "price_usd": coin.price_usd,
-                "volume_usd": coin.volume_usd,
-                "market_cap_usd": coin.market_cap_usd,
-                "percent_change_24h": coin.percent_change_24h,
-                "rank": coin.rank,
-                "no": coin.no,
-            });
-            rt_states_list.push(json!({
-                "coin_id": state.coin_id,
-                "amount": state.amount,
-                "created": state.created,
-                "value_cny": coin.price_usd * state.amount * worker_state.usd2cny_rate,
-                "coin": coin_json
-            }));
-        }
-    }
-
-    let sum = rt_states_list
-        .iter()
-        .fold(0.0, |acc, x| acc + x["value_cny"].as_f64().unwrap());
-    println!("Sum: ￥{}", sum);
+#[get("/email/verify")]
+fn email_verify(qs: QueryString, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    let token = qs.get("token").ok_or(E::EmailTokenInvalid)?;
+    let (email, user_id) = EmailLogin::verify(&mysql_pool, token)?;
 
     Ok(Json(json!({
-        "balance": balance,
-        "states": rt_states_list,
+        "email": email,
+        "user_id": user_id,
     })))
 }
 
-#[get("/states/<coin_id>")]
-fn coin_states(
-    qs: QueryString,
-    mysql_pool: State<Pool>,
-    worker_state_lock: State<Arc<RwLock<worker::State>>>,
-    coin_id: String,
-) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let worker_state = &*(worker_state_lock.read().unwrap());
-    let ret = user.states(&mysql_pool, worker_state, Some(&coin_id))?;
-    let data: Vec<Value> = ret.iter().map(|record| {
-        json!({
-            "id": record.id,
-            "amount": record.amount,
-            "created": record.created,
-        })
-    }).collect();
-
-    Ok(Json(json!(data)))
+#[derive(FromForm)]
+struct LoginForm {
+    username: String,
+    password: String,
 }
 
-#[put("/states", format = "application/json", data = "<data>")]
-fn put_states(
-    qs: QueryString,
-    mysql_pool: State<Pool>,
-    data: Json<Value>,
-) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let id = data["id"].as_i64()?;
-    let coin_id = data["coin_id"].as_str()?;
-    let created = data["created"].as_i64()?;
-    let amount = data["amount"].as_f64()?;
-    user.put_states(&mysql_pool, id, coin_id, created, amount)?;
-
-    Ok(Json(json!(null)))
+/// login page posting to /api/login
+#[get("/login")]
+fn login_page() -> Template {
+    Template::render("login", json!({}))
 }
 
-#[delete("/states", format = "application/json", data = "<data>")]
-fn delete_states(
-    qs: QueryString,
-    mysql_pool: State<Pool>,
+/// ### login by email and password
+/// - /api/login
+/// - Content-Type: application/json
+/// - post
+/// ```js
+/// {
+///     "email": "abc@example.com",
+///     "password": "xxxx"
+/// }
+/// ```
+/// - http 200, same as /api/sms/auth
+#[post("/login", format = "application/json", data = "<data>")]
+fn login(
     data: Json<Value>,
+    mysql_pool: State<Pool>,
+    token_keys: State<TokenKeys>,
+    config: State<Config>,
+    mut cookies: Cookies,
+    client: ClientInfo,
 ) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let id = data["id"].as_i64()?;
-    user.del_states(&mysql_pool, id)?;
+    let email = EmailLogin::normalize(str_field(&data, "email")?)?;
+    let password = str_field(&data, "password")?;
+    let user = EmailLogin::login(&mysql_pool, &email, password)?;
 
-    Ok(Json(json!(null)))
+    let sess = Session::for_user(&mysql_pool, user, &client)?;
+    grant(&sess, &mysql_pool, &token_keys, &config, &mut cookies).map(Json)
 }
 
-#[put("/balance", format = "application/json", data = "<data>")]
-fn put_balance(
-    qs: QueryString,
+/// the form of templates/login.html.hbs, the username is the email. Tokens are set in cookies
+/// and it redirects to home, or shows the login page again with the error.
+#[post("/login", format = "application/x-www-form-urlencoded", data = "<form>", rank = 2)]
+fn login_form(
+    form: Form<LoginForm>,
     mysql_pool: State<Pool>,
-    data: Json<Value>,
-) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let id = data["id"].as_i64()?;
-    let created = data["created"].as_i64()?;
-    let amount = data["amount"].as_f64()?;
-    user.put_balance(&mysql_pool, id, created, amount)?;
-
-    Ok(Json(json!(null)))
+    token_keys: State<TokenKeys>,
+    config: State<Config>,
+    mut cookies: Cookies,
+    client: ClientInfo,
+    locale: Locale,
+) -> Result<Redirect, Template> {
+    let form = form.get();
+    EmailLogin::normalize(&form.username)
+        .and_then(|email| EmailLogin::login(&mysql_pool, &email, &form.password))
+        .and_then(|user| Session::for_user(&mysql_pool, user, &client))
+        .and_then(|sess| grant(&sess, &mysql_pool, &token_keys, &config, &mut cookies))
+        .map(|_| Redirect::to("/"))
+        .map_err(|e| Template::render("login", json!({ "flash": e.message(locale.0) })))
 }
 
-#[delete("/balance", format = "application/json", data = "<data>")]
-fn delete_balance(
-    qs: QueryString,
-    mysql_pool: State<Pool>,
-    data: Json<Value>,
-) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let id = data["id"].as_i64()?;
-    user.del_balance(&mysql_pool, id)?;
+/// issue a short-lived access token and a refresh token of the session, both are also set in
+/// private cookies
+fn grant(
+    sess: &Session,
+    mysql_pool: &Pool,
+    token_keys: &TokenKeys,
+    config: &Config,
+    cookies: &mut Cookies,
+) -> Result<Value, E> {
+    let now = time::get_time().sec;
+    let access_ttl = config.get_int("access_token_ttl_secs").unwrap_or(900);
+    let refresh_ttl = config.get_int("refresh_token_ttl_days").unwrap_or(30) * 86400;
+    let access_token = token_keys.sign(&sess.id, now + access_ttl);
+    let refresh_token = sess.issue_refresh_token(mysql_pool, refresh_ttl)?;
 
-    Ok(Json(json!(null)))
+    let cookie = |name: &'static str, value: &str, path: &'static str, max_age: time::Duration| {
+        let mut cookie = Cookie::build(name, value.to_string())
+            .path(path)
+            .max_age(max_age)
+            .http_only(true)
+            .secure(!config.environment.is_dev())
+            .same_site(SameSite::Strict);
+        if let Ok(domain) = config.get_str("cookie_domain") {
+            cookie = cookie.domain(domain.to_string());
+        }
+        cookie.finish()
+    };
+    cookies.add_private(cookie(
+        Session::COOKIE_NAME,
+        &access_token,
+        "/",
+        time::Duration::hours(config.get_int("cookie_max_age_hours").unwrap_or(48)),
+    ));
+    // only sent to the refresh api
+    cookies.add_private(cookie(
+        Session::REFRESH_COOKIE_NAME,
+        &refresh_token,
+        "/api/token/refresh",
+        time::Duration::seconds(refresh_ttl),
+    ));
+
+    Ok(json!({
+        "access_token": access_token,
+        "expires_in": access_ttl,
+        "refresh_token": refresh_token,
+    }))
 }
 
-/// ### user portfolio historical value
-/// - /api/states/history?access_token={access_token}
+/// ### get a new access token by refresh token
+/// - /api/token/refresh
 /// - Content-Type: application/json
-/// - get
+/// - post
+/// - the refresh token is read from the body, or from the cookie set by /api/sms/auth. It's
+///   used only once, a new one is returned. Replaying a used refresh token revokes the session.
+/// ```js
+/// {
+///     "refresh_token": "xxxx"
+/// }
+/// ```
 /// - http 200:
 /// ```js
-/// [
-///     [123, 12.3],
-///     ...
-/// ]
+/// {
+///     "access_token": "xxxx",
+///     "expires_in": 900, //seconds
+///     "refresh_token": "xxxx"
+/// }
 /// ```
 /// - http 400:
 /// ```js
@@ -381,68 +446,869 @@ fn delete_balance(
 ///     "msg": "error message"
 /// }
 /// ```
-#[get("/states/history")]
-fn states_history(
-    qs: QueryString,
+#[post("/token/refresh", format = "application/json", data = "<data>")]
+fn token_refresh(
+    data: Json<Value>,
     mysql_pool: State<Pool>,
-    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    token_keys: State<TokenKeys>,
+    config: State<Config>,
+    mut cookies: Cookies,
 ) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    let user = sess.user()?;
-    let worker_state = &*(worker_state_lock.read().unwrap());
-    // all states order by created time asc
-    let user_states = user.states(&mysql_pool, worker_state, None)?;
+    let refresh_token = match data["refresh_token"].as_str() {
+        Some(x) => x.to_string(),
+        None => cookies
+            .get_private(Session::REFRESH_COOKIE_NAME)
+            .map(|x| x.value().to_string())
+            .ok_or(E::RefreshTokenInvalid)?,
+    };
+    let sess = Session::refresh(&mysql_pool, &refresh_token)?;
 
-    let end_ts = time::get_time().sec;
-    let mut origin_ts = 0i64;
-    // all states group by coin type and map to state points {COIN => [(ASC TIMESTAMP, AMOUNT)]}
-    let mut coin_to_states = HashMap::<String, Vec<(i64, f64)>>::new();
-    for state in user_states.iter() {
-        if origin_ts == 0i64 {
-            origin_ts = state.created;
-        }
+    grant(&sess, &mysql_pool, &token_keys, &config, &mut cookies).map(Json)
+}
 
-        if !coin_to_states.contains_key(&state.coin_id) {
-            coin_to_states.insert(state.coin_id.clone(), vec![]);
-        }
-        let vec = coin_to_states.get_mut(&state.coin_id)?;
-        vec.push((state.created, state.amount));
-    }
-
-    println!("USER STATES GROUP BY COIN: {:?}", coin_to_states);
-    // {ASC TIMESTAMP => (TIMESTAMP, VALUE)}
-    let mut mix_points = BTreeMap::<i64, (i64, f64)>::new();
-    // each type of coin
-    for (coin_id, states) in coin_to_states {
-        // get the coin historical points among timestamp window
-        // {ASC TIMESTAMP => (PRICE, AMOUNT)}
-        let coin_points = models::coin_history(&mysql_pool, &coin_id, origin_ts, end_ts, &states)?;
-        for (ts, item) in coin_points {
-            let value_cny = item.0 * item.1 * worker_state.usd2cny_rate;
-            if !mix_points.contains_key(&ts) {
-                mix_points.insert(ts, (ts, value_cny));
-            } else {
-                let exist_item = mix_points.get_mut(&ts).unwrap();
-                exist_item.1 = exist_item.1 + value_cny;
-            }
-        }
+/// ### logout current session, or all sessions of the user
+/// - /api/logout
+/// - Content-Type: application/json
+/// - post
+/// ```js
+/// {
+///     "all": false
+/// }
+/// ```
+#[post("/logout", format = "application/json", data = "<data>")]
+fn logout(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    config: State<Config>,
+    mut cookies: Cookies,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    if data["all"].as_bool().unwrap_or(false) {
+        sess.user()?.revoke_sessions(&mysql_pool)?;
+    } else {
+        sess.revoke(&mysql_pool)?;
     }
+    remove_cookies(&config, &mut cookies);
 
-    let ret: Vec<(i64, f64)> = mix_points.values().cloned().collect();
-    Ok(Json(json!(ret)))
+    Ok(Json(json!(null)))
 }
 
-/// ### get coin detail
-/// - /api/coins/<coin_id>?access_token={access_token}
-/// - Content-Type: application/json
+/// webhook urls may point to the local network, only for development
+fn allow_private_hosts(config: &Config) -> bool {
+    config.get_bool("webhook_allow_private_hosts").unwrap_or(false)
+}
+
+/// remove the token cookies set by `grant`
+fn remove_cookies(config: &Config, cookies: &mut Cookies) {
+    for &(name, path) in [
+        (Session::COOKIE_NAME, "/"),
+        (Session::REFRESH_COOKIE_NAME, "/api/token/refresh"),
+    ].iter()
+    {
+        let mut cookie = Cookie::named(name);
+        cookie.set_path(path);
+        if let Ok(domain) = config.get_str("cookie_domain") {
+            cookie.set_domain(domain.to_string());
+        }
+        cookies.remove_private(cookie);
+    }
+}
+
+/// ### active sessions of the user
+/// - /api/sessions
 /// - get
 /// - http 200:
 /// ```js
-/// {
-///     "id": "abc",
+/// [
+///   {
+///     "handle": "xxxx", //to revoke it
+///     "user_agent": "Mozilla/5.0 ...",
+///     "ip": "1.2.3.4",
+///     "current": true, //the session of this request
+///     "created": 123,
+///     "accessed": 123
+///   },
+///   ...
+/// ]
+/// ```
+#[get("/sessions")]
+fn sessions(sess: Session, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let current = sess.handle();
+    let mut data = vec![];
+    for (handle, user_agent, ip, created, accessed) in user.sessions(&mysql_pool)? {
+        data.push(json!({
+            "current": handle == current,
+            "handle": handle,
+            "user_agent": user_agent,
+            "ip": ip,
+            "created": created,
+            "accessed": accessed,
+        }));
+    }
+
+    Ok(Json(json!(data)))
+}
+
+/// ### revoke a session of the user
+/// - /api/sessions
+/// - Content-Type: application/json
+/// - delete
+/// ```js
+/// {
+///     "handle": "xxxx"
+/// }
+/// ```
+#[delete("/sessions", format = "application/json", data = "<data>")]
+fn delete_session(sess: Session, mysql_pool: State<Pool>, data: Json<Value>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let handle = str_field(&data, "handle")?;
+    user.revoke_session(&mysql_pool, handle)?;
+
+    Ok(Json(json!(null)))
+}
+
+/// totp or recovery code in body, numbers lose leading zeros so strings are preferred
+fn totp_code(data: &Value) -> Option<String> {
+    match data["code"] {
+        Value::String(ref code) => Some(code.clone()),
+        Value::Number(ref code) => code.as_u64().map(|x| format!("{:06}", x)),
+        _ => None,
+    }
+}
+
+/// ### start enrolling a totp authenticator
+/// - /api/totp/enroll
+/// - post
+/// - enrolling again before it's confirmed replaces the secret
+/// - http 200:
+/// ```js
+/// {
+///     "secret": "BASE32SECRET", //for manual entry
+///     "uri": "otpauth://totp/..." //show it as a qr code
+/// }
+/// ```
+#[post("/totp/enroll")]
+fn totp_enroll(sess: Session, mysql_pool: State<Pool>, config: State<Config>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let secret = user.totp_enroll(&mysql_pool)?;
+    let issuer = config.get_str("totp_issuer").unwrap_or("yield.watch");
+    let account = if user.mobile.is_empty() {
+        &user.email
+    } else {
+        &user.mobile
+    };
+
+    Ok(Json(json!({
+        "secret": utils::base32_encode(&secret),
+        "uri": totp::uri(issuer, account, &secret),
+    })))
+}
+
+/// ### confirm the authenticator by its first code
+/// - /api/totp/verify
+/// - Content-Type: application/json
+/// - post
+/// ```js
+/// {
+///     "code": "123456"
+/// }
+/// ```
+/// - http 200, recovery codes are shown only once, each of them can be used instead of a code
+///   once:
+/// ```js
+/// {
+///     "recovery_codes": ["abcd-efgh", ...]
+/// }
+/// ```
+#[post("/totp/verify", format = "application/json", data = "<data>")]
+fn totp_verify(sess: Session, mysql_pool: State<Pool>, data: Json<Value>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let code = totp_code(&data).ok_or(E::TotpInvalid)?;
+    let recovery_codes = user.totp_confirm(&mysql_pool, &code)?;
+
+    Ok(Json(json!({
+        "recovery_codes": recovery_codes,
+    })))
+}
+
+/// ### login by totp code instead of sms
+/// - /api/totp/login
+/// - Content-Type: application/json
+/// - post
+/// - a recovery code is accepted as well, the new session is stepped up
+/// ```js
+/// {
+///     "mobile": "+8613800000000", //same as /api/sms
+///     "code": "123456"
+/// }
+/// ```
+/// - http 200, same as /api/sms/auth
+#[post("/totp/login", format = "application/json", data = "<data>")]
+fn totp_login(
+    data: Json<Value>,
+    mysql_pool: State<Pool>,
+    token_keys: State<TokenKeys>,
+    config: State<Config>,
+    mut cookies: Cookies,
+    client: ClientInfo,
+) -> Result<Json<Value>, E> {
+    let mobile = phone_param(&data, &config)?.e164();
+    let code = totp_code(&data).ok_or(E::TotpInvalid)?;
+    let user = User::find(&mysql_pool, None, Some(&mobile)).map_err(|_| E::TotpNotEnrolled)?;
+    user.totp_check(&mysql_pool, &code)?;
+
+    let mut sess = Session::for_user(&mysql_pool, user, &client)?;
+    sess.step_up(&mysql_pool)?;
+    grant(&sess, &mysql_pool, &token_keys, &config, &mut cookies).map(Json)
+}
+
+/// ### step up the current session by totp code
+/// - /api/totp/step-up
+/// - Content-Type: application/json
+/// - post
+/// - disabling totp and regenerating recovery codes require a step-up in the last 10 minutes
+/// ```js
+/// {
+///     "code": "123456"
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "until": 123 //unix time the step-up expires
+/// }
+/// ```
+#[post("/totp/step-up", format = "application/json", data = "<data>")]
+fn totp_step_up(mut sess: Session, mysql_pool: State<Pool>, data: Json<Value>) -> Result<Json<Value>, E> {
+    let code = totp_code(&data).ok_or(E::TotpInvalid)?;
+    sess.user()?.totp_check(&mysql_pool, &code)?;
+    let until = sess.step_up(&mysql_pool)?;
+
+    Ok(Json(json!({
+        "until": until,
+    })))
+}
+
+/// ### replace recovery codes
+/// - /api/totp/recovery-codes
+/// - post
+/// - http 200:
+/// ```js
+/// {
+///     "recovery_codes": ["abcd-efgh", ...]
+/// }
+/// ```
+#[post("/totp/recovery-codes")]
+fn totp_recovery_codes(sess: Session, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    sess.check_step_up()?;
+    let user = sess.user()?;
+    if !user.totp_enabled(&mysql_pool)? {
+        return Err(E::TotpNotEnrolled);
+    }
+    let recovery_codes = user.gen_recovery_codes(&mysql_pool)?;
+
+    Ok(Json(json!({
+        "recovery_codes": recovery_codes,
+    })))
+}
+
+/// ### disable totp
+/// - /api/totp
+/// - delete
+#[delete("/totp")]
+fn delete_totp(sess: Session, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    sess.check_step_up()?;
+    sess.user()?.totp_disable(&mysql_pool)?;
+
+    Ok(Json(json!(null)))
+}
+
+/// ### fetch session owner's info
+/// - /api/me?access_token={access_token}
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// {
+///     "id": 123,
+///     "name": "abc",
+///     "currency": "CNY",
+///     "notify_channel": "sms", //sms, email or webhook
+///     "email": "",
+///     "webhook_url": "",
+///     "digest": "off", //off, daily or weekly
+///     "timezone": "Asia/Shanghai",
+///     "locale": "zh-CN", //zh-CN or en-US
+///     "totp": false, //totp authenticator enabled
+///     "created": 123,
+///     "rates": {"CNY": 6.8, ...}
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/me")]
+fn me_get(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    Ok(Json(json!({
+        "id": user.id,
+        "name": user.name,
+        "currency": user.currency,
+        "notify_channel": user.notify_channel.as_str(),
+        "email": user.email,
+        "webhook_url": user.webhook_url,
+        "digest": user.digest.map(|x| x.as_str()).unwrap_or("off"),
+        "timezone": user.timezone,
+        "locale": user.locale,
+        "totp": user.totp_enabled(&mysql_pool)?,
+        "created": user.created,
+        "usd2cny_rate": worker_state.rate("CNY").unwrap_or(0.0),
+        "rates": worker_state.rates,
+    })))
+}
+
+/// ### register session owner
+/// - /api/me?access_token={access_token}
+/// - Content-Type: application/json
+/// - post
+/// ```js
+/// {
+///     "name": "abc"
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "id": 123,
+///     "name": "abc",
+///     "created": 123
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[post("/me", data = "<data>")]
+fn me_post(
+    mut sess: Session,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let name = str_field(&data, "name")?;
+    sess.signup(&mysql_pool, name)?;
+    let user = sess.user()?;
+    Ok(Json(json!({
+        "id": user.id,
+        "name": user.name,
+        "currency": user.currency,
+        "created": user.created
+    })))
+}
+
+/// ### update session owner's settings
+/// - /api/me?access_token={access_token}
+/// - Content-Type: application/json
+/// - put
+/// ```js
+/// {
+///     "name": "abc",
+///     "timezone": "America/New_York", //IANA time zone name
+///     "locale": "en-US", //zh-CN or en-US
+///     "currency": "USD",
+///     "notify_channel": "email", //sms, email or webhook, alerts and login codes go there
+///     "email": "abc@example.com", //required by email channel
+///     "webhook_url": "https://example.com/hook", //required by webhook channel
+///     "digest": "daily" //off, daily or weekly
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "id": 123,
+///     "name": "abc",
+///     "currency": "USD",
+///     "notify_channel": "email",
+///     "email": "abc@example.com",
+///     "webhook_url": "",
+///     "digest": "daily",
+///     "timezone": "America/New_York",
+///     "locale": "en-US",
+///     "created": 123
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[put("/me", format = "application/json", data = "<data>")]
+fn me_put(
+    mut sess: Session,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    config: State<Config>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user_mut()?;
+    if data["name"].is_string() || data["timezone"].is_string() || data["locale"].is_string() {
+        let name = data["name"].as_str().unwrap_or(&user.name).to_string();
+        let timezone = data["timezone"]
+            .as_str()
+            .unwrap_or(&user.timezone)
+            .to_string();
+        let locale = data["locale"].as_str().unwrap_or(&user.locale).to_string();
+        user.set_profile(&mysql_pool, &name, &timezone, &locale)?;
+    }
+    if let Some(currency) = data["currency"].as_str() {
+        let currency = currency.to_uppercase();
+        let worker_state = &*(worker_state_lock.read().unwrap());
+        worker_state.rate(&currency).ok_or(E::CurrencyNotSupported)?;
+        user.set_currency(&mysql_pool, &currency)?;
+    }
+    if let Some(channel) = data["notify_channel"].as_str() {
+        let channel = Channel::parse(channel).ok_or(E::NotifyChannelInvalid)?;
+        let email = data["email"].as_str().unwrap_or(&user.email).to_string();
+        let webhook_url = data["webhook_url"]
+            .as_str()
+            .unwrap_or(&user.webhook_url)
+            .to_string();
+        user.set_notify(
+            &mysql_pool,
+            channel,
+            &email,
+            &webhook_url,
+            allow_private_hosts(&config),
+        )?;
+    }
+    if let Some(digest) = data["digest"].as_str() {
+        let digest = match digest {
+            "off" => None,
+            x => Some(Period::parse(x)?),
+        };
+        user.set_digest(&mysql_pool, digest)?;
+    }
+    Ok(Json(json!({
+        "id": user.id,
+        "name": user.name,
+        "currency": user.currency,
+        "notify_channel": user.notify_channel.as_str(),
+        "email": user.email,
+        "webhook_url": user.webhook_url,
+        "digest": user.digest.map(|x| x.as_str()).unwrap_or("off"),
+        "timezone": user.timezone,
+        "locale": user.locale,
+        "created": user.created
+    })))
+}
+
+/// ### send codes for changing the bound mobile
+/// - /api/me/mobile/code
+/// - Content-Type: application/json
+/// - post
+/// - a code is sent to the new mobile, and another one to the old mobile if the user has one.
+///   Both are sent by sms.
+/// ```js
+/// {
+///     "mobile": "+8613800000000" //the new one, same as /api/sms
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "interval": 123
+/// }
+/// ```
+#[post("/me/mobile/code", format = "application/json", data = "<data>")]
+fn me_mobile_code(
+    sess: Session,
+    data: Json<Value>,
+    mysql_pool: State<Pool>,
+    config: State<Config>,
+    sms_fac_lock: State<Mutex<SmsFactory>>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let phone = phone_param(&data, &config)?;
+    if User::find(&mysql_pool, None, Some(&phone.e164())).is_ok() {
+        return Err(E::MobileTaken);
+    }
+    let sms_fac = sms_fac_lock.lock()?;
+    let mut interval = sms_fac.gen_sms_code(&mysql_pool, &phone)?;
+    if !user.mobile.is_empty() {
+        let old_phone = Phone::parse(&user.mobile, 86)?;
+        interval = interval.max(sms_fac.gen_sms_code(&mysql_pool, &old_phone)?);
+    }
+
+    Ok(Json(json!({
+        "interval": interval,
+    })))
+}
+
+/// ### change the bound mobile
+/// - /api/me/mobile
+/// - Content-Type: application/json
+/// - put
+/// ```js
+/// {
+///     "mobile": "+8613800000000", //the new one
+///     "code": 1234, //sent to the new mobile
+///     "old_code": 1234 //sent to the old mobile, not required if the user has none
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "mobile": "+8613800000000"
+/// }
+/// ```
+#[put("/me/mobile", format = "application/json", data = "<data>")]
+fn put_me_mobile(
+    mut sess: Session,
+    data: Json<Value>,
+    mysql_pool: State<Pool>,
+    config: State<Config>,
+    sms_fac_lock: State<Mutex<SmsFactory>>,
+) -> Result<Json<Value>, E> {
+    let mobile = phone_param(&data, &config)?.e164();
+    let code = i64_field(&data, "code")?;
+    let sms_fac = sms_fac_lock.lock()?;
+    let user = sess.user_mut()?;
+    if !user.mobile.is_empty() {
+        let old_code = i64_field(&data, "old_code")?;
+        sms_fac.check_code(&mysql_pool, &user.mobile, old_code as u32)?;
+    }
+    sms_fac.check_code(&mysql_pool, &mobile, code as u32)?;
+    user.set_mobile(&mysql_pool, &mobile)?;
+
+    Ok(Json(json!({
+        "mobile": user.mobile,
+    })))
+}
+
+/// ### delete the session owner and all of its data
+/// - /api/me
+/// - Content-Type: application/json
+/// - delete
+/// - it must be confirmed: by a totp step-up if totp is enabled, otherwise by the password of
+///   the email login or a code sent to the bound mobile by /api/sms. Holdings, portfolios and
+///   their sharing, alerts, webhooks, logins, sessions and sms are deleted, it can't be undone.
+/// ```js
+/// {
+///     "password": "xxxx", //or
+///     "code": 1234
+/// }
+/// ```
+#[delete("/me", format = "application/json", data = "<data>")]
+fn delete_me(
+    sess: Session,
+    data: Json<Value>,
+    mysql_pool: State<Pool>,
+    config: State<Config>,
+    sms_fac_lock: State<Mutex<SmsFactory>>,
+    mut cookies: Cookies,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    if user.totp_enabled(&mysql_pool)? {
+        sess.check_step_up()?;
+    } else if let Some(password) = data["password"].as_str() {
+        EmailLogin::check_password(&mysql_pool, user.id, password)?;
+    } else if !user.mobile.is_empty() {
+        let code = i64_field(&data, "code")?;
+        sms_fac_lock
+            .lock()?
+            .check_code(&mysql_pool, &user.mobile, code as u32)?;
+    } else {
+        return Err(E::ParamInvalid("password".to_string()));
+    }
+    user.delete(&mysql_pool)?;
+    remove_cookies(&config, &mut cookies);
+
+    Ok(Json(json!(null)))
+}
+
+/// ### user current coins states
+/// - /api/states?access_token={access_token}&currency={currency}&portfolio={portfolio_id}
+/// - all portfolios are summed up if portfolio is not given
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// {
+///     "balance": 123,
+///     "currency": "CNY", //the user's default currency if not given
+///     "states":
+///     [
+///       {
+///         "coin_id": "abc",
+///         "amount": 12.3,
+///         "created": 123,
+///         "value": 12.3, //in currency, invalid state if this is None
+///         "value_cny": 12.3, //invalid state if this is None
+///         "coin": { //invalid state if this is None
+///             "id": "abc",
+///             "name": "abc",
+///             "symbol": "abc",
+///             "price_usd": 12.3,
+///             "volume_usd": 12.3,
+///             "market_cap_usd": 12.3,
+///             "percent_change_24h": 12.3, //percent
+///             "rank": 123
+///         }
+///       },
+///       ...
+///     ]
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/states")]
+fn states(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let (currency, rate) = currency_rate(&qs, user, worker_state)?;
+    let usd2cny_rate = worker_state.rate("CNY").unwrap_or(0.0);
+    let portfolio = portfolio_param(&qs, &mysql_pool, user)?;
+    let balance = user.balance(&mysql_pool, portfolio)?;
+    let mut user_states = user.states(&mysql_pool, worker_state, None, portfolio)?;
+    user_states.reverse();
+
+    let mut rt_states_list = vec![];
+    let mut got_coins = vec![];
+    for state in user_states {
+        if !got_coins.contains(&state.coin_id) {
+            got_coins.push(state.coin_id.clone());
+            let coin = state.coin.unwrap();
+            let coin_json = json!({
+                "id": coin.id.clone(),
+                "name": coin.name.clone(),
+                "symbol": coin.symbol.clone(),
+                "price_usd": coin.price_usd,
+                "volume_usd": coin.volume_usd,
+                "market_cap_usd": coin.market_cap_usd,
+                "percent_change_24h": coin.percent_change_24h,
+                "rank": coin.rank,
+                "no": coin.no,
+            });
+            rt_states_list.push(json!({
+                "coin_id": state.coin_id,
+                "amount": state.amount,
+                "created": state.created,
+                "value": coin.price_usd * state.amount * rate,
+                "value_cny": coin.price_usd * state.amount * usd2cny_rate,
+                "coin": coin_json
+            }));
+        }
+    }
+
+    Ok(Json(json!({
+        "balance": balance,
+        "currency": currency,
+        "states": rt_states_list,
+    })))
+}
+
+#[get("/states/<coin_id>")]
+fn coin_states(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    coin_id: String,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let portfolio = portfolio_param(&qs, &mysql_pool, user)?;
+    let ret = user.states(&mysql_pool, worker_state, Some(&coin_id), portfolio)?;
+    let data: Vec<Value> = ret.iter().map(|record| {
+        json!({
+            "id": record.id,
+            "amount": record.amount,
+            "created": record.created,
+            "derived": record.derived,
+        })
+    }).collect();
+
+    Ok(Json(json!(data)))
+}
+
+#[put("/states", format = "application/json", data = "<data>")]
+fn put_states(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    let coin_id = str_field(&data, "coin_id")?;
+    let created = i64_field(&data, "created")?;
+    let amount = f64_field(&data, "amount")?;
+    let portfolio_id = portfolio_param(&qs, &mysql_pool, user)?.unwrap_or(0);
+    user.put_states(&mysql_pool, portfolio_id, id, coin_id, created, amount)?;
+    webhook::emit(
+        &mysql_pool,
+        Some(user.id),
+        webhook::HOLDINGS_CHANGED,
+        json!({
+            "action": "put",
+            "portfolio_id": portfolio_id,
+            "id": id,
+            "coin_id": coin_id,
+            "amount": amount,
+            "created": created,
+        }),
+    )?;
+
+    Ok(Json(json!(null)))
+}
+
+#[delete("/states", format = "application/json", data = "<data>")]
+fn delete_states(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    user.del_states(&mysql_pool, id)?;
+    webhook::emit(
+        &mysql_pool,
+        Some(user.id),
+        webhook::HOLDINGS_CHANGED,
+        json!({
+            "action": "delete",
+            "id": id,
+        }),
+    )?;
+
+    Ok(Json(json!(null)))
+}
+
+#[put("/balance", format = "application/json", data = "<data>")]
+fn put_balance(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    let created = i64_field(&data, "created")?;
+    let amount = f64_field(&data, "amount")?;
+    let portfolio_id = portfolio_param(&qs, &mysql_pool, user)?.unwrap_or(0);
+    user.put_balance(&mysql_pool, portfolio_id, id, created, amount)?;
+    webhook::emit(
+        &mysql_pool,
+        Some(user.id),
+        webhook::HOLDINGS_CHANGED,
+        json!({
+            "action": "put_balance",
+            "portfolio_id": portfolio_id,
+            "id": id,
+            "amount": amount,
+            "created": created,
+        }),
+    )?;
+
+    Ok(Json(json!(null)))
+}
+
+#[delete("/balance", format = "application/json", data = "<data>")]
+fn delete_balance(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    user.del_balance(&mysql_pool, id)?;
+    webhook::emit(
+        &mysql_pool,
+        Some(user.id),
+        webhook::HOLDINGS_CHANGED,
+        json!({
+            "action": "delete_balance",
+            "id": id,
+        }),
+    )?;
+
+    Ok(Json(json!(null)))
+}
+
+/// ### user portfolio historical value
+/// - /api/states/history?access_token={access_token}&currency={currency}&portfolio={portfolio_id}
+/// - all portfolios are summed up if portfolio is not given
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// [
+///     [123, 12.3], //value in currency
+///     ...
+/// ]
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/states/history")]
+fn states_history(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let (currency, rate) = currency_rate(&qs, user, worker_state)?;
+    let portfolio = portfolio_param(&qs, &mysql_pool, user)?;
+    // all states order by created time asc
+    let user_states = user.states(&mysql_pool, worker_state, None, portfolio)?;
+    let ret = models::portfolio_history(&mysql_pool, &user_states, &currency, rate)?;
+
+    Ok(Json(json!(ret)))
+}
+
+/// ### get coin detail
+/// - /api/coins/<coin_id>?access_token={access_token}&currency={currency}
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// {
+///     "id": "abc",
 ///     "name": "abc",
 ///     "symbol": "abc",
 ///     "rank": 123,
+///     "currency": "CNY",
+///     "price": 12.3, //in currency
 ///     "price_usd": 12.3,
 ///     "volume_usd": 12.3,
 ///     "market_cap_usd": 12.3,
@@ -461,74 +1327,1228 @@ fn states_history(
 ///     "msg": "error message"
 /// }
 /// ```
-#[get("/coins/<coin_id>")]
-fn coin(
-    qs: QueryString,
+#[get("/coins/<coin_id>")]
+fn coin(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    coin_id: String,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let (currency, rate) = currency_rate(&qs, user, worker_state)?;
+
+    let coin = worker_state.coins.iter().find(|&x| x.id == coin_id);
+    if coin.is_none() {
+        return Err(E::CoinNotFound);
+    }
+    let coin = coin.unwrap();
+
+    let end_ts = time::get_time().sec;
+    let origin_ts = end_ts - 30 * 24 * 3600;
+    let states = vec![(origin_ts, 0.0)];
+    let rates = models::RateSeries::load(&mysql_pool, &currency, origin_ts, end_ts, rate)?;
+    let points =
+        models::coin_history(&mysql_pool, &coin_id, origin_ts, end_ts, &states, &rates)?;
+
+    let history: Vec<(&i64, f64)> = points.iter().map(|(k, item)| (k, item.0)).collect();
+
+    Ok(Json(json!({
+        "id": coin.id,
+        "name": coin.name,
+        "symbol": coin.symbol,
+        "rank": coin.rank,
+        "currency": currency,
+        "price": coin.price_usd * rate,
+        "price_usd": coin.price_usd,
+        "price_cny": coin.price_cny,
+        "volume_usd": coin.volume_usd,
+        "market_cap_usd": coin.market_cap_usd,
+        "percent_change_24h": coin.percent_change_24h,
+        "percent_change_7d": coin.percent_change_7d,
+        "history": history,
+        "no": coin.no,
+    })))
+}
+
+#[get("/coins")]
+fn coins(worker_state_lock: State<Arc<RwLock<worker::State>>>) -> Result<Json<Value>, E> {
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let mut arr = vec![];
+    for coin in worker_state.coins.iter() {
+        arr.push(json!({
+            "id": coin.id,
+            "name": coin.name,
+            "symbol": coin.symbol,
+            "rank": coin.rank,
+            "price_usd": coin.price_usd,
+            "volume_usd": coin.volume_usd,
+            "market_cap_usd": coin.market_cap_usd,
+            "percent_change_24h": coin.percent_change_24h,
+            "percent_change_1h": coin.percent_change_1h,
+            "no": coin.no,
+        }))
+    }
+    arr.sort_by(|a, b|{
+        a["rank"].as_i64().cmp(&(b["rank"].as_i64()))
+    });
+    Ok(Json(json!(arr)))
+}
+
+#[options("/<_path..>", rank = 1)]
+fn options_all(_path: PathBuf) -> status::NoContent {
+    status::NoContent
+}
+
+/// ### user transactions
+/// - /api/transactions?access_token={access_token}&coin_id={coin_id}&portfolio={portfolio_id}
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// [
+///   {
+///     "id": 123,
+///     "portfolio_id": 0, //0 is the default portfolio
+///     "coin_id": "abc",
+///     //buy, sell, deposit, withdrawal, transfer, fee, airdrop, staking, opening
+///     "kind": "buy",
+///     "amount": 12.3, //always positive
+///     "price_usd": 12.3, //unit price, 0 if unknown and kept out of cost basis
+///     "fee_usd": 12.3,
+///     "wallet": "abc",
+///     "to_wallet": "abc", //transfer only
+///     "created": 123
+///   },
+///   ...
+/// ]
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/transactions")]
+fn transactions(sess: Session, qs: QueryString, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let coin_id = qs.get("coin_id").map(|s| s.as_str());
+    let portfolio = portfolio_param(&qs, &mysql_pool, user)?;
+    let data: Vec<Value> = user.transactions(&mysql_pool, coin_id, portfolio)?
+        .iter()
+        .map(Transaction::to_json)
+        .collect();
+
+    Ok(Json(json!(data)))
+}
+
+#[put("/transactions", format = "application/json", data = "<data>")]
+fn put_transaction(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let tx = Transaction {
+        id: i64_field(&data, "id")?,
+        portfolio_id: portfolio_param(&qs, &mysql_pool, user)?.unwrap_or(0),
+        coin_id: str_field(&data, "coin_id")?.to_string(),
+        kind: TxKind::parse(str_field(&data, "kind")?).ok_or(E::TransactionInvalid)?,
+        amount: f64_field(&data, "amount")?,
+        price_usd: data["price_usd"].as_f64().unwrap_or(0.0),
+        fee_usd: data["fee_usd"].as_f64().unwrap_or(0.0),
+        wallet: data["wallet"].as_str().unwrap_or("").to_string(),
+        to_wallet: data["to_wallet"].as_str().unwrap_or("").to_string(),
+        created: i64_field(&data, "created")?,
+    };
+    user.put_transaction(&mysql_pool, &tx)?;
+    webhook::emit(
+        &mysql_pool,
+        Some(user.id),
+        webhook::HOLDINGS_CHANGED,
+        json!({
+            "action": "put_transaction",
+            "transaction": tx.to_json(),
+        }),
+    )?;
+
+    Ok(Json(json!(null)))
+}
+
+/// ### migrate states into the ledger
+/// - /api/transactions/migrate?access_token={access_token}
+/// - Content-Type: application/json
+/// - post
+/// - http 200:
+/// ```js
+/// {
+///     "created": 12 //number of transactions created
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[post("/transactions/migrate")]
+fn migrate_states(sess: Session, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let created = user.migrate_states(&mysql_pool)?;
+
+    Ok(Json(json!({
+        "created": created,
+    })))
+}
+
+#[delete("/transactions", format = "application/json", data = "<data>")]
+fn delete_transaction(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    user.del_transaction(&mysql_pool, id)?;
+    webhook::emit(
+        &mysql_pool,
+        Some(user.id),
+        webhook::HOLDINGS_CHANGED,
+        json!({
+            "action": "delete_transaction",
+            "id": id,
+        }),
+    )?;
+
+    Ok(Json(json!(null)))
+}
+
+/// profit of each coin the user holds or traded, holdings come from the latest states
+fn user_pnl(
+    mysql_pool: &Pool,
+    user: &User,
+    worker_state: &worker::State,
+    coin_id: Option<&str>,
+    portfolio: Option<i64>,
+) -> Result<BTreeMap<String, Pnl>, E> {
+    // {COIN => LATEST AMOUNT}
+    let mut amounts = HashMap::<String, f64>::new();
+    for state in user.states(mysql_pool, worker_state, coin_id, portfolio)? {
+        amounts.insert(state.coin_id, state.amount);
+    }
+    // {COIN => [ASC TRANSACTION]}
+    let mut coin_txs = BTreeMap::<String, Vec<Transaction>>::new();
+    for tx in user.transactions(mysql_pool, coin_id, portfolio)? {
+        coin_txs.entry(tx.coin_id.clone()).or_insert(vec![]).push(tx);
+    }
+    for coin_id in amounts.keys() {
+        coin_txs.entry(coin_id.clone()).or_insert(vec![]);
+    }
+
+    let mut data = BTreeMap::new();
+    for (coin_id, txs) in coin_txs {
+        let price_usd = worker_state
+            .coins
+            .iter()
+            .find(|&x| x.id == coin_id)
+            .map_or(0.0, |coin| coin.price_usd);
+        let item = pnl::coin_pnl(&txs, amounts.get(&coin_id).cloned(), price_usd);
+        data.insert(coin_id, item);
+    }
+
+    Ok(data)
+}
+
+fn pnl_json(item: &Pnl, rate: f64) -> Value {
+    json!({
+        "amount": item.amount,
+        "avg_cost": item.avg_cost_usd * rate,
+        "cost_basis": item.cost_basis_usd * rate,
+        "realized": item.realized_usd * rate,
+        "unrealized": item.unrealized_usd * rate,
+        "value": item.value_usd * rate,
+        "uncovered_amount": item.uncovered_amount,
+        "unmatched_sold_amount": item.unmatched_sold_amount,
+    })
+}
+
+/// ### portfolio cost basis and profit
+/// - /api/pnl?access_token={access_token}&currency={currency}&portfolio={portfolio_id}
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// {
+///     "currency": "CNY",
+///     "total": {
+///         "cost_basis": 12.3,
+///         "realized": 12.3,
+///         "unrealized": 12.3,
+///         "value": 12.3
+///     },
+///     "coins": {
+///         "abc": {
+///             "amount": 12.3,
+///             "avg_cost": 12.3, //unit cost of open lots
+///             "cost_basis": 12.3, //fifo cost of open lots
+///             "realized": 12.3,
+///             "unrealized": 12.3,
+///             "value": 12.3,
+///             "uncovered_amount": 12.3, //holdings without buy records
+///             "unmatched_sold_amount": 12.3 //sold without buy records, not in realized
+///         },
+///         ...
+///     }
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/pnl")]
+fn portfolio_pnl(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let (currency, rate) = currency_rate(&qs, user, worker_state)?;
+
+    let mut total = Pnl::default();
+    let mut coins = BTreeMap::new();
+    let portfolio = portfolio_param(&qs, &mysql_pool, user)?;
+    for (coin_id, item) in user_pnl(&mysql_pool, user, worker_state, None, portfolio)? {
+        total.merge(&item);
+        coins.insert(coin_id, pnl_json(&item, rate));
+    }
+
+    Ok(Json(json!({
+        "currency": currency,
+        "total": {
+            "cost_basis": total.cost_basis_usd * rate,
+            "realized": total.realized_usd * rate,
+            "unrealized": total.unrealized_usd * rate,
+            "value": total.value_usd * rate,
+        },
+        "coins": coins,
+    })))
+}
+
+/// ### coin cost basis and profit
+/// - /api/pnl/<coin_id>?access_token={access_token}&currency={currency}&portfolio={portfolio_id}
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// {
+///     "currency": "CNY",
+///     "coin_id": "abc",
+///     "amount": 12.3,
+///     "avg_cost": 12.3,
+///     "cost_basis": 12.3,
+///     "realized": 12.3,
+///     "unrealized": 12.3,
+///     "value": 12.3,
+///     "uncovered_amount": 12.3,
+///     "unmatched_sold_amount": 12.3
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/pnl/<coin_id>")]
+fn coin_pnl(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    coin_id: String,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let (currency, rate) = currency_rate(&qs, user, worker_state)?;
+
+    let portfolio = portfolio_param(&qs, &mysql_pool, user)?;
+    let data = user_pnl(&mysql_pool, user, worker_state, Some(&coin_id), portfolio)?;
+    let item = data.get(&coin_id).ok_or(E::CoinNotFound)?;
+    let mut ret = pnl_json(item, rate);
+    ret["currency"] = json!(currency);
+    ret["coin_id"] = json!(coin_id);
+
+    Ok(Json(ret))
+}
+
+/// layout of the uploaded csv from `layout` in query string, generic layout maps columns by
+/// `date`, `date_format`, `symbol`, `kind`, `amount`, `price` and `fee`
+fn import_layout(qs: &QueryString) -> Result<Layout, E> {
+    match qs.get("layout").map(|s| s.as_str()) {
+        Some("binance") => Ok(Layout::Binance),
+        Some("coinbase") => Ok(Layout::Coinbase),
+        Some("generic") => {
+            let column = |name: &str| qs.get(name).cloned().unwrap_or_default();
+            let mapping = Mapping {
+                date: column("date"),
+                date_format: column("date_format"),
+                symbol: column("symbol"),
+                kind: column("kind"),
+                amount: column("amount"),
+                price: column("price"),
+                fee: column("fee"),
+            };
+            if mapping.date.is_empty() || mapping.symbol.is_empty() || mapping.kind.is_empty()
+                || mapping.amount.is_empty()
+            {
+                return Err(E::ImportInvalid("date, symbol, kind and amount are required".into()));
+            }
+            Ok(Layout::Generic(mapping))
+        }
+        _ => Err(E::ImportInvalid("unknown layout".into())),
+    }
+}
+
+/// parse the uploaded csv and mark the rows imported before
+fn import_rows(
+    qs: &QueryString,
+    mysql_pool: &Pool,
+    user: &User,
+    worker_state: &worker::State,
+    data: &str,
+) -> Result<Vec<import::Row>, E> {
+    let layout = import_layout(qs)?;
+    let portfolio_id = portfolio_param(qs, mysql_pool, user)?.unwrap_or(0);
+    let price_at = |coin_id: &str, ts: i64| match models::coin_price_at(mysql_pool, coin_id, ts) {
+        Ok(price_usd) => price_usd,
+        Err(e) => {
+            warn!("Error while pricing {} at {}: {:?}", coin_id, ts, e);
+            None
+        }
+    };
+    let mut rows =
+        import::parse(&layout, data, worker_state, &price_at).map_err(E::ImportInvalid)?;
+    let imported = {
+        let hashes: Vec<&str> = rows.iter().map(|row| row.hash.as_str()).collect();
+        user.imported_hashes(mysql_pool, portfolio_id, &hashes)?
+    };
+    for row in rows.iter_mut() {
+        row.duplicate = imported.contains(&row.hash);
+        if let Some(ref mut tx) = row.tx {
+            tx.portfolio_id = portfolio_id;
+        }
+    }
+
+    Ok(rows)
+}
+
+/// ### preview trade history csv
+/// - /api/import/preview?access_token={access_token}&layout={binance|coinbase|generic}&portfolio={portfolio_id}
+/// - generic layout maps columns by &date=..&date_format=..&symbol=..&kind=..&amount=..&price=..&fee=..
+/// - Content-Type: text/csv
+/// - post
+/// - http 200:
+/// ```js
+/// {
+///     "rows": [
+///       {
+///         "line": 1,
+///         "symbol": "BTC",
+///         "transaction": {...}, //null if the row can't be parsed
+///         "duplicate": false, //imported before
+///         "unpriced": false, //no usd price, its cost basis will be unknown
+///         "error": null
+///       },
+///       ...
+///     ]
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[post("/import/preview", format = "text/csv", data = "<data>")]
+fn import_preview(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    data: String,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let rows = import_rows(&qs, &mysql_pool, user, worker_state, &data)?;
+    let rows: Vec<Value> = rows.iter().map(import::Row::to_json).collect();
+
+    Ok(Json(json!({
+        "rows": rows,
+    })))
+}
+
+/// ### import trade history csv
+/// - /api/import/commit?access_token={access_token}&layout={binance|coinbase|generic}&portfolio={portfolio_id}
+/// - the same query string and body as preview, valid rows not imported before are committed
+/// - Content-Type: text/csv
+/// - post
+/// - http 200:
+/// ```js
+/// {
+///     "imported": 12,
+///     "duplicates": 1,
+///     "errors": 1
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[post("/import/commit", format = "text/csv", data = "<data>")]
+fn import_commit(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    data: String,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let rows = import_rows(&qs, &mysql_pool, user, worker_state, &data)?;
+
+    let mut txs = vec![];
+    let mut duplicates = 0;
+    let mut errors = 0;
+    for row in rows.iter() {
+        match row.tx {
+            Some(ref tx) if !row.duplicate => txs.push((row.hash.as_str(), tx)),
+            Some(_) => duplicates += 1,
+            None => errors += 1,
+        }
+    }
+    let imported = user.import_transactions(&mysql_pool, &txs)?;
+    if imported > 0 {
+        webhook::emit(
+            &mysql_pool,
+            Some(user.id),
+            webhook::HOLDINGS_CHANGED,
+            json!({
+                "action": "import",
+                "portfolio_id": txs[0].1.portfolio_id,
+                "imported": imported,
+            }),
+        )?;
+    }
+
+    Ok(Json(json!({
+        "imported": imported,
+        "duplicates": duplicates,
+        "errors": errors,
+    })))
+}
+
+/// ### export portfolio
+/// - /api/export?access_token={access_token}&format={csv|json}&currency={currency}&portfolio={portfolio_id}
+/// - get
+/// - http 200 json, it can be restored by /api/import/json:
+/// ```js
+/// {
+///     "version": 1,
+///     "exported": 123,
+///     "currency": "CNY",
+///     "states": [{"id": 123, "coin_id": "abc", "amount": 12.3, "created": 123}, ...],
+///     "balance": [{"id": 123, "amount": 12.3, "created": 123}, ...],
+///     "transactions": [{..., "fingerprint": "abc"}, ...], //kept by restore to skip existing entries, an entry without it needs its id
+///     "valuations": [[123, 12.3], ...] //portfolio value in currency
+/// }
+/// ```
+/// - http 200 csv, one record per line:
+/// ```
+/// record,id,created,coin_id,kind,amount,price_usd,fee_usd,value,fingerprint
+/// state,123,123,abc,,12.3,,,,
+/// balance,123,123,,,12.3,,,,
+/// transaction,123,123,abc,buy,12.3,12.3,0.1,,abc
+/// valuation,,123,,,,,,12.3,
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/export")]
+fn export(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+) -> Result<content::Content<String>, E> {
+    let user = sess.user()?;
+    let worker_state = &*(worker_state_lock.read().unwrap());
+    let (currency, rate) = currency_rate(&qs, user, worker_state)?;
+
+    let portfolio = portfolio_param(&qs, &mysql_pool, user)?;
+    let states = user.states_rows(&mysql_pool, portfolio)?;
+    let balance = user.balance(&mysql_pool, portfolio)?;
+    let txs = user.transactions(&mysql_pool, None, portfolio)?;
+    let fingerprints = user.fingerprints(&mysql_pool, portfolio)?;
+    let fingerprint = |tx: &Transaction| fingerprints.get(&tx.id).cloned().unwrap_or_default();
+    let user_states = user.states(&mysql_pool, worker_state, None, portfolio)?;
+    let valuations = models::portfolio_history(&mysql_pool, &user_states, &currency, rate)?;
+
+    match qs.get("format").map(|s| s.as_str()).unwrap_or("json") {
+        "json" => {
+            let states: Vec<Value> = states
+                .iter()
+                .map(|&(id, ref coin_id, amount, created)| {
+                    json!({"id": id, "coin_id": coin_id, "amount": amount, "created": created})
+                })
+                .collect();
+            let balance: Vec<Value> = balance
+                .iter()
+                .map(|&(created, amount, id)| {
+                    json!({"id": id, "amount": amount, "created": created})
+                })
+                .collect();
+            let txs: Vec<Value> = txs.iter()
+                .map(|tx| {
+                    let mut item = tx.to_json();
+                    item["fingerprint"] = json!(fingerprint(tx));
+                    item
+                })
+                .collect();
+            let body = json!({
+                "version": 1,
+                "exported": time::get_time().sec,
+                "currency": currency,
+                "states": states,
+                "balance": balance,
+                "transactions": txs,
+                "valuations": valuations,
+            });
+            Ok(content::Content(ContentType::JSON, body.to_string()))
+        }
+        "csv" => {
+            let mut wtr = csv::Writer::from_writer(vec![]);
+            let mut write = |record: &[String]| {
+                wtr.write_record(record)
+                    .map_err(|e| E::Internal(e.to_string()))
+            };
+            let header = [
+                "record", "id", "created", "coin_id", "kind", "amount", "price_usd", "fee_usd",
+                "value", "fingerprint",
+            ];
+            write(&header.iter().map(|s| s.to_string()).collect::<Vec<_>>())?;
+            let blank = String::new;
+            for &(id, ref coin_id, amount, created) in states.iter() {
+                write(&[
+                    "state".into(),
+                    id.to_string(),
+                    created.to_string(),
+                    coin_id.clone(),
+                    blank(),
+                    amount.to_string(),
+                    blank(),
+                    blank(),
+                    blank(),
+                    blank(),
+                ])?;
+            }
+            for &(created, amount, id) in balance.iter() {
+                write(&[
+                    "balance".into(),
+                    id.to_string(),
+                    created.to_string(),
+                    blank(),
+                    blank(),
+                    amount.to_string(),
+                    blank(),
+                    blank(),
+                    blank(),
+                    blank(),
+                ])?;
+            }
+            for tx in txs.iter() {
+                write(&[
+                    "transaction".into(),
+                    tx.id.to_string(),
+                    tx.created.to_string(),
+                    tx.coin_id.clone(),
+                    tx.kind.as_str().into(),
+                    tx.amount.to_string(),
+                    tx.price_usd.to_string(),
+                    tx.fee_usd.to_string(),
+                    blank(),
+                    fingerprint(tx),
+                ])?;
+            }
+            for &(ts, value) in valuations.iter() {
+                write(&[
+                    "valuation".into(),
+                    blank(),
+                    ts.to_string(),
+                    blank(),
+                    blank(),
+                    blank(),
+                    blank(),
+                    blank(),
+                    value.to_string(),
+                    blank(),
+                ])?;
+            }
+            drop(write);
+            let body = wtr.into_inner()
+                .map_err(|e| E::Internal(e.to_string()))?;
+            Ok(content::Content(ContentType::CSV, String::from_utf8(body).map_err(|e| E::Internal(e.to_string()))?))
+        }
+        _ => Err(E::ImportInvalid("unknown format".into())),
+    }
+}
+
+/// ### restore an exported json
+/// - /api/import/json?access_token={access_token}&portfolio={portfolio_id}
+/// - Content-Type: application/json
+/// - post, the body is what /api/export?format=json returns
+/// - http 200:
+/// ```js
+/// {
+///     "restored": 12 //rows which didn't exist
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[post("/import/json", format = "application/json", data = "<data>")]
+fn import_json(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    if data["version"].as_i64() != Some(1) {
+        return Err(E::ImportInvalid("unknown version".into()));
+    }
+    let portfolio_id = portfolio_param(&qs, &mysql_pool, user)?.unwrap_or(0);
+    let restored = user.restore(&mysql_pool, portfolio_id, &data)?;
+    if restored > 0 {
+        webhook::emit(
+            &mysql_pool,
+            Some(user.id),
+            webhook::HOLDINGS_CHANGED,
+            json!({
+                "action": "restore",
+                "portfolio_id": portfolio_id,
+                "restored": restored,
+            }),
+        )?;
+    }
+
+    Ok(Json(json!({
+        "restored": restored,
+    })))
+}
+
+/// ### user portfolios, including the ones shared with the user
+/// - /api/portfolios?access_token={access_token}
+/// - Content-Type: application/json
+/// - get
+/// - http 200:
+/// ```js
+/// [
+///     {"id": 0, "name": "", "role": "owner", "shared": false, "created": 0}, //the default portfolio
+///     {"id": 123, "name": "cold wallet", "role": "owner", "shared": false, "created": 123},
+///     {"id": 456, "name": "fund", "role": "viewer", "shared": true, "created": 123}, //viewer or editor
+///     ...
+/// ]
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[get("/portfolios")]
+fn portfolios(sess: Session, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let mut data = vec![json!({
+        "id": 0,
+        "name": "",
+        "role": "owner",
+        "shared": false,
+        "created": user.created,
+    })];
+    for portfolio in user.portfolios(&mysql_pool)? {
+        data.push(json!({
+            "id": portfolio.id,
+            "name": portfolio.name,
+            "role": portfolio.role.as_str(),
+            "shared": portfolio.user_id != user.id,
+            "created": portfolio.created,
+        }));
+    }
+
+    Ok(Json(json!(data)))
+}
+
+/// ### create or rename portfolio
+/// - /api/portfolios?access_token={access_token}
+/// - Content-Type: application/json
+/// - put
+/// ```js
+/// {
+///     "id": 0, //0 to create
+///     "name": "cold wallet"
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "id": 123
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[put("/portfolios", format = "application/json", data = "<data>")]
+fn put_portfolio(
+    sess: Session,
     mysql_pool: State<Pool>,
-    worker_state_lock: State<Arc<RwLock<worker::State>>>,
-    coin_id: String,
+    data: Json<Value>,
 ) -> Result<Json<Value>, E> {
-    let sess = Session::from_query_string(&mysql_pool, &qs)?;
-    sess.user()?;
-    let worker_state = &*(worker_state_lock.read().unwrap());
+    let user = sess.user()?;
+    let id = data["id"].as_i64().unwrap_or(0);
+    let name = str_field(&data, "name")?;
+    let id = user.put_portfolio(&mysql_pool, id, name)?;
 
-    let coin = worker_state.coins.iter().find(|&x| x.id == coin_id);
-    if coin.is_none() {
-        return Err(E::CoinNotFound);
+    Ok(Json(json!({
+        "id": id,
+    })))
+}
+
+/// ### delete portfolio with all its states, balance and transactions
+/// - /api/portfolios?access_token={access_token}
+/// - Content-Type: application/json
+/// - delete
+/// ```js
+/// {
+///     "id": 123
+/// }
+/// ```
+#[delete("/portfolios", format = "application/json", data = "<data>")]
+fn delete_portfolio(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    user.del_portfolio(&mysql_pool, id)?;
+
+    Ok(Json(json!(null)))
+}
+
+/// ### list members of a portfolio
+/// - /api/portfolios/{portfolio_id}/members?access_token={access_token}
+/// - get
+/// - http 200:
+/// ```js
+/// [
+///   {
+///     "user_id": 123,
+///     "name": "...",
+///     "mobile": "+8613800000000",
+///     "role": "viewer" //viewer or editor
+///   },
+///   ...
+/// ]
+/// ```
+#[get("/portfolios/<id>/members")]
+fn portfolio_members(sess: Session, mysql_pool: State<Pool>, id: i64) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let mut data = vec![];
+    for (user_id, name, mobile, role) in user.portfolio_members(&mysql_pool, id)? {
+        data.push(json!({
+            "user_id": user_id,
+            "name": name,
+            "mobile": mobile,
+            "role": role.as_str(),
+        }));
     }
-    let coin = coin.unwrap();
 
-    let end_ts = time::get_time().sec;
-    let origin_ts = end_ts - 30 * 24 * 3600;
-    let states = vec![(origin_ts, 0.0)];
-    let points = models::coin_history(&mysql_pool, &coin_id, origin_ts, end_ts, &states)?;
+    Ok(Json(json!(data)))
+}
+
+/// ### invite a registered user to a portfolio, or change the member's role. owner only
+/// - /api/portfolios/{portfolio_id}/members?access_token={access_token}
+/// - Content-Type: application/json
+/// - put
+/// ```js
+/// {
+///     "mobile": "+8613800000000", //same as /api/sms
+///     "role": "viewer" //viewer or editor
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "user_id": 123
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[put("/portfolios/<id>/members", format = "application/json", data = "<data>")]
+fn put_portfolio_member(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    config: State<Config>,
+    id: i64,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let mobile = phone_param(&data, &config)?.e164();
+    let role = Role::parse(data["role"].as_str().unwrap_or("viewer"))?;
+    let user_id = user.invite(&mysql_pool, id, &mobile, role)?;
+
+    Ok(Json(json!({
+        "user_id": user_id,
+    })))
+}
+
+/// ### remove a member from a portfolio. the owner removes anyone, a member removes itself
+/// - /api/portfolios/{portfolio_id}/members?access_token={access_token}
+/// - Content-Type: application/json
+/// - delete
+/// ```js
+/// {
+///     "user_id": 123
+/// }
+/// ```
+#[delete("/portfolios/<id>/members", format = "application/json", data = "<data>")]
+fn delete_portfolio_member(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    id: i64,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let user_id = i64_field(&data, "user_id")?;
+    user.remove_member(&mysql_pool, id, user_id)?;
+
+    Ok(Json(json!(null)))
+}
 
-    let history: Vec<(&i64, f64)> = points
+/// ### user alert rules
+/// - /api/alerts?access_token={access_token}
+/// - get
+/// - http 200:
+/// ```js
+/// [
+///   {
+///     "id": 123,
+///     "kind": "price_above",
+///     "coin_id": "bitcoin",
+///     "portfolio_id": null,
+///     "currency": "CNY",
+///     "threshold": 60000.0,
+///     "cooldown": 3600,
+///     "armed": true, //false after fired until the condition turns false
+///     "last_fired": 0,
+///     "created": 123
+///   },
+///   ...
+/// ]
+/// ```
+#[get("/alerts")]
+fn alerts(sess: Session, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let data: Vec<Value> = user.alerts(&mysql_pool)?
         .iter()
-        .map(|(k, item)| (k, item.0 * worker_state.usd2cny_rate))
+        .map(|x| x.to_json())
         .collect();
 
+    Ok(Json(json!(data)))
+}
+
+/// ### create or update alert rule
+/// - /api/alerts?access_token={access_token}
+/// - Content-Type: application/json
+/// - put
+/// ```js
+/// {
+///     "id": 0, //0 to create
+///     "kind": "price_above", //price_above, price_below, change_above, change_below, value_above, value_below
+///     "coin_id": "bitcoin", //for price and change rules
+///     "portfolio_id": 123, //for value rules, null or absent for all portfolios
+///     "currency": "CNY", //currency of threshold, user currency by default
+///     "threshold": 60000.0, //percent for change rules, e.g. -10.0
+///     "cooldown": 3600 //min seconds between two notifications, default 3600
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "id": 123
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[put("/alerts", format = "application/json", data = "<data>")]
+fn put_alert(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    worker_state_lock: State<Arc<RwLock<worker::State>>>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let kind = AlertKind::parse(str_field(&data, "kind")?)?;
+    let coin_id = if kind.on_coin() {
+        let coin_id = str_field(&data, "coin_id")?;
+        let worker_state = &*(worker_state_lock.read().unwrap());
+        if !worker_state.coins.iter().any(|x| x.id == coin_id) {
+            return Err(E::CoinNotFound);
+        }
+        coin_id.to_string()
+    } else {
+        String::new()
+    };
+    let currency = data["currency"]
+        .as_str()
+        .unwrap_or(&user.currency)
+        .to_uppercase();
+    if !worker::CURRENCIES.contains(&currency.as_str()) {
+        return Err(E::CurrencyNotSupported);
+    }
+    let alert = Alert {
+        id: data["id"].as_i64().unwrap_or(0),
+        user_id: user.id,
+        kind: kind,
+        coin_id: coin_id,
+        portfolio_id: if kind.on_coin() {
+            None
+        } else {
+            data["portfolio_id"].as_i64()
+        },
+        currency: currency,
+        threshold: f64_field(&data, "threshold")?,
+        cooldown: data["cooldown"].as_i64().unwrap_or(3600).max(0),
+        armed: true,
+        last_fired: 0,
+        created: 0,
+    };
+    let id = user.put_alert(&mysql_pool, &alert)?;
+
     Ok(Json(json!({
-        "id": coin.id,
-        "name": coin.name,
-        "symbol": coin.symbol,
-        "rank": coin.rank,
-        "price_usd": coin.price_usd,
-        "price_cny": coin.price_cny,
-        "volume_usd": coin.volume_usd,
-        "market_cap_usd": coin.market_cap_usd,
-        "percent_change_24h": coin.percent_change_24h,
-        "percent_change_7d": coin.percent_change_7d,
-        "history": history,
-        "no": coin.no,
+        "id": id,
     })))
 }
 
-#[get("/coins")]
-fn coins(worker_state_lock: State<Arc<RwLock<worker::State>>>) -> Result<Json<Value>, E> {
-    let worker_state = &*(worker_state_lock.read().unwrap());
-    let mut arr = vec![];
-    for coin in worker_state.coins.iter() {
-        arr.push(json!({
-            "id": coin.id,
-            "name": coin.name,
-            "symbol": coin.symbol,
-            "rank": coin.rank,
-            "price_usd": coin.price_usd,
-            "volume_usd": coin.volume_usd,
-            "market_cap_usd": coin.market_cap_usd,
-            "percent_change_24h": coin.percent_change_24h,
-            "percent_change_1h": coin.percent_change_1h,
-            "no": coin.no,
-        }))
+/// ### delete alert rule
+/// - /api/alerts?access_token={access_token}
+/// - Content-Type: application/json
+/// - delete
+/// ```js
+/// {
+///     "id": 123
+/// }
+/// ```
+#[delete("/alerts", format = "application/json", data = "<data>")]
+fn delete_alert(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    user.del_alert(&mysql_pool, id)?;
+
+    Ok(Json(json!(null)))
+}
+
+/// ### user webhook subscriptions
+/// - /api/webhooks?access_token={access_token}
+/// - get
+/// - http 200:
+/// ```js
+/// [
+///   {
+///     "id": 123,
+///     "url": "https://example.com/hook",
+///     "secret": "xxxx", //key of HMAC-SHA256 signature in X-Yield-Signature header
+///     "events": ["holdings.changed", "alert.fired", "coins.refreshed"],
+///     "active": true,
+///     "created": 123
+///   },
+///   ...
+/// ]
+/// ```
+#[get("/webhooks")]
+fn webhooks(sess: Session, mysql_pool: State<Pool>) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let data: Vec<Value> = user.webhooks(&mysql_pool)?
+        .iter()
+        .map(|x| x.to_json())
+        .collect();
+
+    Ok(Json(json!(data)))
+}
+
+/// ### create or update webhook subscription
+/// - /api/webhooks?access_token={access_token}
+/// - Content-Type: application/json
+/// - put
+/// ```js
+/// {
+///     "id": 0, //0 to create
+///     "url": "https://example.com/hook",
+///     "events": ["holdings.changed", "alert.fired"],
+///     "active": true
+/// }
+/// ```
+/// - the url must resolve to public addresses, webhooks are not posted into the local network
+/// - each event is posted as below with headers X-Yield-Event, X-Yield-Delivery,
+///   X-Yield-Timestamp and X-Yield-Signature: sha256={hex HMAC-SHA256 of "{timestamp}.{body}"}.
+///   A delivery is retried with backoff until it gets http 2xx, up to 8 attempts.
+/// ```js
+/// {
+///     "event": "holdings.changed",
+///     "created": 123,
+///     "data": {...}
+/// }
+/// ```
+/// - http 200:
+/// ```js
+/// {
+///     "id": 123
+/// }
+/// ```
+/// - http 400:
+/// ```js
+/// {
+///     "err": 123,
+///     "msg": "error message"
+/// }
+/// ```
+#[put("/webhooks", format = "application/json", data = "<data>")]
+fn put_webhook(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    config: State<Config>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = data["id"].as_i64().unwrap_or(0);
+    let url = str_field(&data, "url")?;
+    let mut events = vec![];
+    for event in data["events"].as_array().ok_or(E::WebhookInvalid)?.iter() {
+        events.push(event.as_str().ok_or(E::WebhookInvalid)?);
     }
-    arr.sort_by(|a, b|{
-        a["rank"].as_i64().cmp(&(b["rank"].as_i64()))
-    });
-    Ok(Json(json!(arr)))
+    let active = data["active"].as_bool().unwrap_or(true);
+    let id = user.put_webhook(
+        &mysql_pool,
+        id,
+        url,
+        &events,
+        active,
+        allow_private_hosts(&config),
+    )?;
+
+    Ok(Json(json!({
+        "id": id,
+    })))
 }
 
-#[options("/<_path..>", rank = 1)]
-fn options_all(_path: PathBuf) -> status::NoContent {
-    status::NoContent
+/// ### delete webhook subscription with its deliveries
+/// - /api/webhooks?access_token={access_token}
+/// - Content-Type: application/json
+/// - delete
+/// ```js
+/// {
+///     "id": 123
+/// }
+/// ```
+#[delete("/webhooks", format = "application/json", data = "<data>")]
+fn delete_webhook(
+    sess: Session,
+    mysql_pool: State<Pool>,
+    data: Json<Value>,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let id = i64_field(&data, "id")?;
+    user.del_webhook(&mysql_pool, id)?;
+
+    Ok(Json(json!(null)))
+}
+
+/// ### latest deliveries of a webhook
+/// - /api/webhooks/{webhook_id}/deliveries?access_token={access_token}&limit={limit}
+/// - limit is 50 by default, 200 at most, delivered and failed ones are kept for 30 days
+/// - get
+/// - http 200:
+/// ```js
+/// [
+///   {
+///     "id": 123,
+///     "event": "alert.fired",
+///     "payload": {...},
+///     "status": "delivered", //pending, delivered or failed
+///     "attempts": 1,
+///     "next_attempt": 123, //time of the next retry if pending
+///     "response_code": 200, //0 if the request failed or the host was refused
+///     "delivered": 123, //time of the last attempt
+///     "created": 123
+///   },
+///   ...
+/// ]
+/// ```
+#[get("/webhooks/<id>/deliveries")]
+fn webhook_deliveries(
+    sess: Session,
+    qs: QueryString,
+    mysql_pool: State<Pool>,
+    id: i64,
+) -> Result<Json<Value>, E> {
+    let user = sess.user()?;
+    let limit = qs.get("limit")
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(50)
+        .max(1)
+        .min(200);
+    let data = user.webhook_deliveries(&mysql_pool, id, limit)?;
+
+    Ok(Json(json!(data)))
 }
@@ -0,0 +1,364 @@
+//! Scheduled summaries of a user's holdings: how the value changed over the period, which held
+//! coins moved most, and which holdings and balance changed most.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use mysql::Pool;
+use time;
+use error::E;
+use models::{self, RateSeries, User};
+use notify::Message;
+use worker;
+
+/// how many movers and changes are listed
+const TOP_NUM: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "daily" => Some(Period::Daily),
+            "weekly" => Some(Period::Weekly),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+        }
+    }
+
+    pub fn secs(&self) -> i64 {
+        match *self {
+            Period::Daily => 86400,
+            Period::Weekly => 86400 * 7,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match *self {
+            Period::Daily => "日报",
+            Period::Weekly => "周报",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Digest {
+    pub period: Period,
+    pub currency: String,
+    pub value_start: f64,
+    pub value_end: f64,
+    /// [(SYMBOL, PRICE CHANGE PERCENT)] of held coins, biggest first
+    pub movers: Vec<(String, f64)>,
+    /// [(SYMBOL, AMOUNT CHANGE, VALUE CHANGE)], biggest value change first
+    pub changes: Vec<(String, f64, f64)>,
+    pub balance_change: f64,
+}
+
+impl Digest {
+    pub fn value_change(&self) -> f64 {
+        self.value_end - self.value_start
+    }
+
+    /// count a held coin by its amounts and prices at the start and end of the period
+    fn add_coin(
+        &mut self,
+        symbol: &str,
+        amount_start: f64,
+        amount_end: f64,
+        price_start: f64,
+        price_end: f64,
+    ) {
+        self.value_start += amount_start * price_start;
+        self.value_end += amount_end * price_end;
+        if amount_end > 0.0 && price_start > 0.0 {
+            self.movers
+                .push((symbol.to_string(), (price_end / price_start - 1.0) * 100.0));
+        }
+        if amount_end != amount_start {
+            self.changes.push((
+                symbol.to_string(),
+                amount_end - amount_start,
+                (amount_end - amount_start) * price_end,
+            ));
+        }
+    }
+
+    /// keep the biggest movers and changes, a NaN doesn't stop the ranking
+    fn rank(&mut self) {
+        self.movers.sort_by(|a, b| {
+            b.1.abs()
+                .partial_cmp(&a.1.abs())
+                .unwrap_or(Ordering::Equal)
+        });
+        self.movers.truncate(TOP_NUM);
+        self.changes.sort_by(|a, b| {
+            b.2.abs()
+                .partial_cmp(&a.2.abs())
+                .unwrap_or(Ordering::Equal)
+        });
+        self.changes.truncate(TOP_NUM);
+    }
+
+    pub fn to_message(&self, user: &User) -> Message {
+        let percent = if self.value_start > 0.0 {
+            self.value_change() / self.value_start * 100.0
+        } else {
+            0.0
+        };
+        let mut details = vec![];
+        details.push(format!(
+            "资产：{:.2}{}（{:+.2}，{:+.2}%）",
+            self.value_end,
+            self.currency,
+            self.value_change(),
+            percent
+        ));
+        if !self.movers.is_empty() {
+            let movers: Vec<String> = self.movers
+                .iter()
+                .map(|&(ref symbol, pct)| format!("{} {:+.2}%", symbol, pct))
+                .collect();
+            details.push(format!("涨跌：{}", movers.join("，")));
+        }
+        if !self.changes.is_empty() {
+            let changes: Vec<String> = self.changes
+                .iter()
+                .map(|&(ref symbol, amount, _)| format!("{} {:+}", symbol, amount))
+                .collect();
+            details.push(format!("持仓变化：{}", changes.join("，")));
+        }
+        if self.balance_change != 0.0 {
+            details.push(format!("余额变化：{:+.2}", self.balance_change));
+        }
+
+        Message::Digest {
+            name: user.name.clone(),
+            period: self.period.label().to_string(),
+            value: format!("{:.2}{}", self.value_end, self.currency),
+            change: format!("{:+.2}%", percent),
+            details: details,
+        }
+    }
+}
+
+/// summarize the user's holdings of the last period, None if nothing is held
+pub fn build(
+    mysql_pool: &Pool,
+    user: &User,
+    worker_state: &worker::State,
+    period: Period,
+) -> Result<Option<Digest>, E> {
+    let end_ts = time::get_time().sec;
+    let start_ts = end_ts - period.secs();
+    let rate = worker_state.rate(&user.currency).unwrap_or(1.0);
+    let user_states = user.states(mysql_pool, worker_state, None, None)?;
+    if user_states.is_empty() {
+        return Ok(None);
+    }
+
+    // {COIN => [(ASC TIMESTAMP, AMOUNT)]}
+    let mut coin_to_states = HashMap::<&str, Vec<(i64, f64)>>::new();
+    for state in user_states.iter() {
+        coin_to_states
+            .entry(state.coin_id.as_str())
+            .or_insert(vec![])
+            .push((state.created, state.amount));
+    }
+
+    let rates = RateSeries::load(mysql_pool, &user.currency, start_ts, end_ts, rate)?;
+    let mut digest = Digest {
+        period: period,
+        currency: user.currency.clone(),
+        value_start: 0.0,
+        value_end: 0.0,
+        movers: vec![],
+        changes: vec![],
+        balance_change: 0.0,
+    };
+    for (coin_id, states) in coin_to_states.iter() {
+        let coin = match worker_state.coins.iter().find(|x| x.id == *coin_id) {
+            Some(coin) => coin,
+            None => continue,
+        };
+        let amount_start = states
+            .iter()
+            .filter(|x| x.0 <= start_ts)
+            .last()
+            .map(|x| x.1)
+            .unwrap_or(0.0);
+        let amount_end = states.last().map(|x| x.1).unwrap_or(0.0);
+        let points = models::coin_history(
+            mysql_pool,
+            &coin_id.to_string(),
+            start_ts,
+            end_ts,
+            states,
+            &rates,
+        )?;
+        let price_start = points.values().next().map(|x| x.0).unwrap_or(0.0);
+        let price_end = coin.price_usd * rate;
+
+        digest.add_coin(&coin.symbol, amount_start, amount_end, price_start, price_end);
+    }
+    digest.rank();
+
+    // balance rows are (ASC TIMESTAMP, TOTAL AMOUNT, ID)
+    let balance = user.balance(mysql_pool, None)?;
+    let balance_start = balance
+        .iter()
+        .filter(|x| x.0 <= start_ts)
+        .last()
+        .map(|x| x.1)
+        .unwrap_or(0.0);
+    let balance_end = balance.last().map(|x| x.1).unwrap_or(0.0);
+    digest.balance_change = balance_end - balance_start;
+
+    Ok(Some(digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use models::User;
+    use notify::{Channel, Message};
+    use super::{Digest, Period, TOP_NUM};
+
+    fn digest() -> Digest {
+        Digest {
+            period: Period::Daily,
+            currency: "CNY".to_string(),
+            value_start: 0.0,
+            value_end: 0.0,
+            movers: vec![],
+            changes: vec![],
+            balance_change: 0.0,
+        }
+    }
+
+    fn user() -> User {
+        User {
+            id: 1,
+            name: "jon".to_string(),
+            mobile: "+8613800000000".to_string(),
+            currency: "CNY".to_string(),
+            email: String::new(),
+            webhook_url: String::new(),
+            notify_channel: Channel::Email,
+            digest: Some(Period::Daily),
+            timezone: User::DEFAULT_TIMEZONE.to_string(),
+            locale: User::LOCALES[0].to_string(),
+            created: 0,
+        }
+    }
+
+    fn symbols<T>(rows: &[(String, f64, T)]) -> Vec<&str> {
+        rows.iter().map(|x| x.0.as_str()).collect()
+    }
+
+    #[test]
+    fn values_at_start_and_end() {
+        let mut d = digest();
+        d.add_coin("BTC", 1.0, 1.5, 100.0, 110.0);
+        d.add_coin("ETH", 2.0, 2.0, 10.0, 5.0);
+        // sold out during the period
+        d.add_coin("LTC", 4.0, 0.0, 5.0, 6.0);
+        d.rank();
+
+        assert_eq!(d.value_start, 100.0 + 20.0 + 20.0);
+        assert_eq!(d.value_end, 165.0 + 10.0);
+        assert_eq!(d.value_change(), 35.0);
+        // only coins still held move
+        let movers: Vec<&str> = d.movers.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(movers, vec!["ETH", "BTC"]);
+        assert_eq!(d.movers[0].1, -50.0);
+        assert_eq!(symbols(&d.changes), vec!["LTC", "BTC"]);
+        assert_eq!(d.changes[0].1, -4.0);
+        assert_eq!(d.changes[0].2, -24.0);
+        assert_eq!(d.changes[1].2, 55.0);
+    }
+
+    #[test]
+    fn only_top_movers_and_changes_are_kept() {
+        let mut d = digest();
+        for (idx, symbol) in ["A", "B", "C", "D", "E"].iter().enumerate() {
+            let x = idx as f64 + 1.0;
+            d.add_coin(symbol, 1.0, 1.0 + x, 100.0, 100.0 + x);
+        }
+        d.rank();
+
+        assert_eq!(d.movers.len(), TOP_NUM);
+        let movers: Vec<&str> = d.movers.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(movers, vec!["E", "D", "C"]);
+        assert_eq!(symbols(&d.changes), vec!["E", "D", "C"]);
+    }
+
+    #[test]
+    fn nan_doesnt_stop_ranking() {
+        let mut d = digest();
+        d.add_coin("A", 1.0, 1.0, 1.0, 2.0);
+        d.movers.push(("B".to_string(), f64::NAN));
+        d.changes.push(("B".to_string(), 1.0, f64::NAN));
+        d.add_coin("C", 1.0, 2.0, 1.0, 3.0);
+        d.rank();
+
+        assert_eq!(d.movers.len(), 3);
+        assert_eq!(d.changes.len(), 2);
+    }
+
+    #[test]
+    fn message_of_digest() {
+        let mut d = digest();
+        d.add_coin("BTC", 1.0, 2.0, 100.0, 150.0);
+        d.balance_change = -12.5;
+        d.rank();
+
+        match d.to_message(&user()) {
+            Message::Digest {
+                name,
+                period,
+                value,
+                change,
+                details,
+            } => {
+                assert_eq!(name, "jon");
+                assert_eq!(period, "日报");
+                assert_eq!(value, "300.00CNY");
+                assert_eq!(change, "+200.00%");
+                assert_eq!(
+                    details,
+                    vec![
+                        "资产：300.00CNY（+200.00，+200.00%）".to_string(),
+                        "涨跌：BTC +50.00%".to_string(),
+                        "持仓变化：BTC +1".to_string(),
+                        "余额变化：-12.50".to_string(),
+                    ]
+                );
+            }
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
+    #[test]
+    fn message_of_empty_start() {
+        let mut d = digest();
+        d.add_coin("BTC", 0.0, 1.0, 0.0, 100.0);
+        d.rank();
+
+        match d.to_message(&user()) {
+            Message::Digest { change, details, .. } => {
+                assert_eq!(change, "+0.00%");
+                assert_eq!(details.len(), 2);
+            }
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+}
@@ -1,12 +1,41 @@
-use rocket::request::Request;
+use rocket::request::{self, FromRequest, Request};
 use rocket::response::{self, Responder};
 use rocket::http::{ContentType, Status};
-use rocket::Response;
+use rocket::{Outcome, Response};
+use std::cell::{Cell, RefCell};
 use std::io::Cursor;
+use uuid::Uuid;
+
+use i18n;
+use serde_json::Value;
+
+thread_local! {
+    /// saved locale of the session user of the request being handled. Rocket handles a request
+    /// on one thread from the request fairings to the response, the fairing resets it and the
+    /// session guard sets it.
+    static SESSION_LOCALE: Cell<Option<&'static str>> = Cell::new(None);
+    /// why the session guard of the request failed, told by the 401 catcher
+    static SESSION_ERROR: RefCell<Option<E>> = RefCell::new(None);
+}
+
+/// remember the saved locale of the session user, None forgets the one of the last request
+pub fn set_session_locale(locale: Option<&'static str>) {
+    SESSION_LOCALE.with(|x| x.set(locale));
+}
+
+/// remember why the session guard failed, None forgets the reason of the last request
+pub fn set_session_error(e: Option<E>) {
+    SESSION_ERROR.with(|x| *x.borrow_mut() = e);
+}
+
+/// the reason the session guard failed, if it did in this request
+pub fn take_session_error() -> Option<E> {
+    SESSION_ERROR.with(|x| x.borrow_mut().take())
+}
 
 #[derive(Debug)]
 pub enum E {
-    SmsSendLimit,
+    SmsSendLimit(i64),
     SmsSendInterval(i64),
     SmsVerifyNotFound,
     SmsVerified,
@@ -21,49 +50,243 @@ pub enum E {
     UserNotFound,
     SessionIsOwned,
     CoinNotFound,
+    CurrencyNotSupported,
+    TransactionInvalid,
+    ImportInvalid(String),
+    PortfolioNotFound,
+    PortfolioReadOnly,
+    NotifyChannelInvalid,
+    WebhookInvalid,
+    WebhookNotFound,
+    SessionNotFound,
+    AccessTokenExpired,
+    RefreshTokenInvalid,
+    RefreshTokenReused,
+    TotpNotEnrolled,
+    TotpEnrolled,
+    TotpInvalid,
+    TotpLimit,
+    StepUpRequired,
+    EmailInvalid,
+    EmailTaken,
+    EmailLoginExists,
+    EmailNotVerified,
+    EmailTokenInvalid,
+    PasswordWeak,
+    PasswordInvalid,
+    LoginLimit,
+    SmsCountryNotSupported,
+    SmsCountryLimit,
+    ProfileInvalid(String),
+    MobileTaken,
+    /// a row of states, balance or transactions which doesn't exist or the user can't see
+    RecordNotFound,
+    /// a field of the request body is missing or of a wrong type
+    ParamInvalid(String),
+    /// a service we depend on failed
+    Upstream(String),
+    /// database failure, kept for logging and never shown to clients
+    Database(mysql::Error),
+    /// bug or broken environment, the cause is logged
+    Internal(String),
+    /// mostly a missing or malformed parameter, the `?` on None ends up here
     Unknown,
 }
 
 impl E {
-    pub fn spec(&self) -> (u32, String) {
+    /// stable code for clients
+    pub fn code(&self) -> u32 {
+        match *self {
+            E::SmsSendLimit(_) => 1,
+            E::SmsSendInterval(_) => 2,
+            E::SmsVerifyNotFound => 3,
+            E::SmsVerified => 4,
+            E::SmsVerifyLimit => 5,
+            E::SmsVerifyExpired => 6,
+            E::SmsVerifyInvalid => 7,
+            E::SmsMobileInvalid => 8,
+            E::AccessTokenNotFound => 9,
+            E::AccessTokenInvalid => 10,
+            E::SessionExpired => 11,
+            E::UserNotFound => 12,
+            E::SessionIsOwned => 13,
+            E::CoinNotFound => 14,
+            E::SmsSendError => 15,
+            E::CurrencyNotSupported => 16,
+            E::TransactionInvalid => 17,
+            E::ImportInvalid(_) => 18,
+            E::PortfolioNotFound => 19,
+            E::PortfolioReadOnly => 20,
+            E::NotifyChannelInvalid => 21,
+            E::WebhookInvalid => 22,
+            E::WebhookNotFound => 23,
+            E::SessionNotFound => 24,
+            E::AccessTokenExpired => 25,
+            E::RefreshTokenInvalid => 26,
+            E::RefreshTokenReused => 27,
+            E::TotpNotEnrolled => 28,
+            E::TotpEnrolled => 29,
+            E::TotpInvalid => 30,
+            E::TotpLimit => 31,
+            E::StepUpRequired => 32,
+            E::EmailInvalid => 33,
+            E::EmailTaken => 34,
+            E::EmailLoginExists => 35,
+            E::EmailNotVerified => 36,
+            E::EmailTokenInvalid => 37,
+            E::PasswordWeak => 38,
+            E::PasswordInvalid => 39,
+            E::LoginLimit => 40,
+            E::SmsCountryNotSupported => 41,
+            E::SmsCountryLimit => 42,
+            E::ProfileInvalid(_) => 43,
+            E::MobileTaken => 44,
+            E::Upstream(_) => 45,
+            E::ParamInvalid(_) => 46,
+            E::RecordNotFound => 47,
+            // same as before they were told apart
+            E::Database(_) | E::Internal(_) | E::Unknown => 999,
+        }
+    }
+
+    /// what fills in the message
+    fn arg(&self) -> Option<String> {
         match *self {
-            E::SmsSendLimit => (
-                1,
-                "过去24小时发送的短信已超过10条，请稍后再试！".into(),
-            ),
-            E::SmsSendInterval(secs) => (
-                2,
-                format!("两次短信发送时间需间隔{secs}秒！", secs = secs),
-            ),
-            E::SmsVerifyNotFound => (3, "无验证短信！".into()),
-            E::SmsVerified => (4, "该验证码已使用！".into()),
-            E::SmsVerifyLimit => (5, "验证码尝试已超过10次！".into()),
-            E::SmsVerifyExpired => (6, "验证码已过期！".into()),
-            E::SmsVerifyInvalid => (7, "验证码错误！".into()),
-            E::SmsMobileInvalid => (8, "手机号格式错误！".into()),
-            E::AccessTokenNotFound => (9, "未授权，请先登录！".into()),
-            E::AccessTokenInvalid => (10, "授权无效，请重新登录！".into()),
-            E::SessionExpired => (11, "授权已过期，请重新登录！".into()),
-            E::UserNotFound => (12, "该用户不存在！".into()),
-            E::SessionIsOwned => (13, "会话已有所属！".into()),
-            E::CoinNotFound => (14, "加密币不存在！".into()),
-            E::SmsSendError => (15, "短信发送失败！".into()),
-            E::Unknown => (999, "未知错误！".into()),
+            E::SmsSendLimit(times) => Some(times.to_string()),
+            E::SmsSendInterval(secs) => Some(secs.to_string()),
+            E::ImportInvalid(ref reason) => Some(reason.clone()),
+            E::ProfileInvalid(ref field) => Some(field.clone()),
+            E::ParamInvalid(ref field) => Some(field.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match *self {
+            E::AccessTokenNotFound
+            | E::AccessTokenInvalid
+            | E::AccessTokenExpired
+            | E::SessionExpired
+            | E::RefreshTokenInvalid
+            | E::RefreshTokenReused
+            | E::PasswordInvalid => Status::Unauthorized,
+            E::PortfolioReadOnly | E::StepUpRequired | E::EmailNotVerified => Status::Forbidden,
+            E::UserNotFound
+            | E::CoinNotFound
+            | E::PortfolioNotFound
+            | E::WebhookNotFound
+            | E::SessionNotFound
+            | E::RecordNotFound => Status::NotFound,
+            E::SessionIsOwned
+            | E::TotpEnrolled
+            | E::EmailTaken
+            | E::EmailLoginExists
+            | E::MobileTaken => Status::Conflict,
+            E::SmsSendLimit(_)
+            | E::SmsSendInterval(_)
+            | E::SmsVerifyLimit
+            | E::SmsCountryLimit
+            | E::TotpLimit
+            | E::LoginLimit => Status::TooManyRequests,
+            E::SmsSendError | E::Upstream(_) => Status::BadGateway,
+            E::Database(_) | E::Internal(_) => Status::InternalServerError,
+            _ => Status::BadRequest,
+        }
+    }
+
+    /// structured details for clients, like the invalid field
+    pub fn details(&self) -> Option<Value> {
+        match *self {
+            E::SmsSendLimit(times) => Some(json!({ "limit": times })),
+            E::SmsSendInterval(secs) => Some(json!({ "retry_after": secs })),
+            E::ImportInvalid(ref reason) => Some(json!({ "reason": reason })),
+            E::ProfileInvalid(ref field) | E::ParamInvalid(ref field) => {
+                Some(json!({ "field": field }))
+            }
+            _ => None,
+        }
+    }
+
+    /// message from the catalog of locale
+    pub fn message(&self, locale: &str) -> String {
+        let message = i18n::message(locale, self.code());
+        match self.arg() {
+            Some(arg) => message.replace("{}", &arg),
+            None => message.to_string(),
+        }
+    }
+
+    /// code and message in the default locale
+    pub fn spec(&self) -> (u32, String) {
+        (self.code(), self.message(i18n::LOCALES[0]))
+    }
+
+    /// `Accept-Language` of the request, or the saved locale of the session user when it's
+    /// not given
+    fn locale(req: &Request) -> &'static str {
+        if let Some(accept_language) = req.headers().get_one("Accept-Language") {
+            return i18n::negotiate(accept_language).unwrap_or(i18n::LOCALES[0]);
         }
+        SESSION_LOCALE
+            .with(|x| x.get())
+            .unwrap_or(i18n::LOCALES[0])
     }
 }
 
+/// locale of messages for the request, negotiated the same way as the one of errors
+pub struct Locale(pub &'static str);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Locale {
+    type Error = E;
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Locale, E> {
+        Outcome::Success(Locale(E::locale(req)))
+    }
+}
+
+/// id of the request from the reverse proxy, or a new one
+fn request_id(req: &Request) -> String {
+    match req.headers().get_one("X-Request-Id") {
+        Some(id) if !id.is_empty() && id.len() <= 64
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            id.to_string()
+        }
+        _ => Uuid::new_v4().simple().to_string(),
+    }
+}
+
+/// The body is `{"err": code, "msg": message, "request_id": id}` with `details` when there are,
+/// the request id is also in `X-Request-Id` header. Failures of the server are logged with the
+/// request id.
 impl<'r> Responder<'r> for E {
-    fn respond_to(self, _: &Request) -> response::Result<'r> {
-        let (err, msg): (u32, String) = self.spec();
-        let body = json!({
-            "err": err,
-            "msg": msg,
-        }).to_string();
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let status = self.status();
+        let request_id = request_id(req);
+        match self {
+            E::Database(ref e) => {
+                error!("[{}] {} {}: mysql: {}", request_id, req.method(), req.uri(), e)
+            }
+            E::Upstream(ref e) | E::Internal(ref e) => {
+                error!("[{}] {} {}: {}", request_id, req.method(), req.uri(), e)
+            }
+            _ => (),
+        }
+        let mut body = json!({
+            "err": self.code(),
+            "msg": self.message(E::locale(req)),
+            "request_id": request_id,
+        });
+        if let Some(details) = self.details() {
+            body["details"] = details;
+        }
         let mut res = Response::new();
-        res.set_status(Status::BadRequest);
-        res.set_sized_body(Cursor::new(body));
+        res.set_status(status);
+        res.set_sized_body(Cursor::new(body.to_string()));
         res.set_header(ContentType::JSON);
+        res.set_raw_header("X-Request-Id", request_id);
+        if let E::SmsSendInterval(secs) = self {
+            res.set_raw_header("Retry-After", secs.to_string());
+        }
 
         Ok(res)
     }
@@ -84,38 +307,104 @@ impl From<option::NoneError> for E {
 }
 
 impl From<regex::Error> for E {
-    fn from(_: regex::Error) -> Self {
-        E::Unknown
+    fn from(e: regex::Error) -> Self {
+        E::Internal(format!("regex: {}", e))
     }
 }
 
 impl<T> From<sync::PoisonError<T>> for E {
     fn from(_: sync::PoisonError<T>) -> Self {
-        E::Unknown
+        E::Internal("lock poisoned".to_string())
     }
 }
 
 impl<T> From<mpsc::SendError<T>> for E {
     fn from(_: mpsc::SendError<T>) -> Self {
-        E::Unknown
+        E::Internal("notify worker was down".to_string())
     }
 }
 
 impl From<mysql::Error> for E {
     fn from(e: mysql::Error) -> Self {
-        println!("{:?}", e);
-        E::Unknown
+        E::Database(e)
     }
 }
 
+// tokens map their decoding errors to AccessTokenInvalid themselves, others are our bugs
 impl From<str::Utf8Error> for E {
-    fn from(_: str::Utf8Error) -> Self {
-        E::Unknown
+    fn from(e: str::Utf8Error) -> Self {
+        E::Internal(format!("utf8: {}", e))
     }
 }
 
 impl From<symmetriccipher::SymmetricCipherError> for E {
-    fn from(_: symmetriccipher::SymmetricCipherError) -> Self {
-        E::Unknown
+    fn from(e: symmetriccipher::SymmetricCipherError) -> Self {
+        E::Internal(format!("cipher: {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status;
+    use crypto::symmetriccipher::SymmetricCipherError;
+    use std::str;
+    use super::{set_session_error, take_session_error, E};
+
+    #[test]
+    fn status_of_errors() {
+        let cases = vec![
+            (E::SmsVerifyInvalid, Status::BadRequest, 7),
+            (E::ParamInvalid("amount".to_string()), Status::BadRequest, 46),
+            (E::AccessTokenExpired, Status::Unauthorized, 25),
+            (E::PasswordInvalid, Status::Unauthorized, 39),
+            (E::StepUpRequired, Status::Forbidden, 32),
+            (E::RecordNotFound, Status::NotFound, 47),
+            (E::WebhookNotFound, Status::NotFound, 23),
+            (E::MobileTaken, Status::Conflict, 44),
+            (E::SmsSendInterval(60), Status::TooManyRequests, 2),
+            (E::LoginLimit, Status::TooManyRequests, 40),
+            (E::SmsSendError, Status::BadGateway, 15),
+            (E::Upstream("coingecko".to_string()), Status::BadGateway, 45),
+            (E::Internal("bug".to_string()), Status::InternalServerError, 999),
+        ];
+        for (e, status, code) in cases {
+            assert_eq!(e.status(), status, "{:?}", e);
+            assert_eq!(e.code(), code, "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn details_of_errors() {
+        assert_eq!(E::SmsSendInterval(60).details(), Some(json!({ "retry_after": 60 })));
+        assert_eq!(E::SmsSendLimit(10).details(), Some(json!({ "limit": 10 })));
+        assert_eq!(
+            E::ParamInvalid("amount".to_string()).details(),
+            Some(json!({ "field": "amount" }))
+        );
+        assert_eq!(E::RecordNotFound.details(), None);
+        assert_eq!(E::ParamInvalid("amount".to_string()).message("en-US"), "Invalid parameter: amount");
+    }
+
+    #[test]
+    fn internal_failures_are_not_bad_requests() {
+        let utf8 = str::from_utf8(&[0xff]).unwrap_err();
+        assert_eq!(E::from(utf8).status(), Status::InternalServerError);
+        let cipher = E::from(SymmetricCipherError::InvalidPadding);
+        assert_eq!(cipher.status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn session_error_is_told_once() {
+        set_session_error(Some(E::SessionExpired));
+        match take_session_error() {
+            Some(E::SessionExpired) => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        assert!(take_session_error().is_none());
+
+        // the next request starts without one
+        set_session_error(Some(E::AccessTokenNotFound));
+        set_session_error(None);
+        assert!(take_session_error().is_none());
     }
 }
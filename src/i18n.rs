@@ -0,0 +1,216 @@
+/// supported locales, the first one is the default
+pub const LOCALES: &'static [&'static str] = &["zh-CN", "en-US"];
+
+/// messages of error codes, `{}` is replaced by the argument of the error
+const ZH_CN: &'static [(u32, &'static str)] = &[
+    (1, "过去24小时发送的短信已超过{}条，请稍后再试！"),
+    (2, "两次短信发送时间需间隔{}秒！"),
+    (3, "无验证短信！"),
+    (4, "该验证码已使用！"),
+    (5, "验证码尝试已超过10次！"),
+    (6, "验证码已过期！"),
+    (7, "验证码错误！"),
+    (8, "手机号格式错误！"),
+    (9, "未授权，请先登录！"),
+    (10, "授权无效，请重新登录！"),
+    (11, "授权已过期，请重新登录！"),
+    (12, "该用户不存在！"),
+    (13, "会话已有所属！"),
+    (14, "加密币不存在！"),
+    (15, "短信发送失败！"),
+    (16, "不支持该货币！"),
+    (17, "交易记录无效！"),
+    (18, "导入文件无效：{}"),
+    (19, "投资组合不存在！"),
+    (20, "无权修改该投资组合！"),
+    (21, "通知方式或地址无效！"),
+    (22, "Webhook地址或事件无效！"),
+    (23, "Webhook不存在！"),
+    (24, "会话不存在！"),
+    (25, "授权已过期，请刷新授权！"),
+    (26, "刷新凭证无效，请重新登录！"),
+    (27, "刷新凭证已被使用，请重新登录！"),
+    (28, "未启用动态验证码！"),
+    (29, "已启用动态验证码！"),
+    (30, "动态验证码错误！"),
+    (31, "验证码错误次数过多，请10分钟后再试！"),
+    (32, "请先验证动态验证码！"),
+    (33, "邮箱格式错误！"),
+    (34, "该邮箱已被注册！"),
+    (35, "已绑定邮箱登录！"),
+    (36, "邮箱尚未验证，请查收验证邮件！"),
+    (37, "验证链接无效或已过期！"),
+    (38, "密码至少需要8位！"),
+    (39, "邮箱或密码错误！"),
+    (40, "登录失败次数过多，请10分钟后再试！"),
+    (41, "暂不支持该国家或地区的手机号！"),
+    (42, "该地区短信发送过于频繁，请稍后再试！"),
+    (43, "资料无效：{}"),
+    (44, "该手机号已被其他用户绑定！"),
+    (45, "外部服务暂时不可用，请稍后再试！"),
+    (46, "参数无效：{}"),
+    (47, "记录不存在！"),
+    (999, "未知错误！"),
+];
+
+const EN_US: &'static [(u32, &'static str)] = &[
+    (1, "More than {} messages were sent in the last 24 hours, please try again later."),
+    (2, "Please wait {} seconds before requesting another code."),
+    (3, "No verification code was sent."),
+    (4, "The verification code has been used."),
+    (5, "Too many attempts, more than 10 codes were wrong."),
+    (6, "The verification code has expired."),
+    (7, "The verification code is wrong."),
+    (8, "The mobile number is invalid."),
+    (9, "Unauthorized, please log in."),
+    (10, "Invalid authorization, please log in again."),
+    (11, "Authorization expired, please log in again."),
+    (12, "The user does not exist."),
+    (13, "The session already belongs to a user."),
+    (14, "The coin does not exist."),
+    (15, "Failed to send the message."),
+    (16, "The currency is not supported."),
+    (17, "The transaction is invalid."),
+    (18, "The import file is invalid: {}"),
+    (19, "The portfolio does not exist."),
+    (20, "You are not allowed to change this portfolio."),
+    (21, "The notification channel or address is invalid."),
+    (22, "The webhook url or events are invalid."),
+    (23, "The webhook does not exist."),
+    (24, "The session does not exist."),
+    (25, "Authorization expired, please refresh it."),
+    (26, "The refresh token is invalid, please log in again."),
+    (27, "The refresh token has been used, please log in again."),
+    (28, "Authenticator codes are not enabled."),
+    (29, "Authenticator codes are already enabled."),
+    (30, "The authenticator code is wrong."),
+    (31, "Too many wrong codes, please try again in 10 minutes."),
+    (32, "Please verify an authenticator code first."),
+    (33, "The email address is invalid."),
+    (34, "The email address is already registered."),
+    (35, "An email login is already linked."),
+    (36, "The email address is not verified yet, please check your inbox."),
+    (37, "The verification link is invalid or has expired."),
+    (38, "The password must have at least 8 characters."),
+    (39, "Wrong email or password."),
+    (40, "Too many failed logins, please try again in 10 minutes."),
+    (41, "Mobile numbers of this country or region are not supported yet."),
+    (42, "Too many messages to this region, please try again later."),
+    (43, "Invalid profile: {}"),
+    (44, "The mobile number is bound to another user."),
+    (45, "An upstream service is unavailable, please try again later."),
+    (46, "Invalid parameter: {}"),
+    (47, "The record does not exist."),
+    (999, "Unknown error."),
+];
+
+fn catalog(locale: &str) -> &'static [(u32, &'static str)] {
+    match locale {
+        "en-US" => EN_US,
+        _ => ZH_CN,
+    }
+}
+
+/// message of the error code in locale, the default locale fills in missing ones
+pub fn message(locale: &str, code: u32) -> &'static str {
+    let find = |catalog: &'static [(u32, &'static str)]| {
+        catalog.iter().find(|x| x.0 == code).map(|x| x.1)
+    };
+    find(catalog(locale))
+        .or_else(|| find(ZH_CN))
+        .unwrap_or("未知错误！")
+}
+
+/// the supported locale of a tag like "en", "en-GB" or "zh-Hans-CN"
+pub fn match_locale(tag: &str) -> Option<&'static str> {
+    let tag = tag.trim();
+    if let Some(locale) = LOCALES.iter().find(|x| x.eq_ignore_ascii_case(tag)) {
+        return Some(*locale);
+    }
+    let language = tag.split(|c| c == '-' || c == '_').next().unwrap_or("");
+    LOCALES
+        .iter()
+        .find(|x| x.split('-').next().unwrap_or("").eq_ignore_ascii_case(language))
+        .map(|x| *x)
+}
+
+/// pick a supported locale from `Accept-Language` like "en-US,en;q=0.9,zh;q=0.8"
+pub fn negotiate(accept_language: &str) -> Option<&'static str> {
+    let mut tags: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next().unwrap_or("").trim();
+            let q = parts
+                .filter_map(|x| {
+                    let x = x.trim();
+                    if x.starts_with("q=") {
+                        x[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            (tag, q)
+        })
+        .filter(|&(tag, q)| !tag.is_empty() && tag != "*" && q > 0.0)
+        .collect();
+    // stable, so tags of the same weight keep their order
+    tags.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(::std::cmp::Ordering::Equal));
+
+    tags.iter().filter_map(|&(tag, _)| match_locale(tag)).next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{match_locale, message, negotiate, EN_US, LOCALES, ZH_CN};
+
+    #[test]
+    fn catalogs_have_the_same_codes() {
+        let codes = |catalog: &[(u32, &str)]| catalog.iter().map(|x| x.0).collect::<Vec<_>>();
+        assert_eq!(codes(ZH_CN), codes(EN_US));
+        // error codes are numbered without gaps
+        let codes = codes(ZH_CN);
+        assert_eq!(codes[..codes.len() - 1], (1..codes.len() as u32).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn tags_match_by_language() {
+        assert_eq!(match_locale("EN-us"), Some("en-US"));
+        assert_eq!(match_locale("en-GB"), Some("en-US"));
+        assert_eq!(match_locale("en"), Some("en-US"));
+        assert_eq!(match_locale("zh-Hans-CN"), Some("zh-CN"));
+        assert_eq!(match_locale("zh_TW"), Some("zh-CN"));
+        assert_eq!(match_locale("fr-FR"), None);
+        assert_eq!(match_locale(""), None);
+    }
+
+    #[test]
+    fn heaviest_supported_tag_wins() {
+        assert_eq!(negotiate("zh-CN;q=0.5,en-GB;q=0.9"), Some("en-US"));
+        assert_eq!(negotiate("en;q=0.8,zh-Hans-CN"), Some("zh-CN"));
+        // same weight keeps the order of the header
+        assert_eq!(negotiate("en-US, zh-CN"), Some("en-US"));
+        // unsupported tags are passed over
+        assert_eq!(negotiate("fr-FR,de;q=0.9,en;q=0.1"), Some("en-US"));
+        assert_eq!(negotiate("en;q=abc"), Some("en-US"));
+    }
+
+    #[test]
+    fn wildcard_and_refused_tags_are_skipped() {
+        assert_eq!(negotiate("*,en;q=0.5"), Some("en-US"));
+        assert_eq!(negotiate("zh;q=0,en;q=0.1"), Some("en-US"));
+        assert_eq!(negotiate("*"), None);
+        assert_eq!(negotiate("en;q=0"), None);
+    }
+
+    #[test]
+    fn unsupported_locales_fall_back_to_default() {
+        assert_eq!(negotiate("fr-FR,de;q=0.9"), None);
+        assert_eq!(negotiate(""), None);
+        assert_eq!(message("fr-FR", 47), message(LOCALES[0], 47));
+        assert_eq!(message("en-US", 47), "The record does not exist.");
+        assert_eq!(message("en-US", 12345), "未知错误！");
+    }
+}
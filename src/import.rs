@@ -0,0 +1,476 @@
+//! Parse trade history csv exported from exchanges into ledger transactions. Every row is
+//! fingerprinted, so importing the same file twice doesn't double the holdings. Trades quoted
+//! in another coin are converted to usd by that coin's price at the trade time.
+
+use std::collections::HashMap;
+use csv;
+use time;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use serde_json::Value;
+
+use models::{Transaction, TxKind};
+use worker;
+
+/// quote currencies which are taken as usd
+const USD_LIKE: [&'static str; 5] = ["USD", "USDT", "USDC", "TUSD", "PAX"];
+
+/// column names of generic layout, mapped from the query string
+#[derive(Debug, Default)]
+pub struct Mapping {
+    pub date: String,
+    /// strptime format, or unix seconds if empty
+    pub date_format: String,
+    pub symbol: String,
+    pub kind: String,
+    pub amount: String,
+    pub price: String,
+    pub fee: String,
+}
+
+#[derive(Debug)]
+pub enum Layout {
+    /// Date(UTC),Market,Type,Price,Amount,Total,Fee,Fee Coin
+    Binance,
+    /// Timestamp,Transaction Type,Asset,Quantity Transacted,USD Spot Price at Transaction,...
+    Coinbase,
+    Generic(Mapping),
+}
+
+impl Layout {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Layout::Binance => "binance",
+            Layout::Coinbase => "coinbase",
+            Layout::Generic(_) => "generic",
+        }
+    }
+}
+
+/// one transaction parsed from a csv record
+#[derive(Debug)]
+pub struct Row {
+    /// 1-based record number, header excluded
+    pub line: usize,
+    pub symbol: String,
+    pub tx: Option<Transaction>,
+    pub hash: String,
+    pub duplicate: bool,
+    pub error: Option<String>,
+}
+
+/// usd price of a coin id at a unix time, None if unknown
+pub type PriceAt<'a> = &'a Fn(&str, i64) -> Option<f64>;
+
+impl Row {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "line": self.line,
+            "symbol": self.symbol,
+            "transaction": self.tx.as_ref().map(Transaction::to_json),
+            "duplicate": self.duplicate,
+            "unpriced": self.unpriced(),
+            "error": self.error,
+        })
+    }
+
+    /// coin received or sold without a usd price, its cost basis would be unknown
+    pub fn unpriced(&self) -> bool {
+        self.tx.as_ref().map_or(false, |tx| {
+            tx.price_usd == 0.0 && (tx.kind.sign() > 0.0 || tx.kind == TxKind::Sell)
+        })
+    }
+}
+
+fn parse_time(s: &str, format: &str) -> Result<i64, String> {
+    if format.is_empty() {
+        return s.trim()
+            .parse::<i64>()
+            .map_err(|_| format!("invalid timestamp: {}", s));
+    }
+    time::strptime(s.trim(), format)
+        .map(|tm| tm.to_timespec().sec)
+        .map_err(|_| format!("invalid date: {}", s))
+}
+
+fn parse_f64(s: &str) -> Result<f64, String> {
+    let cleaned: String = s.chars().filter(|&c| c != ',' && c != '$').collect();
+    cleaned
+        .trim()
+        .parse::<f64>()
+        .map(|v| v.abs())
+        .map_err(|_| format!("invalid number: {}", s))
+}
+
+fn parse_kind(s: &str) -> Result<TxKind, String> {
+    let lower = s.trim().to_lowercase();
+    match lower.as_str() {
+        "send" => Ok(TxKind::Withdrawal),
+        "receive" => Ok(TxKind::Deposit),
+        "rewards income" | "coinbase earn" | "learning reward" => Ok(TxKind::Airdrop),
+        "staking income" | "staking" | "reward" => Ok(TxKind::Staking),
+        _ => TxKind::parse(&lower).ok_or(format!("unknown type: {}", s)),
+    }
+}
+
+/// split a market like BTCUSDT into (BTC, USDT) by the known quote suffixes
+fn split_market(market: &str) -> Option<(String, String)> {
+    let market = market.trim().to_uppercase().replace("/", "").replace("-", "");
+    for quote in ["USDT", "USDC", "TUSD", "BUSD", "PAX", "BTC", "ETH", "BNB", "USD"].iter() {
+        if market.ends_with(quote) && market.len() > quote.len() {
+            let base = &market[..market.len() - quote.len()];
+            return Some((base.to_string(), quote.to_string()));
+        }
+    }
+    None
+}
+
+/// fingerprint of a record, `nth` tells identical records in the same file apart
+fn fingerprint(layout: &Layout, record: &csv::StringRecord, nth: usize, part: &str) -> String {
+    let mut sh = Sha1::new();
+    let fields: Vec<&str> = record.iter().map(|f| f.trim()).collect();
+    sh.input_str(&format!("{}|{}|{}|{}", layout.name(), fields.join(","), nth, part));
+    sh.result_str()
+}
+
+/// a transaction in the ledger of the coin with symbol
+fn resolve(
+    worker_state: &worker::State,
+    symbol: &str,
+    kind: TxKind,
+    amount: f64,
+    price_usd: f64,
+    fee_usd: f64,
+    created: i64,
+) -> Result<Transaction, String> {
+    let coin = worker_state
+        .coin_by_symbol(symbol)
+        .ok_or(format!("unknown symbol: {}", symbol))?;
+    if amount <= 0.0 {
+        return Err("amount should be positive".into());
+    }
+
+    Ok(Transaction {
+        id: 0,
+        portfolio_id: 0,
+        coin_id: coin.id.clone(),
+        kind: kind,
+        amount: amount,
+        price_usd: price_usd,
+        fee_usd: fee_usd,
+        wallet: String::new(),
+        to_wallet: String::new(),
+        created: created,
+    })
+}
+
+/// parse a record into (SYMBOL, TRANSACTION) pairs, a trade against a crypto quote changes
+/// holdings of both coins
+fn parse_record(
+    layout: &Layout,
+    header: &HashMap<String, usize>,
+    record: &csv::StringRecord,
+    worker_state: &worker::State,
+    price_at: PriceAt,
+) -> Result<Vec<(String, Result<Transaction, String>)>, String> {
+    let field = |name: &str| -> Result<&str, String> {
+        header
+            .get(&name.to_lowercase())
+            .and_then(|&idx| record.get(idx))
+            .ok_or(format!("missing column: {}", name))
+    };
+
+    match *layout {
+        Layout::Binance => {
+            let created = parse_time(field("Date(UTC)")?, "%Y-%m-%d %H:%M:%S")?;
+            let (base, quote) = split_market(field("Market")?).ok_or("unknown market")?;
+            let kind = parse_kind(field("Type")?)?;
+            let price = parse_f64(field("Price")?)?;
+            let amount = parse_f64(field("Amount")?)?;
+            let total = parse_f64(field("Total")?)?;
+            let fee = parse_f64(field("Fee")?)?;
+            let fee_coin = field("Fee Coin")?.trim().to_uppercase();
+
+            // usd price of a coin by symbol at the trade time, 0 if unknown
+            let usd_of = |symbol: &str| -> f64 {
+                if USD_LIKE.contains(&symbol) {
+                    return 1.0;
+                }
+                worker_state
+                    .coin_by_symbol(symbol)
+                    .and_then(|coin| price_at(coin.id.as_str(), created))
+                    .unwrap_or(0.0)
+            };
+            let quote_is_usd = USD_LIKE.contains(&quote.as_str());
+            let quote_usd = usd_of(&quote);
+            let price_usd = price * quote_usd;
+            let fee_usd = if fee_coin == base {
+                fee * price_usd
+            } else if fee_coin == quote {
+                fee * quote_usd
+            } else {
+                fee * usd_of(&fee_coin)
+            };
+
+            let mut ret = vec![(
+                base.clone(),
+                resolve(worker_state, &base, kind, amount, price_usd, fee_usd, created),
+            )];
+            if !quote_is_usd {
+                let counter = if kind == TxKind::Buy {
+                    TxKind::Sell
+                } else {
+                    TxKind::Buy
+                };
+                ret.push((
+                    quote.clone(),
+                    resolve(worker_state, &quote, counter, total, quote_usd, 0.0, created),
+                ));
+            }
+            Ok(ret)
+        }
+        Layout::Coinbase => {
+            let created = parse_time(field("Timestamp")?, "%Y-%m-%dT%H:%M:%SZ")?;
+            let kind = parse_kind(field("Transaction Type")?)?;
+            let symbol = field("Asset")?.trim().to_uppercase();
+            let amount = parse_f64(field("Quantity Transacted")?)?;
+            let price_usd = parse_f64(field("USD Spot Price at Transaction")?)?;
+            let fee_usd = field("USD Fees")
+                .and_then(parse_f64)
+                .unwrap_or(0.0);
+
+            let tx = resolve(worker_state, &symbol, kind, amount, price_usd, fee_usd, created);
+            Ok(vec![(symbol, tx)])
+        }
+        Layout::Generic(ref m) => {
+            let created = parse_time(field(&m.date)?, &m.date_format)?;
+            let symbol = field(&m.symbol)?.trim().to_uppercase();
+            let kind = parse_kind(field(&m.kind)?)?;
+            let amount = parse_f64(field(&m.amount)?)?;
+            let price_usd = if m.price.is_empty() {
+                0.0
+            } else {
+                parse_f64(field(&m.price)?)?
+            };
+            let fee_usd = if m.fee.is_empty() {
+                0.0
+            } else {
+                parse_f64(field(&m.fee)?)?
+            };
+
+            let tx = resolve(worker_state, &symbol, kind, amount, price_usd, fee_usd, created);
+            Ok(vec![(symbol, tx)])
+        }
+    }
+}
+
+/// parse csv content with header, rows are not checked for duplicates yet
+pub fn parse(
+    layout: &Layout,
+    content: &str,
+    worker_state: &worker::State,
+    price_at: PriceAt,
+) -> Result<Vec<Row>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+    let header: HashMap<String, usize> = reader
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .enumerate()
+        .map(|(idx, name)| (name.to_lowercase(), idx))
+        .collect();
+
+    let mut rows = vec![];
+    // {RECORD => TIMES SEEN}
+    let mut seen = HashMap::<Vec<String>, usize>::new();
+    for (idx, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| e.to_string())?;
+        let nth = {
+            let key: Vec<String> = record.iter().map(|f| f.to_string()).collect();
+            let times = seen.entry(key).or_insert(0);
+            *times += 1;
+            *times
+        };
+
+        let parsed = match parse_record(layout, &header, &record, worker_state, price_at) {
+            Ok(parsed) => parsed,
+            Err(e) => vec![(String::new(), Err(e))],
+        };
+        for (part, (symbol, ret)) in parsed.into_iter().enumerate() {
+            let (tx, error) = match ret {
+                Ok(tx) => (Some(tx), None),
+                Err(e) => (None, Some(e)),
+            };
+            rows.push(Row {
+                line: idx + 1,
+                symbol: symbol,
+                tx: tx,
+                hash: fingerprint(layout, &record, nth, &part.to_string()),
+                duplicate: false,
+                error: error,
+            });
+        }
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use models::TxKind;
+    use stub;
+    use super::{parse, parse_kind, split_market, Layout, Mapping, Row};
+
+    const BINANCE: &'static str = "Date(UTC),Market,Type,Price,Amount,Total,Fee,Fee Coin\n";
+
+    fn btc_at_40000(coin_id: &str, _created: i64) -> Option<f64> {
+        if coin_id == "bitcoin" {
+            Some(40000.0)
+        } else {
+            None
+        }
+    }
+
+    fn unknown_price(_coin_id: &str, _created: i64) -> Option<f64> {
+        None
+    }
+
+    fn approx(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn binance(lines: &str) -> Vec<Row> {
+        parse(&Layout::Binance, &format!("{}{}", BINANCE, lines), &stub::state(), &btc_at_40000)
+            .unwrap()
+    }
+
+    #[test]
+    fn markets_split_by_quote() {
+        assert_eq!(split_market("BTCUSDT"), Some(("BTC".to_string(), "USDT".to_string())));
+        assert_eq!(split_market("eth/btc"), Some(("ETH".to_string(), "BTC".to_string())));
+        assert_eq!(split_market("ETH-USD"), Some(("ETH".to_string(), "USD".to_string())));
+        assert_eq!(split_market("USDT"), None);
+        assert_eq!(split_market("BTCXYZ"), None);
+    }
+
+    #[test]
+    fn kinds_of_exchanges() {
+        assert_eq!(parse_kind("Send").unwrap(), TxKind::Withdrawal);
+        assert_eq!(parse_kind("Receive").unwrap(), TxKind::Deposit);
+        assert_eq!(parse_kind("Coinbase Earn").unwrap(), TxKind::Airdrop);
+        assert_eq!(parse_kind("Staking Income").unwrap(), TxKind::Staking);
+        assert_eq!(parse_kind(" BUY ").unwrap(), TxKind::Buy);
+        assert!(parse_kind("margin").is_err());
+    }
+
+    #[test]
+    fn binance_usd_quoted_trade() {
+        let rows = binance("2021-03-01 10:00:00,BTCUSDT,BUY,50000,0.1,5000,5,USDT\n");
+        assert_eq!(rows.len(), 1);
+        let tx = rows[0].tx.as_ref().unwrap();
+        assert_eq!(rows[0].line, 1);
+        assert_eq!(tx.coin_id, "bitcoin");
+        assert_eq!(tx.kind, TxKind::Buy);
+        assert_eq!(tx.amount, 0.1);
+        assert_eq!(tx.price_usd, 50000.0);
+        assert_eq!(tx.fee_usd, 5.0);
+        assert_eq!(tx.created, 1614592800);
+        assert!(!rows[0].unpriced());
+    }
+
+    #[test]
+    fn binance_crypto_quoted_trade_has_a_counter_leg() {
+        let rows = binance("2021-03-01 10:00:00,ETHBTC,SELL,0.05,2,0.1,0.0001,BTC\n");
+        assert_eq!(rows.len(), 2);
+        let eth = rows[0].tx.as_ref().unwrap();
+        assert_eq!(eth.coin_id, "ethereum");
+        assert_eq!(eth.kind, TxKind::Sell);
+        assert_eq!(eth.amount, 2.0);
+        assert!(approx(eth.price_usd, 2000.0));
+        // fee paid in the quote coin
+        assert!(approx(eth.fee_usd, 4.0));
+        // the btc received for it
+        let btc = rows[1].tx.as_ref().unwrap();
+        assert_eq!(btc.coin_id, "bitcoin");
+        assert_eq!(btc.kind, TxKind::Buy);
+        assert_eq!(btc.amount, 0.1);
+        assert_eq!(btc.price_usd, 40000.0);
+        assert_ne!(rows[0].hash, rows[1].hash);
+    }
+
+    #[test]
+    fn trades_of_unknown_quote_price_are_flagged() {
+        let content = format!("{}2021-03-01 10:00:00,ETHBTC,BUY,0.05,2,0.1,0,BTC\n", BINANCE);
+        let rows = parse(&Layout::Binance, &content, &stub::state(), &unknown_price).unwrap();
+        assert_eq!(rows[0].tx.as_ref().unwrap().price_usd, 0.0);
+        assert!(rows[0].unpriced());
+        assert!(rows[1].unpriced());
+    }
+
+    #[test]
+    fn coinbase_columns() {
+        let content = "Timestamp,Transaction Type,Asset,Quantity Transacted,\
+                       USD Spot Price at Transaction,USD Subtotal,USD Total,USD Fees,Notes\n\
+                       2021-03-01T10:00:00Z,Buy,ETH,1.5,\"1,500.00\",2250,2260,10,\n\
+                       2021-03-01T11:00:00Z,Receive,BTC,0.2,40000,,,,\n\
+                       2021-03-01T12:00:00Z,Buy,DOGE,100,0.05,5,5,0,\n";
+        let rows = parse(&Layout::Coinbase, content, &stub::state(), &unknown_price).unwrap();
+        assert_eq!(rows.len(), 3);
+        let eth = rows[0].tx.as_ref().unwrap();
+        assert_eq!(eth.coin_id, "ethereum");
+        assert_eq!(eth.kind, TxKind::Buy);
+        assert_eq!(eth.amount, 1.5);
+        assert_eq!(eth.price_usd, 1500.0);
+        assert_eq!(eth.fee_usd, 10.0);
+        assert_eq!(eth.created, 1614592800);
+        let btc = rows[1].tx.as_ref().unwrap();
+        assert_eq!(btc.kind, TxKind::Deposit);
+        assert_eq!(btc.fee_usd, 0.0);
+        // not in the market data
+        assert!(rows[2].tx.is_none());
+        assert_eq!(rows[2].error, Some("unknown symbol: DOGE".to_string()));
+    }
+
+    #[test]
+    fn generic_columns_by_mapping() {
+        let mapping = Mapping {
+            date: "time".to_string(),
+            date_format: String::new(),
+            symbol: "coin".to_string(),
+            kind: "side".to_string(),
+            amount: "qty".to_string(),
+            price: "usd".to_string(),
+            fee: String::new(),
+        };
+        let content = "Time,Coin,Side,Qty,USD\n1614592800,btc,staking,0.01,$40000\n";
+        let rows = parse(&Layout::Generic(mapping), content, &stub::state(), &unknown_price)
+            .unwrap();
+        let tx = rows[0].tx.as_ref().unwrap();
+        assert_eq!(tx.coin_id, "bitcoin");
+        assert_eq!(tx.kind, TxKind::Staking);
+        assert_eq!(tx.amount, 0.01);
+        assert_eq!(tx.price_usd, 40000.0);
+        assert_eq!(tx.fee_usd, 0.0);
+        assert_eq!(tx.created, 1614592800);
+    }
+
+    #[test]
+    fn reimports_have_the_same_hashes() {
+        let lines = "2021-03-01 10:00:00,BTCUSDT,BUY,50000,0.1,5000,0,BTC\n\
+                     2021-03-01 10:00:00,BTCUSDT,BUY,50000,0.1,5000,0,BTC\n\
+                     2021-03-02 10:00:00,ETHBTC,BUY,0.05,1,0.05,0,ETH\n";
+        let hashes = |rows: Vec<Row>| rows.into_iter().map(|x| x.hash).collect::<Vec<_>>();
+        let first = hashes(binance(lines));
+        assert_eq!(first.len(), 4);
+        // the same file again is detected by its hashes
+        assert_eq!(first, hashes(binance(lines)));
+        // identical trades in a file are different trades
+        assert_ne!(first[0], first[1]);
+        // the file extended later keeps the hashes of its old lines
+        let extended = format!("{}2021-03-03 10:00:00,BTCUSDT,SELL,51000,0.1,5100,0,USDT\n", lines);
+        assert_eq!(hashes(binance(&extended))[..4], first[..]);
+    }
+}
@@ -2,9 +2,12 @@
 #![plugin(rocket_codegen)]
 
 extern crate crypto;
+extern crate csv;
 extern crate futures;
 extern crate hyper;
 extern crate hyper_tls;
+#[macro_use]
+extern crate log;
 extern crate mysql;
 extern crate r2d2;
 extern crate rand;
@@ -14,6 +17,8 @@ extern crate rocket_contrib;
 extern crate rustc_serialize;
 extern crate serde;
 #[macro_use]
+extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 extern crate time;
 extern crate tokio_core;
@@ -26,6 +31,18 @@ mod error;
 mod models;
 mod alisms;
 mod hmac_sha1;
+mod i18n;
+mod provider;
+mod phone;
+mod pnl;
+mod import;
+mod notify;
+mod digest;
+mod webhook;
+mod token;
+mod totp;
+#[cfg(test)]
+mod stub;
 
 use std::{thread, time as stdtime};
 use std::sync::{mpsc, Arc, Mutex, RwLock};
@@ -39,24 +56,54 @@ fn main() {
     let pool_tx1 = pool_mysql.clone();
     let pool_tx2 = pool_mysql.clone();
     let pool_tx3 = pool_mysql.clone();
+    let pool_tx4 = pool_mysql.clone();
+    let pool_tx5 = pool_mysql.clone();
+    let pool_tx6 = pool_mysql.clone();
+    let webhook_timeout = config.get_int("webhook_timeout").unwrap_or(10) as u64;
+    let webhook_allow_private_hosts = config
+        .get_bool("webhook_allow_private_hosts")
+        .unwrap_or(false);
+
+    // states of coins which got a ledger before they were merged into it
+    match models::merge_leftover_states(&pool_mysql) {
+        Ok(num) => info!("Merged states into {} ledger transactions", num),
+        Err(e) => error!("Error while merging states into the ledger: {:?}", e),
+    }
+
+    let market_provider = Arc::new(provider::from_config(&config).unwrap());
+    let market_provider_tx1 = market_provider.clone();
+    let market_provider_tx2 = market_provider.clone();
+
+    let token_keys = token::TokenKeys::from_config(&config).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let notify_dispatcher = notify::Dispatcher::from_config(&config);
+    let sms_fac_lock = Mutex::new(models::SmsFactory::new(tx.clone()));
+    let sms_fac_tx1 = models::SmsFactory::new(tx.clone());
+    let sms_fac_tx2 = models::SmsFactory::new(tx);
 
     let worker_state_lock = Arc::new(RwLock::new(worker::State::init(&pool_mysql)));
     let worker_state_lock_tx1 = worker_state_lock.clone();
     let worker_state_lock_tx2 = worker_state_lock.clone();
+    let worker_state_lock_tx3 = worker_state_lock.clone();
     // update all coins price every 5 minutes
     thread::spawn(move || loop {
-        match worker::refresh_coins(&pool_tx1, &worker_state_lock_tx1) {
+        match worker::refresh_coins(&pool_tx1, &worker_state_lock_tx1, &**market_provider_tx1) {
             Ok(_) => (),
-            Err(e) => println!("Error while refreshing coins: {}", &*e.to_string()),
+            Err(e) => error!("Error while refreshing coins: {}", &*e.to_string()),
+        }
+        match worker::check_alerts(&pool_tx1, &worker_state_lock_tx1, &sms_fac_tx1) {
+            Ok(_) => (),
+            Err(e) => error!("Error while checking alerts: {}", &*e.to_string()),
         }
         thread::sleep(stdtime::Duration::from_secs(300));
     });
     // update specific coin historical price every 7 seconds
     thread::spawn(move || loop {
-        let sleep_secs = match worker::refresh_prices(&pool_tx2) {
+        let sleep_secs = match worker::refresh_prices(&pool_tx2, &**market_provider_tx2) {
             Ok(secs) => secs,
             Err(e) => {
-                println!("Error while refreshing prices: {}", &*e.to_string());
+                error!("Error while refreshing prices: {}", &*e.to_string());
                 6
             }
         };
@@ -66,29 +113,62 @@ fn main() {
     thread::spawn(move || loop {
         match worker::refresh_rates(&pool_tx3, &worker_state_lock_tx2) {
             Ok(_) => (),
-            Err(e) => println!("Error while refreshing rates: {}", &*e.to_string()),
+            Err(e) => error!("Error while refreshing rates: {}", &*e.to_string()),
         }
         thread::sleep(stdtime::Duration::from_secs(86400));
     });
-
-    let (tx, rx) = mpsc::channel();
-    let sms_fac_lock = Mutex::new(models::SmsFactory::new(
-        config.get_str("ali_sms_key_id").unwrap(),
-        config.get_str("ali_sms_key_secret").unwrap(),
-        tx,
-    ));
-    // asynchronous sms sending
+    // send daily and weekly digests, check every hour
     thread::spawn(move || loop {
-        let sms_body = rx.recv().unwrap();
-        alisms::sms_api(sms_body);
+        match worker::send_digests(&pool_tx4, &worker_state_lock_tx3, &sms_fac_tx2) {
+            Ok(_) => (),
+            Err(e) => error!("Error while sending digests: {}", &*e.to_string()),
+        }
+        thread::sleep(stdtime::Duration::from_secs(3600));
     });
+    // purge expired sessions and old webhook deliveries every hour
+    thread::spawn(move || loop {
+        match models::Session::sweep(&pool_tx6) {
+            Ok(num) => info!("Purged {} expired sessions", num),
+            Err(e) => error!("Error while purging sessions: {:?}", e),
+        }
+        match webhook::sweep(&pool_tx6) {
+            Ok(num) => info!("Purged {} webhook deliveries", num),
+            Err(e) => error!("Error while purging webhook deliveries: {:?}", e),
+        }
+        thread::sleep(stdtime::Duration::from_secs(3600));
+    });
+    // post webhook deliveries and retry the failed ones
+    thread::spawn(move || loop {
+        let sleep_secs = match webhook::deliver_pending(
+            &pool_tx5,
+            webhook_timeout,
+            webhook_allow_private_hosts,
+        ) {
+            // keep going while there are more due
+            Ok(num) if num > 0 => 1,
+            Ok(_) => 10,
+            Err(e) => {
+                error!("Error while delivering webhooks: {}", &*e.to_string());
+                10
+            }
+        };
+        thread::sleep(stdtime::Duration::from_secs(sleep_secs));
+    });
+
+    // asynchronous notification sending
+    thread::spawn(move || notify_dispatcher.run(rx));
 
     server
         .manage(pool_mysql)
         .manage(config)
         .manage(worker_state_lock)
         .manage(sms_fac_lock)
+        .manage(token_keys)
         .attach(Template::fairing())
+        .attach(rocket::fairing::AdHoc::on_request(|_, _| {
+            error::set_session_locale(None);
+            error::set_session_error(None);
+        }))
         .attach(rocket::fairing::AdHoc::on_response(|req, response| {
             let raw = req.headers().get_one("Origin");
             if raw == None {
@@ -116,8 +196,28 @@ fn main() {
             routes![
                 api::sms,
                 api::sms_auth,
+                api::token_refresh,
+                api::logout,
+                api::sessions,
+                api::delete_session,
+                api::email_signup,
+                api::me_email,
+                api::email_verify,
+                api::login_page,
+                api::login,
+                api::login_form,
+                api::totp_enroll,
+                api::totp_verify,
+                api::totp_login,
+                api::totp_step_up,
+                api::totp_recovery_codes,
+                api::delete_totp,
                 api::me_get,
                 api::me_post,
+                api::me_put,
+                api::me_mobile_code,
+                api::put_me_mobile,
+                api::delete_me,
                 api::states,
                 api::states_history,
                 api::coin,
@@ -128,11 +228,35 @@ fn main() {
                 api::delete_states,
                 api::put_balance,
                 api::delete_balance,
+                api::transactions,
+                api::put_transaction,
+                api::delete_transaction,
+                api::migrate_states,
+                api::import_preview,
+                api::import_commit,
+                api::import_json,
+                api::export,
+                api::portfolios,
+                api::put_portfolio,
+                api::delete_portfolio,
+                api::portfolio_members,
+                api::put_portfolio_member,
+                api::delete_portfolio_member,
+                api::webhooks,
+                api::put_webhook,
+                api::delete_webhook,
+                api::webhook_deliveries,
+                api::alerts,
+                api::put_alert,
+                api::delete_alert,
+                api::portfolio_pnl,
+                api::coin_pnl,
             ],
         )
         .catch(errors![
             api::bad_gateway,
             api::bad_request,
+            api::unauthorized,
             api::internal_server_error
         ])
         .launch();
@@ -1,36 +1,68 @@
 use std::collections::{BTreeMap, HashMap};
 use std::ops::Deref;
 use rocket::request::{self, FormItems, FromRequest, Request};
-use rocket::Outcome;
-use alisms::SmsBody;
+use rocket::{Config, Outcome, State};
+use std::net::IpAddr;
 use std::sync::mpsc::Sender;
 use mysql::{self, Pool};
-use rand::{self, Rng};
+use rand::{self, OsRng, Rng};
+use regex::Regex;
 use time;
 use crypto::digest::Digest;
-use crypto::md5::Md5;
-use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use crypto::scrypt::{scrypt_check, scrypt_simple, ScryptParams};
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
 use rocket::http::Status;
 use uuid::Uuid;
 use std::io::{Error, ErrorKind};
+use serde_json::{self, Value};
 
-use error::E;
+use digest::Period;
+use error::{self, E};
+use i18n;
+use notify::{Channel, Message, Notification, Recipient};
+use phone::Phone;
+use token::TokenKeys;
+use totp;
 use utils;
+use webhook;
 use worker;
 
-pub enum Sms {
-    Verification { phone: String, code: String },
-}
-
+/// generates login codes and queues notifications for the dispatching thread
 pub struct SmsFactory {
-    pub key_id: String,
-    pub key_secret: String,
-    pub tx: Sender<SmsBody>,
+    pub tx: Sender<Notification>,
 }
 
 impl SmsFactory {
-    pub fn gen_code(&self, mysql_pool: &Pool, mobile: &str) -> Result<i64, E> {
+    /// send a login code to the phone, limited by its country. It goes to the user's verified
+    /// email if that's the preferred channel, otherwise by sms.
+    pub fn gen_code(&self, mysql_pool: &Pool, phone: &Phone) -> Result<i64, E> {
+        self.issue_code(mysql_pool, phone, false)
+    }
+
+    /// send a code by sms to prove owning the number
+    pub fn gen_sms_code(&self, mysql_pool: &Pool, phone: &Phone) -> Result<i64, E> {
+        self.issue_code(mysql_pool, phone, true)
+    }
+
+    fn issue_code(&self, mysql_pool: &Pool, phone: &Phone, sms_only: bool) -> Result<i64, E> {
         let now = time::get_time().sec;
+        let e164 = phone.e164();
+        let mobile: &str = &e164;
+        let country = phone.country;
+        if country.hourly_limit > 0 {
+            let row = mysql_pool
+                .prep_exec(
+                    "SELECT COUNT(0) FROM sms WHERE mobile LIKE ? AND created>?",
+                    (format!("+{}%", country.code), now - 3600),
+                )?
+                .next()??;
+            let country_times: i64 = mysql::from_row(row);
+            if country_times >= country.hourly_limit {
+                return Err(E::SmsCountryLimit);
+            }
+        }
         let row = mysql_pool
             .prep_exec(
                 "SELECT COUNT(0) AS send_times,MAX(created) AS last_created FROM sms \
@@ -41,11 +73,15 @@ impl SmsFactory {
         let (send_times, last_created): (i64, Option<i64>) = mysql::from_row(row);
         let last_created = last_created.unwrap_or(0);
 
-        if send_times > 10 {
-            return Err(E::SmsSendLimit);
+        if send_times > country.daily_limit {
+            return Err(E::SmsSendLimit(country.daily_limit));
         } else if last_created + send_times * 60 > now {
             return Err(E::SmsSendInterval(send_times * 60));
         }
+        let recipients = match User::find(mysql_pool, None, Some(mobile)) {
+            Ok(ref user) if !sms_only => user.code_recipients(&user.verified_emails(mysql_pool)?),
+            _ => vec![Recipient::new(Channel::Sms, mobile)],
+        };
         let code = rand::thread_rng().gen_range(1000, 9999);
         mysql_pool
             .start_transaction(false, None, None)
@@ -54,17 +90,17 @@ impl SmsFactory {
                     "INSERT INTO sms (mobile,code,err_times,created) VALUES (?,?,?,?)",
                     (mobile, code, 0, now),
                 )?;
-                self.send(Sms::Verification {
-                    phone: mobile.to_string(),
-                    code: code.to_string(),
-                }).map_err(|_| Error::new(ErrorKind::Other, "sms worker was down"))?;
+                self.send(
+                    recipients,
+                    Message::Code {
+                        code: code.to_string(),
+                    },
+                ).map_err(|_| Error::new(ErrorKind::Other, "notify worker was down"))?;
                 t.commit()
             })
             .map_err(|_| E::SmsSendError)?;
-        let interval = (send_times + 1) * 60;
-        println!("sms code: {}, interval: {}", code, interval);
 
-        Ok(interval)
+        Ok((send_times + 1) * 60)
     }
 
     pub fn check_code(&self, mysql_pool: &Pool, mobile: &str, code_input: u32) -> Result<(), E> {
@@ -91,28 +127,15 @@ impl SmsFactory {
         }
     }
 
-    pub fn new(key_id: &str, key_secret: &str, tx: Sender<SmsBody>) -> Self {
-        SmsFactory {
-            key_id: key_id.to_string(),
-            key_secret: key_secret.to_string(),
-            tx: tx,
-        }
+    pub fn new(tx: Sender<Notification>) -> Self {
+        SmsFactory { tx: tx }
     }
 
-    pub fn send(&self, sms: Sms) -> Result<(), E> {
-        match sms {
-            Sms::Verification { phone, code } => {
-                self.tx.send(SmsBody {
-                    key_id: self.key_id.clone(),
-                    key_secret: self.key_secret.clone(),
-                    sign_name: "yield助手".to_string(),
-                    template_code: "SMS_123673246".to_string(),
-                    phone_numbers: phone.to_string(),
-                    template_param: format!("{{\"code\":\"{code}\"}}", code = code),
-                    out_id: "".to_string(),
-                })?;
-            }
-        }
+    pub fn send(&self, recipients: Vec<Recipient>, message: Message) -> Result<(), E> {
+        self.tx.send(Notification {
+            recipients: recipients,
+            message: message,
+        })?;
 
         Ok(())
     }
@@ -161,38 +184,21 @@ pub struct Session {
     user: Option<User>,
     pub accessed: i64,
     pub created: i64,
+    /// last time the user proved a totp code in this session
+    pub stepped_up: i64,
 }
 
-/// This Session is based on mysql database. For reduce db queries, I encrypt session's id
-/// as a url-safe access token. When it come in with request, it will be decrypted. If success,
+/// This Session is based on mysql database. Session's id is signed into a url-safe access
+/// token (see `token`). When it come in with request, it will be verified. If success,
 /// then fetch session and user's infomation from db.
 impl Session {
-    pub const KEY: &'static str = "j0n";
     const EXPIRES_IN: i64 = 2592000;
-
-    /// encrypt session id to access_token form
-    pub fn id_to_access_token(sess_id: &str) -> Result<String, E> {
-        let mut sh = Md5::new();
-        sh.input_str(Self::KEY);
-        let key = sh.result_str();
-        let enc = utils::encrypt(sess_id.as_bytes(), &key.as_bytes())?;
-
-        Ok(enc.to_base64(URL_SAFE))
-    }
-
-    /// decrypt access_token to session id
-    pub fn access_token_to_id(access_token: &str) -> Result<String, E> {
-        let mut sh = Md5::new();
-        sh.input_str(Session::KEY);
-        let key = sh.result_str();
-        let enc = access_token
-            .from_base64()
-            .map_err(|_| E::AccessTokenInvalid)?;
-        let dec = utils::decrypt(&enc, &key.as_bytes()).map_err(|_| E::AccessTokenInvalid)?;
-        let sess_id = String::from_utf8(dec).map_err(|_| E::AccessTokenInvalid)?;
-
-        Ok(sess_id)
-    }
+    /// name of the private cookie holding access token
+    pub const COOKIE_NAME: &'static str = "access_token";
+    /// name of the private cookie holding refresh token
+    pub const REFRESH_COOKIE_NAME: &'static str = "refresh_token";
+    /// seconds a totp step-up lasts
+    const STEP_UP_TTL: i64 = 600;
 
     /// borrow user from session if user exist or raise user not found error
     pub fn user(&self) -> Result<&User, E> {
@@ -202,6 +208,14 @@ impl Session {
         }
     }
 
+    /// mutably borrow user from session if user exist or raise user not found error
+    pub fn user_mut(&mut self) -> Result<&mut User, E> {
+        match self.user {
+            Some(ref mut u) => Ok(u),
+            None => Err(E::UserNotFound),
+        }
+    }
+
     /// signup user
     pub fn signup(&mut self, mysql_pool: &Pool, name: &str) -> Result<(), E> {
         if !self.user.is_none() {
@@ -229,6 +243,13 @@ impl Session {
                     id: user_id,
                     name: name.to_string(),
                     mobile: self.mobile.clone(),
+                    currency: User::DEFAULT_CURRENCY.to_string(),
+                    email: "".to_string(),
+                    webhook_url: "".to_string(),
+                    notify_channel: Channel::Sms,
+                    digest: None,
+                    timezone: User::DEFAULT_TIMEZONE.to_string(),
+                    locale: User::LOCALES[0].to_string(),
                     created: now,
                 });
                 t.commit()
@@ -237,9 +258,24 @@ impl Session {
     }
 
     /// create session for mobile
-    pub fn new(mysql_pool: &Pool, mobile: &str) -> Result<Self, E> {
-        let now = time::get_time().sec;
+    pub fn new(mysql_pool: &Pool, mobile: &str, client: &ClientInfo) -> Result<Self, E> {
         let user = User::find(mysql_pool, None, Some(mobile)).ok();
+        Session::create(mysql_pool, mobile, user, client)
+    }
+
+    /// create session for user logged in without sms
+    pub fn for_user(mysql_pool: &Pool, user: User, client: &ClientInfo) -> Result<Self, E> {
+        let mobile = user.mobile.clone();
+        Session::create(mysql_pool, &mobile, Some(user), client)
+    }
+
+    fn create(
+        mysql_pool: &Pool,
+        mobile: &str,
+        user: Option<User>,
+        client: &ClientInfo,
+    ) -> Result<Self, E> {
+        let now = time::get_time().sec;
         let id = Uuid::new_v4().hyphenated().to_string();
         let mut sess = Session {
             id: id,
@@ -247,6 +283,7 @@ impl Session {
             user: None,
             accessed: now,
             created: now,
+            stepped_up: 0,
         };
         let user_id = if user.is_none() {
             0
@@ -258,25 +295,152 @@ impl Session {
         };
 
         mysql_pool.prep_exec(
-            "INSERT INTO _session (id,mobile,user_id,created,accessed) VALUES (?,?,?,?,?)",
-            (&sess.id, mobile, user_id, now, now),
+            "INSERT INTO _session (id,mobile,user_id,user_agent,ip,created,accessed) \
+             VALUES (?,?,?,?,?,?,?)",
+            (
+                &sess.id,
+                mobile,
+                user_id,
+                &client.user_agent,
+                &client.ip,
+                now,
+                now,
+            ),
         )?;
         Ok(sess)
     }
 
+    /// mark the session as verified by totp for a while
+    pub fn step_up(&mut self, mysql_pool: &Pool) -> Result<i64, E> {
+        let now = time::get_time().sec;
+        mysql_pool.prep_exec(
+            "UPDATE _session SET stepped_up=? WHERE id=?",
+            (now, &self.id),
+        )?;
+        self.stepped_up = now;
+
+        Ok(now + Self::STEP_UP_TTL)
+    }
+
+    /// sensitive operations require a recent totp step-up
+    pub fn check_step_up(&self) -> Result<(), E> {
+        if self.stepped_up + Self::STEP_UP_TTL < time::get_time().sec {
+            return Err(E::StepUpRequired);
+        }
+
+        Ok(())
+    }
+
+    /// public name of session, the id itself is never exposed
+    pub fn handle(&self) -> String {
+        let mut sh = Sha1::new();
+        sh.input_str(&self.id);
+        sh.result_str()
+    }
+
+    /// logout, along with the refresh tokens of the session
+    pub fn revoke(&self, mysql_pool: &Pool) -> Result<(), E> {
+        Session::revoke_by_id(mysql_pool, &self.id)
+    }
+
+    fn revoke_by_id(mysql_pool: &Pool, sess_id: &str) -> Result<(), E> {
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                t.prep_exec("DELETE FROM refresh_tokens WHERE session_id=?", (sess_id,))?;
+                t.prep_exec("DELETE FROM _session WHERE id=?", (sess_id,))?;
+                t.commit()
+            })?;
+
+        Ok(())
+    }
+
+    /// purge expired sessions and refresh tokens, return the number of sessions
+    pub fn sweep(mysql_pool: &Pool) -> Result<u64, E> {
+        let now = time::get_time().sec;
+        mysql_pool.prep_exec("DELETE FROM refresh_tokens WHERE expires<?", (now,))?;
+        let ret = mysql_pool.prep_exec(
+            "DELETE FROM _session WHERE accessed<?",
+            (now - Self::EXPIRES_IN,),
+        )?;
+
+        Ok(ret.affected_rows())
+    }
+
+    fn hash_refresh_token(refresh_token: &str) -> String {
+        let mut sh = Sha256::new();
+        sh.input_str(refresh_token);
+        sh.result_str()
+    }
+
+    /// issue a new refresh token of the session valid for `ttl` seconds, only its hash is kept
+    pub fn issue_refresh_token(&self, mysql_pool: &Pool, ttl: i64) -> Result<String, E> {
+        let mut rng = OsRng::new().map_err(|e| E::Internal(format!("rng: {}", e)))?;
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        let refresh_token = bytes.to_base64(URL_SAFE);
+        let now = time::get_time().sec;
+        mysql_pool.prep_exec(
+            "INSERT INTO refresh_tokens (session_id,token_hash,used,created,expires) \
+             VALUES (?,?,0,?,?)",
+            (
+                &self.id,
+                Session::hash_refresh_token(&refresh_token),
+                now,
+                now + ttl,
+            ),
+        )?;
+
+        Ok(refresh_token)
+    }
+
+    /// spend a refresh token and return its session. A refresh token is used only once, the
+    /// session is revoked if one is replayed since it may have been stolen.
+    pub fn refresh(mysql_pool: &Pool, refresh_token: &str) -> Result<Self, E> {
+        let ret = mysql_pool
+            .prep_exec(
+                "SELECT id,session_id,used,expires FROM refresh_tokens WHERE token_hash=?",
+                (Session::hash_refresh_token(refresh_token),),
+            )?
+            .next();
+        let (id, sess_id, used, expires): (i64, String, bool, i64) = match ret {
+            Some(row) => mysql::from_row(row?),
+            None => return Err(E::RefreshTokenInvalid),
+        };
+        // mark it used in one statement, so two concurrent refreshes can't both win
+        let spent = !used
+            && mysql_pool
+                .prep_exec(
+                    "UPDATE refresh_tokens SET used=1 WHERE id=? AND used=0",
+                    (id,),
+                )?
+                .affected_rows() == 1;
+        if !spent {
+            warn!("Refresh token of session {} is reused, revoke it", sess_id);
+            Session::revoke_by_id(mysql_pool, &sess_id)?;
+            return Err(E::RefreshTokenReused);
+        }
+        if expires < time::get_time().sec {
+            return Err(E::RefreshTokenInvalid);
+        }
+
+        Session::init(mysql_pool, &sess_id).map_err(|_| E::RefreshTokenInvalid)
+    }
+
     /// fetch session from id
     pub fn init(mysql_pool: &Pool, sess_id: &str) -> Result<Self, E> {
         let now = time::get_time().sec;
         let ret = mysql_pool
             .prep_exec(
-                "SELECT mobile,user_id,accessed,created FROM _session WHERE id=?",
+                "SELECT mobile,user_id,accessed,created,stepped_up FROM _session WHERE id=?",
                 (sess_id,),
             )?
             .next();
         if ret.is_none() {
             return Err(E::SessionExpired);
         }
-        let (mobile, user_id, accessed, created): (String, i64, i64, i64) = mysql::from_row(ret??);
+        let (mobile, user_id, accessed, created, stepped_up): (String, i64, i64, i64, i64) =
+            mysql::from_row(ret??);
         if accessed + Self::EXPIRES_IN < now {
             return Err(E::SessionExpired);
         }
@@ -294,17 +458,116 @@ impl Session {
             user: user,
             created: created,
             accessed: accessed,
+            stepped_up: stepped_up,
+        })
+    }
+
+    /// search access_token in `Authorization: Bearer` header, then the private cookie, then
+    /// the query string, and create session if it exists and is correct
+    pub fn from_request_parts(req: &Request) -> Result<Self, E> {
+        let mysql_pool = match req.guard::<State<Pool>>() {
+            Outcome::Success(x) => x,
+            _ => return Err(E::Internal("mysql pool is not managed".to_string())),
+        };
+        let token_keys = match req.guard::<State<TokenKeys>>() {
+            Outcome::Success(x) => x,
+            _ => return Err(E::Internal("token keys are not managed".to_string())),
+        };
+        let access_token = Session::access_token(req)?;
+        let sess_id = token_keys.verify(&access_token)?;
+
+        Session::init(&mysql_pool, &sess_id)
+    }
+
+    fn access_token(req: &Request) -> Result<String, E> {
+        if let Some(auth) = req.headers().get_one("Authorization") {
+            if auth.len() > 7 && auth[..7].eq_ignore_ascii_case("bearer ") {
+                return Ok(auth[7..].trim().to_string());
+            }
+        }
+        if let Some(cookie) = req.cookies().get_private(Self::COOKIE_NAME) {
+            return Ok(cookie.value().to_string());
+        }
+        match QueryString::from_request(req) {
+            Outcome::Success(qs) => qs.get("access_token")
+                .cloned()
+                .ok_or(E::AccessTokenNotFound),
+            _ => Err(E::AccessTokenNotFound),
+        }
+    }
+}
+
+/// who is creating a session
+#[derive(Debug)]
+pub struct ClientInfo {
+    pub user_agent: String,
+    pub ip: String,
+}
+
+impl ClientInfo {
+    /// whether the peer is one of `trusted_proxies`
+    fn from_trusted_proxy(req: &Request) -> bool {
+        let remote = match req.remote() {
+            Some(x) => x.ip(),
+            None => return false,
+        };
+        let config = match req.guard::<State<Config>>() {
+            Outcome::Success(x) => x,
+            _ => return false,
+        };
+        config
+            .get_slice("trusted_proxies")
+            .map(|proxies| {
+                proxies
+                    .iter()
+                    .filter_map(|x| x.as_str().and_then(|x| x.parse::<IpAddr>().ok()))
+                    .any(|x| x == remote)
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = E;
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<ClientInfo, E> {
+        let user_agent: String = req.headers()
+            .get_one("User-Agent")
+            .unwrap_or("")
+            .chars()
+            .take(255)
+            .collect();
+        // the address set by the reverse proxy in front of us, anyone else could fake it
+        let ip = match req.headers().get_one("X-Real-IP") {
+            Some(ip) if ClientInfo::from_trusted_proxy(req) => ip.chars().take(45).collect(),
+            _ => req.remote().map(|x| x.ip().to_string()).unwrap_or_default(),
+        };
+
+        Outcome::Success(ClientInfo {
+            user_agent: user_agent,
+            ip: ip,
         })
     }
+}
 
-    /// search access_token in query string and create session if it exists and is correct
-    pub fn from_query_string(mysql_pool: &Pool, qs: &QueryString) -> Result<Self, E> {
-        match qs.get("access_token") {
-            Some(access_token) => {
-                let sess_id = Session::access_token_to_id(access_token)?;
-                Session::init(mysql_pool, &sess_id)
+/// The failure status is 401, the catcher looks the session up again to tell the reason.
+impl<'a, 'r> FromRequest<'a, 'r> for Session {
+    type Error = ();
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Session, ()> {
+        match Session::from_request_parts(req) {
+            Ok(sess) => {
+                // errors of the request are told in the user's language
+                error::set_session_locale(
+                    sess.user()
+                        .ok()
+                        .and_then(|x| i18n::match_locale(&x.locale)),
+                );
+                Outcome::Success(sess)
+            }
+            Err(e) => {
+                // the 401 catcher can't see the outcome, it tells this one
+                error::set_session_error(Some(e));
+                Outcome::Failure((Status::Unauthorized, ()))
             }
-            None => Err(E::AccessTokenNotFound), // not found access_token in query string
         }
     }
 }
@@ -314,175 +577,2470 @@ pub struct User {
     pub id: i64,
     pub name: String,
     pub mobile: String,
+    /// default display currency
+    pub currency: String,
+    pub email: String,
+    pub webhook_url: String,
+    /// preferred channel of notifications, falls back to sms
+    pub notify_channel: Channel,
+    /// opt-in scheduled digest
+    pub digest: Option<Period>,
+    /// IANA time zone name
+    pub timezone: String,
+    /// locale of messages, one of `User::LOCALES`
+    pub locale: String,
     pub created: i64,
 }
 
 impl<'a> User {
+    pub const DEFAULT_CURRENCY: &'static str = "CNY";
+    pub const DEFAULT_TIMEZONE: &'static str = "Asia/Shanghai";
+    /// supported locales, the first one is the default
+    pub const LOCALES: &'static [&'static str] = i18n::LOCALES;
+
     /// fetch user by user id or mobile
-    fn find(mysql_pool: &Pool, user_id: Option<i64>, mobile: Option<&str>) -> Result<Self, E> {
-        let mut sql = String::from("SELECT id,name,mobile,created FROM users WHERE ");
+    pub fn find(mysql_pool: &Pool, user_id: Option<i64>, mobile: Option<&str>) -> Result<Self, E> {
+        let mut sql = String::from(
+            "SELECT id,name,mobile,currency,email,webhook_url,notify_channel,digest,timezone,\
+             locale,created FROM users WHERE ",
+        );
         let mut params = Vec::new();
         if !user_id.is_none() {
             sql.push_str("id=?");
             params.push(mysql::Value::from(user_id.unwrap()));
+        } else if mobile == Some("") {
+            // users signed up by email have no mobile
+            return Err(E::UserNotFound);
         } else if !mobile.is_none() {
             sql.push_str("mobile=?");
             params.push(mysql::Value::from(mobile.unwrap()));
         } else {
-            return Err(E::Unknown);
+            return Err(E::Internal("user must be found by id or mobile".to_string()));
         }
         let ret = mysql_pool.prep_exec(sql, params)?.next();
         if ret.is_none() {
             return Err(E::UserNotFound);
         }
-        let (id, name, mobile, created): (i64, String, String, i64) = mysql::from_row(ret??);
+        let (
+            id,
+            name,
+            mobile,
+            currency,
+            email,
+            webhook_url,
+            notify_channel,
+            digest,
+            timezone,
+            locale,
+            created,
+        ): (
+            i64,
+            String,
+            String,
+            String,
+            String,
+            String,
+            String,
+            String,
+            String,
+            String,
+            i64,
+        ) = mysql::from_row(ret??);
         Ok(User {
             id: id,
             name: name,
             mobile: mobile,
+            currency: currency,
+            email: email,
+            webhook_url: webhook_url,
+            notify_channel: Channel::parse(&notify_channel).unwrap_or(Channel::Sms),
+            digest: Period::parse(&digest),
+            timezone: timezone,
+            locale: locale,
             created: created,
         })
     }
 
-    pub fn put_balance(
-        &self,
+    /// update name, time zone and locale
+    pub fn set_profile(
+        &mut self,
         mysql_pool: &Pool,
-        id: i64,
-        created: i64,
-        amount: f64,
+        name: &str,
+        timezone: &str,
+        locale: &str,
     ) -> Result<(), E> {
-        if id > 0 {
-            mysql_pool.prep_exec(
-                "UPDATE balance SET amount=?,created=? WHERE id=? AND user_id=?",
-                (amount, created, id, self.id),
-            )?;
-        } else {
-            mysql_pool.prep_exec(
-                "INSERT INTO balance (user_id,amount,created) VALUES (?,?,?)",
-                (self.id, amount, created),
-            )?;
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > 64 {
+            return Err(E::ProfileInvalid("name".to_string()));
+        }
+        // no tz database here, only the shape of names like "UTC" or "America/New_York"
+        if !Regex::new(r"^[A-Za-z]+(/[A-Za-z0-9_+\-]+){0,2}$")?.is_match(timezone) {
+            return Err(E::ProfileInvalid("timezone".to_string()));
         }
+        if !User::LOCALES.contains(&locale) {
+            return Err(E::ProfileInvalid("locale".to_string()));
+        }
+        mysql_pool.prep_exec(
+            "UPDATE users SET name=?,timezone=?,locale=? WHERE id=?",
+            (name, timezone, locale, self.id),
+        )?;
+        self.name = name.to_string();
+        self.timezone = timezone.to_string();
+        self.locale = locale.to_string();
 
         Ok(())
     }
 
-    pub fn del_balance(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
-        mysql_pool.prep_exec("DELETE FROM balance WHERE user_id=? AND id=?", (self.id, id))?;
+    /// bind a new mobile, its code and the one sent to the old mobile must be checked first
+    pub fn set_mobile(&mut self, mysql_pool: &Pool, mobile: &str) -> Result<(), E> {
+        match User::find(mysql_pool, None, Some(mobile)) {
+            Ok(ref other) if other.id != self.id => return Err(E::MobileTaken),
+            Ok(_) => return Ok(()),
+            Err(_) => (),
+        }
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                t.prep_exec(
+                    "UPDATE users SET mobile=? WHERE id=?",
+                    (mobile, self.id),
+                )?;
+                t.prep_exec(
+                    "UPDATE _session SET mobile=? WHERE user_id=?",
+                    (mobile, self.id),
+                )?;
+                t.commit()
+            })?;
+        self.mobile = mobile.to_string();
 
         Ok(())
     }
 
-    pub fn balance(&self, mysql_pool: &Pool) -> Result<Vec<(i64, f64, i64)>, E> {
-        let ret = mysql_pool
-            .prep_exec(
-                "SELECT created,amount,id FROM balance WHERE user_id=? ORDER BY created ASC",
-                (self.id,),
-            )?;
+    /// delete the user and everything of it: holdings of its portfolios, sharing, alerts,
+    /// webhooks, logins, sessions and sms
+    pub fn delete(&self, mysql_pool: &Pool) -> Result<(), E> {
+        let id = self.id;
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                for sql in [
+                    "DELETE FROM states WHERE user_id=?",
+                    "DELETE FROM balance WHERE user_id=?",
+                    "DELETE FROM transactions WHERE user_id=?",
+                    "DELETE FROM portfolio_members WHERE portfolio_id IN \
+                     (SELECT id FROM portfolios WHERE user_id=?)",
+                    "DELETE FROM portfolio_members WHERE user_id=?",
+                    "DELETE FROM portfolios WHERE user_id=?",
+                    "DELETE FROM alerts WHERE user_id=?",
+                    "DELETE FROM webhook_deliveries WHERE user_id=?",
+                    "DELETE FROM webhooks WHERE user_id=?",
+                    "DELETE FROM recovery_codes WHERE user_id=?",
+                    "DELETE FROM totp WHERE user_id=?",
+                    "DELETE FROM email_logins WHERE user_id=?",
+                    "DELETE FROM refresh_tokens WHERE session_id IN \
+                     (SELECT id FROM _session WHERE user_id=?)",
+                    "DELETE FROM _session WHERE user_id=?",
+                ].iter()
+                {
+                    t.prep_exec(*sql, (id,))?;
+                }
+                if !self.mobile.is_empty() {
+                    t.prep_exec("DELETE FROM sms WHERE mobile=?", (&self.mobile,))?;
+                }
+                t.prep_exec("DELETE FROM users WHERE id=?", (id,))?;
+                t.commit()
+            })?;
 
-        let mut data = vec![];
+        Ok(())
+    }
 
-        for row in ret {
-            match row {
-                Ok(row) => {
-                    let (created, amount, id): (i64, f64, i64) = mysql::from_row(row);
-                    data.push((created, amount, id));
-                }
-                Err(_) => (),
-            }
-        }
-        
-        Ok(data)
+    /// set default display currency
+    pub fn set_currency(&mut self, mysql_pool: &Pool, currency: &str) -> Result<(), E> {
+        mysql_pool.prep_exec(
+            "UPDATE users SET currency=? WHERE id=?",
+            (currency, self.id),
+        )?;
+        self.currency = currency.to_string();
+
+        Ok(())
     }
 
-    pub fn put_states(
-        &self,
+    /// set where notifications go, the address of the preferred channel must be given. A
+    /// webhook url must be public unless `allow_private_hosts` is configured for development.
+    pub fn set_notify(
+        &mut self,
         mysql_pool: &Pool,
-        id: i64,
-        coin_id: &str,
-        created: i64,
-        amount: f64,
+        channel: Channel,
+        email: &str,
+        webhook_url: &str,
+        allow_private_hosts: bool,
     ) -> Result<(), E> {
-        if id > 0 {
-            mysql_pool.prep_exec(
-                "UPDATE states SET coin_id=?,amount=?,created=? WHERE id=? AND user_id=?",
-                (coin_id, amount, created, id, self.id),
-            )?;
-        } else {
-            mysql_pool.prep_exec(
-                "INSERT INTO states (user_id,coin_id,amount,created) VALUES (?,?,?,?)",
-                (self.id, coin_id, amount, created),
-            )?;
+        let valid = match channel {
+            Channel::Sms => true,
+            Channel::Email => Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$")?.is_match(email),
+            Channel::Webhook => {
+                (webhook_url.starts_with("http://") || webhook_url.starts_with("https://"))
+                    && (allow_private_hosts || utils::check_public_url(webhook_url).is_ok())
+            }
+        };
+        if !valid {
+            return Err(E::NotifyChannelInvalid);
         }
+        mysql_pool.prep_exec(
+            "UPDATE users SET notify_channel=?,email=?,webhook_url=? WHERE id=?",
+            (channel.as_str(), email, webhook_url, self.id),
+        )?;
+        self.notify_channel = channel;
+        self.email = email.to_string();
+        self.webhook_url = webhook_url.to_string();
 
         Ok(())
     }
 
-    pub fn del_states(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
-        mysql_pool.prep_exec("DELETE FROM states WHERE user_id=? AND id=?", (self.id, id))?;
+    /// active sessions order by accessed time desc, [(HANDLE, USER AGENT, IP, CREATED, ACCESSED)]
+    pub fn sessions(&self, mysql_pool: &Pool) -> Result<Vec<(String, String, String, i64, i64)>, E> {
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            "SELECT SHA1(id),user_agent,ip,created,accessed FROM _session \
+             WHERE user_id=? AND accessed>=? ORDER BY accessed DESC",
+            (self.id, time::get_time().sec - Session::EXPIRES_IN),
+        )? {
+            data.push(mysql::from_row(row?));
+        }
+
+        Ok(data)
+    }
+
+    /// revoke a session by its handle
+    pub fn revoke_session(&self, mysql_pool: &Pool, handle: &str) -> Result<(), E> {
+        let ret = mysql_pool
+            .prep_exec(
+                "SELECT id FROM _session WHERE user_id=? AND SHA1(id)=?",
+                (self.id, handle),
+            )?
+            .next();
+        match ret {
+            Some(row) => {
+                let sess_id: String = mysql::from_row(row?);
+                Session::revoke_by_id(mysql_pool, &sess_id)
+            }
+            None => Err(E::SessionNotFound),
+        }
+    }
+
+    /// logout everywhere
+    pub fn revoke_sessions(&self, mysql_pool: &Pool) -> Result<(), E> {
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                t.prep_exec(
+                    "DELETE FROM refresh_tokens WHERE session_id IN \
+                     (SELECT id FROM _session WHERE user_id=?)",
+                    (self.id,),
+                )?;
+                t.prep_exec("DELETE FROM _session WHERE user_id=?", (self.id,))?;
+                t.commit()
+            })?;
 
         Ok(())
     }
 
-    pub fn states(
-        &self,
-        mysql_pool: &Pool,
-        worker_state: &'a worker::State,
-        coin_id: Option<&str>,
-    ) -> Result<Vec<UserCoin<'a>>, E> {
-        let ret = if coin_id.is_none() {
-            mysql_pool.prep_exec(
-                "SELECT id,coin_id,amount,created FROM states WHERE user_id=? ORDER BY created ASC",
+    /// whether the user has a confirmed totp authenticator
+    pub fn totp_enabled(&self, mysql_pool: &Pool) -> Result<bool, E> {
+        let ret = mysql_pool
+            .prep_exec("SELECT enabled FROM totp WHERE user_id=?", (self.id,))?
+            .next();
+        match ret {
+            Some(row) => Ok(mysql::from_row(row?)),
+            None => Ok(false),
+        }
+    }
+
+    /// start enrolling a totp authenticator and return its secret. It's pending until the
+    /// first code is confirmed, enrolling again replaces a pending secret.
+    pub fn totp_enroll(&self, mysql_pool: &Pool) -> Result<Vec<u8>, E> {
+        if self.totp_enabled(mysql_pool)? {
+            return Err(E::TotpEnrolled);
+        }
+        let mut rng = OsRng::new().map_err(|e| E::Internal(format!("rng: {}", e)))?;
+        let mut secret = [0u8; 20];
+        rng.fill_bytes(&mut secret);
+        mysql_pool.prep_exec(
+            "REPLACE INTO totp (user_id,secret,enabled,last_step,err_times,failed,created) \
+             VALUES (?,?,0,0,0,0,?)",
+            (
+                self.id,
+                utils::base32_encode(&secret),
+                time::get_time().sec,
+            ),
+        )?;
+
+        Ok(secret.to_vec())
+    }
+
+    /// confirm the pending authenticator by its first code, return the recovery codes
+    pub fn totp_confirm(&self, mysql_pool: &Pool, code: &str) -> Result<Vec<String>, E> {
+        if self.totp_enabled(mysql_pool)? {
+            return Err(E::TotpEnrolled);
+        }
+        self.totp_verify(mysql_pool, code, false)?;
+        mysql_pool.prep_exec("UPDATE totp SET enabled=1 WHERE user_id=?", (self.id,))?;
+
+        self.gen_recovery_codes(mysql_pool)
+    }
+
+    /// check a code of the authenticator, or spend one of the recovery codes
+    pub fn totp_check(&self, mysql_pool: &Pool, code: &str) -> Result<(), E> {
+        if !self.totp_enabled(mysql_pool)? {
+            return Err(E::TotpNotEnrolled);
+        }
+
+        self.totp_verify(mysql_pool, code, true)
+    }
+
+    fn totp_verify(&self, mysql_pool: &Pool, code: &str, recovery: bool) -> Result<(), E> {
+        let now = time::get_time().sec;
+        let ret = mysql_pool
+            .prep_exec(
+                "SELECT secret,last_step,err_times,failed FROM totp WHERE user_id=?",
                 (self.id,),
             )?
-        } else {
-            mysql_pool.prep_exec(
-                "SELECT id,coin_id,amount,created FROM states WHERE user_id=? AND coin_id=? ORDER BY created ASC",
-                (self.id, coin_id.unwrap()),
-            )?
+            .next();
+        let (secret, last_step, err_times, failed): (String, i64, i64, i64) = match ret {
+            Some(row) => mysql::from_row(row?),
+            None => return Err(E::TotpNotEnrolled),
         };
-        let mut states: Vec<UserCoin> = vec![];
+        // like sms codes, stop guessing after 10 errors, for 10 minutes here
+        if err_times >= 10 && failed + 600 > now {
+            return Err(E::TotpLimit);
+        }
+        let secret = utils::base32_decode(&secret)?;
+        let code: String = code.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
 
-        for row in ret {
-            match row {
-                Ok(row) => {
-                    let (id, coin_id, amount, created): (i64, String, f64, i64) =
-                        mysql::from_row(row);
-                    let coin = worker_state.coins.iter().find(|&x| x.id == coin_id);
-                    if !coin.is_none() {
-                        states.push(UserCoin {
-                            id: id,
-                            coin_id: coin_id,
-                            amount: amount,
-                            created: created,
-                            coin: coin,
-                        });
-                    }
-                }
-                Err(_) => (),
+        let ok = match totp::verify(&secret, &code, now) {
+            // a code is accepted only once, and never one older than the last accepted
+            Some(step) => {
+                step > last_step
+                    && mysql_pool
+                        .prep_exec(
+                            "UPDATE totp SET last_step=? WHERE user_id=? AND last_step<?",
+                            (step, self.id, step),
+                        )?
+                        .affected_rows() == 1
+            }
+            None => {
+                recovery
+                    && mysql_pool
+                        .prep_exec(
+                            "UPDATE recovery_codes SET used=1 \
+                             WHERE user_id=? AND code_hash=? AND used=0",
+                            (self.id, User::hash_recovery_code(&code)),
+                        )?
+                        .affected_rows() == 1
             }
+        };
+        if !ok {
+            mysql_pool.prep_exec(
+                "UPDATE totp SET err_times=IF(failed+600>?,err_times+1,1),failed=? WHERE user_id=?",
+                (now, now, self.id),
+            )?;
+            return Err(E::TotpInvalid);
         }
+        mysql_pool.prep_exec("UPDATE totp SET err_times=0 WHERE user_id=?", (self.id,))?;
 
-        Ok(states)
+        Ok(())
     }
-}
 
-#[derive(Debug, Clone)]
+    fn hash_recovery_code(code: &str) -> String {
+        let mut sh = Sha256::new();
+        sh.input_str(code);
+        sh.result_str()
+    }
+
+    /// replace the recovery codes of the user with new ones, only their hashes are kept
+    pub fn gen_recovery_codes(&self, mysql_pool: &Pool) -> Result<Vec<String>, E> {
+        let mut rng = OsRng::new().map_err(|e| E::Internal(format!("rng: {}", e)))?;
+        let mut codes = vec![];
+        for _ in 0..10 {
+            let mut bytes = [0u8; 5];
+            rng.fill_bytes(&mut bytes);
+            codes.push(utils::base32_encode(&bytes).to_lowercase());
+        }
+        let now = time::get_time().sec;
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                t.prep_exec("DELETE FROM recovery_codes WHERE user_id=?", (self.id,))?;
+                for code in codes.iter() {
+                    t.prep_exec(
+                        "INSERT INTO recovery_codes (user_id,code_hash,used,created) \
+                         VALUES (?,?,0,?)",
+                        (self.id, User::hash_recovery_code(code), now),
+                    )?;
+                }
+                t.commit()
+            })?;
+
+        // xxxx-xxxx is easier to copy down, the dash is ignored when checking
+        Ok(codes
+            .iter()
+            .map(|code| format!("{}-{}", &code[..4], &code[4..]))
+            .collect())
+    }
+
+    /// remove the authenticator and recovery codes
+    pub fn totp_disable(&self, mysql_pool: &Pool) -> Result<(), E> {
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                t.prep_exec("DELETE FROM recovery_codes WHERE user_id=?", (self.id,))?;
+                t.prep_exec("DELETE FROM totp WHERE user_id=?", (self.id,))?;
+                t.commit()
+            })?;
+
+        Ok(())
+    }
+
+    /// subscribe to the digest of period, or unsubscribe if it's None. The first digest is
+    /// sent one period later.
+    pub fn set_digest(&mut self, mysql_pool: &Pool, digest: Option<Period>) -> Result<(), E> {
+        mysql_pool.prep_exec(
+            "UPDATE users SET digest=?,digest_sent=? WHERE id=?",
+            (
+                digest.map(|x| x.as_str()).unwrap_or("off"),
+                time::get_time().sec,
+                self.id,
+            ),
+        )?;
+        self.digest = digest;
+
+        Ok(())
+    }
+
+    /// users whose digest is due at `now`, [(USER ID, PERIOD)]
+    pub fn digest_due(mysql_pool: &Pool, now: i64) -> Result<Vec<(i64, Period)>, E> {
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            "SELECT id,digest,digest_sent FROM users WHERE digest!='off'",
+            (),
+        )? {
+            let (id, digest, digest_sent): (i64, String, i64) = mysql::from_row(row?);
+            if let Some(period) = Period::parse(&digest) {
+                // the worker wakes up hourly, allow it to be a bit early
+                if digest_sent + period.secs() - 1800 <= now {
+                    data.push((id, period));
+                }
+            }
+        }
+
+        Ok(data)
+    }
+
+    pub fn digest_sent(&self, mysql_pool: &Pool, now: i64) -> Result<(), E> {
+        mysql_pool.prep_exec(
+            "UPDATE users SET digest_sent=? WHERE id=?",
+            (now, self.id),
+        )?;
+
+        Ok(())
+    }
+
+    /// email addresses of the user's verified email logins
+    pub fn verified_emails(&self, mysql_pool: &Pool) -> Result<Vec<String>, E> {
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            "SELECT email FROM email_logins WHERE user_id=? AND verified=1",
+            (self.id,),
+        )? {
+            data.push(mysql::from_row(row?));
+        }
+
+        Ok(data)
+    }
+
+    /// recipients of login codes. Whoever reads the code can log in, so it goes to the
+    /// preferred email only if that address is a verified email login of the user, never to
+    /// a webhook, and to the mobile as the fallback.
+    pub fn code_recipients(&self, verified_emails: &[String]) -> Vec<Recipient> {
+        let mut recipients = vec![];
+        if self.notify_channel == Channel::Email
+            && verified_emails
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(self.email.trim()))
+        {
+            recipients.push(Recipient::new(Channel::Email, &self.email));
+        }
+        if !self.mobile.is_empty() {
+            recipients.push(Recipient::new(Channel::Sms, &self.mobile));
+        }
+
+        recipients
+    }
+
+    /// recipients of notifications, the preferred channel first and sms as the fallback
+    pub fn recipients(&self) -> Vec<Recipient> {
+        let mut recipients = vec![];
+        match self.notify_channel {
+            Channel::Email if !self.email.is_empty() => {
+                recipients.push(Recipient::new(Channel::Email, &self.email))
+            }
+            Channel::Webhook if !self.webhook_url.is_empty() => {
+                recipients.push(Recipient::new(Channel::Webhook, &self.webhook_url))
+            }
+            _ => (),
+        }
+        // users signed up by email have no mobile
+        if !self.mobile.is_empty() {
+            recipients.push(Recipient::new(Channel::Sms, &self.mobile));
+        }
+
+        recipients
+    }
+
+    /// sql condition and its params which select rows of a portfolio, or rows of all the
+    /// portfolios the user owns or is a member of if it's None. The access to the portfolio
+    /// should be checked first.
+    fn portfolio_cond(&self, portfolio: Option<i64>) -> (String, Vec<mysql::Value>) {
+        match portfolio {
+            // the default portfolio's id is 0 for every user
+            Some(0) => (
+                "user_id=? AND portfolio_id=0".to_string(),
+                vec![mysql::Value::from(self.id)],
+            ),
+            Some(portfolio_id) => (
+                "portfolio_id=?".to_string(),
+                vec![mysql::Value::from(portfolio_id)],
+            ),
+            None => (
+                "(user_id=? OR portfolio_id IN \
+                 (SELECT portfolio_id FROM portfolio_members WHERE user_id=?))"
+                    .to_string(),
+                vec![mysql::Value::from(self.id), mysql::Value::from(self.id)],
+            ),
+        }
+    }
+
+    /// sql condition and its params which select rows the user can change
+    fn editable_cond(&self) -> (String, Vec<mysql::Value>) {
+        (
+            "(user_id=? OR portfolio_id IN \
+             (SELECT portfolio_id FROM portfolio_members WHERE user_id=? AND role='editor'))"
+                .to_string(),
+            vec![mysql::Value::from(self.id), mysql::Value::from(self.id)],
+        )
+    }
+
+    /// named portfolios the user owns or is a member of, the default one (id 0) is not included
+    pub fn portfolios(&self, mysql_pool: &Pool) -> Result<Vec<Portfolio>, E> {
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            "SELECT p.id,p.user_id,p.name,p.created,IFNULL(m.role,'owner') FROM portfolios p \
+             LEFT JOIN portfolio_members m ON m.portfolio_id=p.id AND m.user_id=? \
+             WHERE p.user_id=? OR m.user_id IS NOT NULL ORDER BY p.id ASC",
+            (self.id, self.id),
+        )? {
+            let (id, user_id, name, created, role): (i64, i64, String, i64, String) =
+                mysql::from_row(row?);
+            data.push(Portfolio {
+                id: id,
+                user_id: user_id,
+                name: name,
+                role: Role::parse(&role).unwrap_or(Role::Viewer),
+                created: created,
+            });
+        }
+
+        Ok(data)
+    }
+
+    /// the user's role in the portfolio, the default one is always owned
+    pub fn portfolio_role(&self, mysql_pool: &Pool, portfolio_id: i64) -> Result<Role, E> {
+        if portfolio_id == 0 {
+            return Ok(Role::Owner);
+        }
+        let ret = mysql_pool
+            .prep_exec(
+                "SELECT IF(p.user_id=?,'owner',m.role) FROM portfolios p \
+                 LEFT JOIN portfolio_members m ON m.portfolio_id=p.id AND m.user_id=? \
+                 WHERE p.id=? AND (p.user_id=? OR m.user_id IS NOT NULL)",
+                (self.id, self.id, portfolio_id, self.id),
+            )?
+            .next();
+        match ret {
+            Some(row) => {
+                let role: String = mysql::from_row(row?);
+                Ok(Role::parse(&role).unwrap_or(Role::Viewer))
+            }
+            None => Err(E::PortfolioNotFound),
+        }
+    }
+
+    /// make sure the user can see the portfolio
+    pub fn check_portfolio(&self, mysql_pool: &Pool, portfolio_id: i64) -> Result<(), E> {
+        self.portfolio_role(mysql_pool, portfolio_id).map(|_| ())
+    }
+
+    /// make sure the user can change the portfolio and return its owner's id, rows of a
+    /// portfolio always belong to its owner
+    pub fn editable_portfolio(&self, mysql_pool: &Pool, portfolio_id: i64) -> Result<i64, E> {
+        match self.portfolio_role(mysql_pool, portfolio_id)? {
+            Role::Owner => Ok(self.id),
+            Role::Editor => {
+                let row = mysql_pool
+                    .prep_exec("SELECT user_id FROM portfolios WHERE id=?", (portfolio_id,))?
+                    .next()??;
+                Ok(mysql::from_row(row))
+            }
+            Role::Viewer => Err(E::PortfolioReadOnly),
+        }
+    }
+
+    /// members of a portfolio the user can see, [(USER ID, NAME, MOBILE, ROLE)]
+    pub fn portfolio_members(
+        &self,
+        mysql_pool: &Pool,
+        portfolio_id: i64,
+    ) -> Result<Vec<(i64, String, String, Role)>, E> {
+        self.check_portfolio(mysql_pool, portfolio_id)?;
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            "SELECT u.id,u.name,u.mobile,m.role FROM portfolio_members m \
+             INNER JOIN users u ON u.id=m.user_id WHERE m.portfolio_id=? ORDER BY m.created ASC",
+            (portfolio_id,),
+        )? {
+            let (id, name, mobile, role): (i64, String, String, String) = mysql::from_row(row?);
+            data.push((id, name, mobile, Role::parse(&role).unwrap_or(Role::Viewer)));
+        }
+
+        Ok(data)
+    }
+
+    /// invite a registered user by mobile to view or edit the portfolio, or change the role
+    /// if invited already
+    pub fn invite(
+        &self,
+        mysql_pool: &Pool,
+        portfolio_id: i64,
+        mobile: &str,
+        role: Role,
+    ) -> Result<i64, E> {
+        if portfolio_id == 0 || self.portfolio_role(mysql_pool, portfolio_id)? != Role::Owner {
+            return Err(E::PortfolioNotFound);
+        }
+        if role == Role::Owner {
+            return Err(E::PortfolioReadOnly);
+        }
+        let member = User::find(mysql_pool, None, Some(mobile))?;
+        // the owner is not a member
+        if member.id == self.id {
+            return Ok(member.id);
+        }
+        mysql_pool.prep_exec(
+            "REPLACE INTO portfolio_members (portfolio_id,user_id,role,created) VALUES (?,?,?,?)",
+            (portfolio_id, member.id, role.as_str(), time::get_time().sec),
+        )?;
+
+        Ok(member.id)
+    }
+
+    /// the owner removes a member, or a member leaves the portfolio
+    pub fn remove_member(&self, mysql_pool: &Pool, portfolio_id: i64, user_id: i64) -> Result<(), E> {
+        let role = self.portfolio_role(mysql_pool, portfolio_id)?;
+        if role != Role::Owner && user_id != self.id {
+            return Err(E::PortfolioReadOnly);
+        }
+        mysql_pool.prep_exec(
+            "DELETE FROM portfolio_members WHERE portfolio_id=? AND user_id=?",
+            (portfolio_id, user_id),
+        )?;
+
+        Ok(())
+    }
+
+    /// create or rename a portfolio, return its id
+    pub fn put_portfolio(&self, mysql_pool: &Pool, id: i64, name: &str) -> Result<i64, E> {
+        if id > 0 {
+            if self.portfolio_role(mysql_pool, id)? != Role::Owner {
+                return Err(E::PortfolioReadOnly);
+            }
+            mysql_pool.prep_exec(
+                "UPDATE portfolios SET name=? WHERE id=? AND user_id=?",
+                (name, id, self.id),
+            )?;
+            Ok(id)
+        } else {
+            let ret = mysql_pool.prep_exec(
+                "INSERT INTO portfolios (user_id,name,created) VALUES (?,?,?)",
+                (self.id, name, time::get_time().sec),
+            )?;
+            Ok(ret.last_insert_id() as i64)
+        }
+    }
+
+    /// delete a portfolio along with its states, balance and transactions
+    pub fn del_portfolio(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
+        if id <= 0 {
+            return Err(E::PortfolioNotFound);
+        }
+        if self.portfolio_role(mysql_pool, id)? != Role::Owner {
+            return Err(E::PortfolioReadOnly);
+        }
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                for table in ["states", "balance", "transactions"].iter() {
+                    t.prep_exec(
+                        format!("DELETE FROM {} WHERE user_id=? AND portfolio_id=?", table),
+                        (self.id, id),
+                    )?;
+                }
+                t.prep_exec("DELETE FROM portfolio_members WHERE portfolio_id=?", (id,))?;
+                t.prep_exec(
+                    "DELETE FROM portfolios WHERE id=? AND user_id=?",
+                    (id, self.id),
+                )?;
+                t.commit()
+            })?;
+
+        Ok(())
+    }
+
+    pub fn put_balance(
+        &self,
+        mysql_pool: &Pool,
+        portfolio_id: i64,
+        id: i64,
+        created: i64,
+        amount: f64,
+    ) -> Result<(), E> {
+        if id > 0 {
+            self.editable_row(mysql_pool, "balance", id)?;
+            mysql_pool.prep_exec(
+                "UPDATE balance SET amount=?,created=? WHERE id=?",
+                (amount, created, id),
+            )?;
+        } else {
+            let owner_id = self.editable_portfolio(mysql_pool, portfolio_id)?;
+            mysql_pool.prep_exec(
+                "INSERT INTO balance (user_id,portfolio_id,amount,created) VALUES (?,?,?,?)",
+                (owner_id, portfolio_id, amount, created),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn del_balance(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
+        self.del_row(mysql_pool, "balance", id)?;
+
+        Ok(())
+    }
+
+    /// balance of a portfolio, or the sum of all portfolios if it's None
+    pub fn balance(
+        &self,
+        mysql_pool: &Pool,
+        portfolio: Option<i64>,
+    ) -> Result<Vec<(i64, f64, i64)>, E> {
+        let (cond, params) = self.portfolio_cond(portfolio);
+        let ret = mysql_pool.prep_exec(
+            format!(
+                "SELECT created,amount,id,portfolio_id FROM balance WHERE {} ORDER BY created ASC",
+                cond
+            ),
+            params,
+        )?;
+
+        let mut data = vec![];
+        // {PORTFOLIO => AMOUNT}
+        let mut amounts = HashMap::<i64, f64>::new();
+
+        for row in ret {
+            match row {
+                Ok(row) => {
+                    let (created, amount, id, portfolio_id): (i64, f64, i64, i64) =
+                        mysql::from_row(row);
+                    // balance rows are snapshots, sum the latest ones of each portfolio
+                    amounts.insert(portfolio_id, amount);
+                    data.push((created, amounts.values().sum(), id));
+                }
+                Err(_) => (),
+            }
+        }
+
+        Ok(data)
+    }
+
+    pub fn put_states(
+        &self,
+        mysql_pool: &Pool,
+        portfolio_id: i64,
+        id: i64,
+        coin_id: &str,
+        created: i64,
+        amount: f64,
+    ) -> Result<(), E> {
+        let (owner_id, portfolio_id) = if id > 0 {
+            let row = self.editable_row(mysql_pool, "states", id)?;
+            // an edited state is merged again, as the difference to the ledger
+            mysql_pool.prep_exec(
+                "UPDATE states SET coin_id=?,amount=?,created=?,migrated=0 WHERE id=?",
+                (coin_id, amount, created, id),
+            )?;
+            row
+        } else {
+            let owner_id = self.editable_portfolio(mysql_pool, portfolio_id)?;
+            mysql_pool.prep_exec(
+                "INSERT INTO states (user_id,portfolio_id,coin_id,amount,created) VALUES (?,?,?,?,?)",
+                (owner_id, portfolio_id, coin_id, amount, created),
+            )?;
+            (owner_id, portfolio_id)
+        };
+
+        // coins with a ledger take the state into it right away
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                merge_states(&mut t, owner_id, Some(portfolio_id), Some(coin_id), true)?;
+                t.commit()
+            })?;
+
+        Ok(())
+    }
+
+    pub fn del_states(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
+        self.del_row(mysql_pool, "states", id)?;
+
+        Ok(())
+    }
+
+    /// create or update a ledger entry, it can't make the ledger hold less than nothing at any
+    /// time
+    pub fn put_transaction(&self, mysql_pool: &Pool, tx: &Transaction) -> Result<(), E> {
+        if tx.amount <= 0.0 || tx.price_usd < 0.0 || tx.fee_usd < 0.0 {
+            return Err(E::TransactionInvalid);
+        }
+        let (owner_id, portfolio_id) = if tx.id > 0 {
+            self.editable_row(mysql_pool, "transactions", tx.id)?
+        } else {
+            let owner_id = self.editable_portfolio(mysql_pool, tx.portfolio_id)?;
+            (owner_id, tx.portfolio_id)
+        };
+
+        // rolled back when dropped on errors
+        let mut t = mysql_pool.start_transaction(false, None, None)?;
+        // keep the states of the coin before the ledger takes over
+        merge_states(&mut t, owner_id, Some(portfolio_id), Some(&tx.coin_id), false)?;
+        // an edit may move the entry out of another coin's ledger
+        let mut coins = vec![tx.coin_id.clone()];
+        if tx.id > 0 {
+            let row = t.prep_exec("SELECT coin_id FROM transactions WHERE id=?", (tx.id,))?
+                .next()
+                .ok_or(E::RecordNotFound)?;
+            let coin_id: String = mysql::from_row(row?);
+            if coin_id != tx.coin_id {
+                coins.push(coin_id);
+            }
+        }
+        let mut lows = vec![];
+        for coin_id in coins.iter() {
+            lows.push(ledger_low(&ledger_entries(&mut t, owner_id, portfolio_id, coin_id)?));
+        }
+        if tx.id > 0 {
+            t.prep_exec(
+                "UPDATE transactions SET coin_id=?,kind=?,amount=?,price_usd=?,fee_usd=?,\
+                 wallet=?,to_wallet=?,created=? WHERE id=?",
+                (
+                    &tx.coin_id,
+                    tx.kind.as_str(),
+                    tx.amount,
+                    tx.price_usd,
+                    tx.fee_usd,
+                    &tx.wallet,
+                    &tx.to_wallet,
+                    tx.created,
+                    tx.id,
+                ),
+            )?;
+        } else {
+            t.prep_exec(
+                "INSERT INTO transactions \
+                 (user_id,portfolio_id,coin_id,kind,amount,price_usd,fee_usd,wallet,to_wallet,created) \
+                 VALUES (?,?,?,?,?,?,?,?,?,?)",
+                (
+                    owner_id,
+                    portfolio_id,
+                    &tx.coin_id,
+                    tx.kind.as_str(),
+                    tx.amount,
+                    tx.price_usd,
+                    tx.fee_usd,
+                    &tx.wallet,
+                    &tx.to_wallet,
+                    tx.created,
+                ),
+            )?;
+            fingerprint_entry(&mut t)?;
+        }
+        for (coin_id, &low) in coins.iter().zip(lows.iter()) {
+            let entries = ledger_entries(&mut t, owner_id, portfolio_id, coin_id)?;
+            check_overdraw(low, ledger_low(&entries))?;
+        }
+        t.commit()?;
+
+        Ok(())
+    }
+
+    /// hashes among `hashes` which were imported into the portfolio owner's ledger already
+    pub fn imported_hashes(
+        &self,
+        mysql_pool: &Pool,
+        portfolio_id: i64,
+        hashes: &[&str],
+    ) -> Result<Vec<String>, E> {
+        let mut data = vec![];
+        if hashes.is_empty() {
+            return Ok(data);
+        }
+        let owner_id = self.editable_portfolio(mysql_pool, portfolio_id)?;
+        let mut sql = String::from("SELECT import_hash FROM transactions WHERE user_id=? AND import_hash IN (");
+        let mut params = vec![mysql::Value::from(owner_id)];
+        for hash in hashes.iter() {
+            sql.push_str("?,");
+            params.push(mysql::Value::from(*hash));
+        }
+        sql.pop();
+        sql.push_str(")");
+
+        for row in mysql_pool.prep_exec(sql, params)? {
+            let hash: String = mysql::from_row(row?);
+            data.push(hash);
+        }
+
+        Ok(data)
+    }
+
+    /// insert imported transactions in one db transaction, rows imported before are ignored
+    /// by their hash, return the number of transactions inserted
+    pub fn import_transactions(
+        &self,
+        mysql_pool: &Pool,
+        txs: &[(&str, &Transaction)],
+    ) -> Result<u64, E> {
+        let mut inserted = 0;
+        // {PORTFOLIO => OWNER}
+        let mut owners = HashMap::<i64, i64>::new();
+        for &(_, tx) in txs.iter() {
+            if !owners.contains_key(&tx.portfolio_id) {
+                let owner_id = self.editable_portfolio(mysql_pool, tx.portfolio_id)?;
+                owners.insert(tx.portfolio_id, owner_id);
+            }
+        }
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                // keep the states of the coins before the ledger takes over
+                let mut pairs = vec![];
+                for &(_, tx) in txs.iter() {
+                    let pair = (tx.portfolio_id, tx.coin_id.as_str());
+                    if !pairs.contains(&pair) {
+                        pairs.push(pair);
+                    }
+                }
+                for &(portfolio_id, coin_id) in pairs.iter() {
+                    merge_states(&mut t, owners[&portfolio_id], Some(portfolio_id), Some(coin_id), false)?;
+                }
+                for &(hash, tx) in txs.iter() {
+                    let ret = t.prep_exec(
+                        "INSERT IGNORE INTO transactions \
+                         (user_id,portfolio_id,coin_id,kind,amount,price_usd,fee_usd,wallet,to_wallet,created,import_hash) \
+                         VALUES (?,?,?,?,?,?,?,?,?,?,?)",
+                        vec![
+                            mysql::Value::from(owners[&tx.portfolio_id]),
+                            mysql::Value::from(tx.portfolio_id),
+                            mysql::Value::from(&tx.coin_id),
+                            mysql::Value::from(tx.kind.as_str()),
+                            mysql::Value::from(tx.amount),
+                            mysql::Value::from(tx.price_usd),
+                            mysql::Value::from(tx.fee_usd),
+                            mysql::Value::from(&tx.wallet),
+                            mysql::Value::from(&tx.to_wallet),
+                            mysql::Value::from(tx.created),
+                            mysql::Value::from(hash),
+                        ],
+                    )?;
+                    inserted += ret.affected_rows();
+                }
+                t.commit()
+            })?;
+
+        Ok(inserted)
+    }
+
+    /// delete a ledger entry, like put_transaction it can't overdraw the ledger
+    pub fn del_transaction(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
+        let (owner_id, portfolio_id) = self.editable_row(mysql_pool, "transactions", id)?;
+        let mut t = mysql_pool.start_transaction(false, None, None)?;
+        let row = t.prep_exec("SELECT coin_id FROM transactions WHERE id=?", (id,))?
+            .next()
+            .ok_or(E::RecordNotFound)?;
+        let coin_id: String = mysql::from_row(row?);
+        let low = ledger_low(&ledger_entries(&mut t, owner_id, portfolio_id, &coin_id)?);
+        t.prep_exec("DELETE FROM transactions WHERE id=?", (id,))?;
+        let entries = ledger_entries(&mut t, owner_id, portfolio_id, &coin_id)?;
+        check_overdraw(low, ledger_low(&entries))?;
+        t.commit()?;
+
+        Ok(())
+    }
+
+    /// delete a row of states, balance or transactions the user can change
+    fn del_row(&self, mysql_pool: &Pool, table: &str, id: i64) -> Result<(), E> {
+        self.editable_row(mysql_pool, table, id)?;
+        mysql_pool.prep_exec(format!("DELETE FROM {} WHERE id=?", table), (id,))?;
+
+        Ok(())
+    }
+
+    /// (OWNER, PORTFOLIO) of a row of states, balance or transactions the user can change.
+    /// A row the user can't see is not found, one the user can only view is read only.
+    fn editable_row(&self, mysql_pool: &Pool, table: &str, id: i64) -> Result<(i64, i64), E> {
+        let (cond, cond_params) = self.portfolio_cond(None);
+        let mut params = vec![mysql::Value::from(id)];
+        params.extend(cond_params);
+        let row = mysql_pool
+            .prep_exec(
+                format!("SELECT user_id,portfolio_id FROM {} WHERE id=? AND {}", table, cond),
+                params,
+            )?
+            .next()
+            .ok_or(E::RecordNotFound)?;
+        let (owner_id, portfolio_id): (i64, i64) = mysql::from_row(row?);
+
+        let (cond, cond_params) = self.editable_cond();
+        let mut params = vec![mysql::Value::from(id)];
+        params.extend(cond_params);
+        match mysql_pool
+            .prep_exec(format!("SELECT id FROM {} WHERE id=? AND {}", table, cond), params)?
+            .next()
+        {
+            Some(_) => Ok((owner_id, portfolio_id)),
+            None => Err(E::PortfolioReadOnly),
+        }
+    }
+
+    /// transactions of a portfolio, or all portfolios if it's None, order by created time asc
+    pub fn transactions(
+        &self,
+        mysql_pool: &Pool,
+        coin_id: Option<&str>,
+        portfolio: Option<i64>,
+    ) -> Result<Vec<Transaction>, E> {
+        let (cond, mut params) = self.portfolio_cond(portfolio);
+        let mut sql = format!(
+            "SELECT id,portfolio_id,coin_id,kind,amount,price_usd,fee_usd,wallet,to_wallet,created \
+             FROM transactions WHERE {}",
+            cond
+        );
+        if let Some(coin_id) = coin_id {
+            sql.push_str(" AND coin_id=?");
+            params.push(mysql::Value::from(coin_id));
+        }
+        sql.push_str(" ORDER BY created ASC,id ASC");
+
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(sql, params)? {
+            let (id, portfolio_id, coin_id, kind, amount, price_usd, fee_usd, wallet, to_wallet, created): (
+                i64,
+                i64,
+                String,
+                String,
+                f64,
+                f64,
+                f64,
+                String,
+                String,
+                i64,
+            ) = mysql::from_row(row?);
+            let kind = match TxKind::parse(&kind) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            data.push(Transaction {
+                id: id,
+                portfolio_id: portfolio_id,
+                coin_id: coin_id,
+                kind: kind,
+                amount: amount,
+                price_usd: price_usd,
+                fee_usd: fee_usd,
+                wallet: wallet,
+                to_wallet: to_wallet,
+                created: created,
+            });
+        }
+
+        Ok(data)
+    }
+
+    /// fingerprints of the ledger entries of a portfolio, or all portfolios if it's None,
+    /// {ID => FINGERPRINT}
+    pub fn fingerprints(
+        &self,
+        mysql_pool: &Pool,
+        portfolio: Option<i64>,
+    ) -> Result<HashMap<i64, String>, E> {
+        let (cond, params) = self.portfolio_cond(portfolio);
+        let mut data = HashMap::new();
+        for row in mysql_pool.prep_exec(
+            format!(
+                "SELECT id,import_hash FROM transactions WHERE {}",
+                cond
+            ),
+            params,
+        )? {
+            let (id, fingerprint): (i64, Option<String>) = mysql::from_row(row?);
+            if let Some(fingerprint) = fingerprint {
+                data.insert(id, fingerprint);
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// states rows the user put order by created time asc, [(ID, COIN, AMOUNT, CREATED)]
+    pub fn states_rows(
+        &self,
+        mysql_pool: &Pool,
+        portfolio: Option<i64>,
+    ) -> Result<Vec<(i64, String, f64, i64)>, E> {
+        let (cond, params) = self.portfolio_cond(portfolio);
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            format!(
+                "SELECT id,coin_id,amount,created FROM states WHERE {} ORDER BY created ASC",
+                cond
+            ),
+            params,
+        )? {
+            data.push(mysql::from_row(row?));
+        }
+
+        Ok(data)
+    }
+
+    /// restore states, balance and transactions from an export, rows which exist already are
+    /// skipped so it can be run again. Return the number of rows restored.
+    pub fn restore(&self, mysql_pool: &Pool, portfolio_id: i64, data: &Value) -> Result<u64, E> {
+        let owner_id = self.editable_portfolio(mysql_pool, portfolio_id)?;
+        let empty = vec![];
+        let mut txs = vec![];
+        for item in data["transactions"].as_array().unwrap_or(&empty).iter() {
+            let kind = match item["kind"].as_str().and_then(TxKind::parse) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let (coin_id, amount, created) = match (
+                item["coin_id"].as_str(),
+                item["amount"].as_f64(),
+                item["created"].as_i64(),
+            ) {
+                (Some(c), Some(a), Some(t)) => (c, a, t),
+                _ => continue,
+            };
+            txs.push((item, kind, coin_id, amount, created, restore_fingerprint(item)?));
+        }
+        let mut restored = 0;
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                for item in data["states"].as_array().unwrap_or(&empty).iter() {
+                    let (coin_id, amount, created) = match (
+                        item["coin_id"].as_str(),
+                        item["amount"].as_f64(),
+                        item["created"].as_i64(),
+                    ) {
+                        (Some(c), Some(a), Some(t)) => (c, a, t),
+                        _ => continue,
+                    };
+                    restored += t.prep_exec(
+                        "INSERT INTO states (user_id,portfolio_id,coin_id,amount,created) \
+                         SELECT ?,?,?,?,? FROM DUAL WHERE NOT EXISTS \
+                         (SELECT 1 FROM states WHERE user_id=? AND portfolio_id=? AND coin_id=? \
+                         AND created=?)",
+                        vec![
+                            mysql::Value::from(owner_id),
+                            mysql::Value::from(portfolio_id),
+                            mysql::Value::from(coin_id),
+                            mysql::Value::from(amount),
+                            mysql::Value::from(created),
+                            mysql::Value::from(owner_id),
+                            mysql::Value::from(portfolio_id),
+                            mysql::Value::from(coin_id),
+                            mysql::Value::from(created),
+                        ],
+                    )?
+                        .affected_rows();
+                }
+                for item in data["balance"].as_array().unwrap_or(&empty).iter() {
+                    let (amount, created) =
+                        match (item["amount"].as_f64(), item["created"].as_i64()) {
+                            (Some(a), Some(t)) => (a, t),
+                            _ => continue,
+                        };
+                    restored += t.prep_exec(
+                        "INSERT INTO balance (user_id,portfolio_id,amount,created) \
+                         SELECT ?,?,?,? FROM DUAL WHERE NOT EXISTS \
+                         (SELECT 1 FROM balance WHERE user_id=? AND portfolio_id=? AND created=?)",
+                        (owner_id, portfolio_id, amount, created, owner_id, portfolio_id, created),
+                    )?
+                        .affected_rows();
+                }
+                for &(item, kind, coin_id, amount, created, ref fingerprint) in txs.iter() {
+                    restored += t.prep_exec(
+                        "INSERT IGNORE INTO transactions \
+                         (user_id,portfolio_id,coin_id,kind,amount,price_usd,fee_usd,wallet,to_wallet,created,import_hash) \
+                         VALUES (?,?,?,?,?,?,?,?,?,?,?)",
+                        vec![
+                            mysql::Value::from(owner_id),
+                            mysql::Value::from(portfolio_id),
+                            mysql::Value::from(coin_id),
+                            mysql::Value::from(kind.as_str()),
+                            mysql::Value::from(amount),
+                            mysql::Value::from(item["price_usd"].as_f64().unwrap_or(0.0)),
+                            mysql::Value::from(item["fee_usd"].as_f64().unwrap_or(0.0)),
+                            mysql::Value::from(item["wallet"].as_str().unwrap_or("")),
+                            mysql::Value::from(item["to_wallet"].as_str().unwrap_or("")),
+                            mysql::Value::from(created),
+                            mysql::Value::from(fingerprint.as_str()),
+                        ],
+                    )?
+                        .affected_rows();
+                }
+                merge_states(&mut t, owner_id, Some(portfolio_id), None, true)?;
+                t.commit()
+            })?;
+
+        Ok(restored)
+    }
+
+    /// amount timeline of a portfolio, or the sum of all portfolios if it's None, order by
+    /// created time asc. Coins with transactions are derived from the ledger, which the states
+    /// of those coins were merged into, others come from the states the user put.
+    pub fn states(
+        &self,
+        mysql_pool: &Pool,
+        worker_state: &'a worker::State,
+        coin_id: Option<&str>,
+        portfolio: Option<i64>,
+    ) -> Result<Vec<UserCoin<'a>>, E> {
+        let txs = self.transactions(mysql_pool, coin_id, portfolio)?;
+        let mut states = ledger_states(&txs, worker_state);
+
+        let (cond, mut params) = self.portfolio_cond(portfolio);
+        let mut sql = format!(
+            "SELECT id,portfolio_id,coin_id,amount,created FROM states WHERE {} AND migrated=0",
+            cond
+        );
+        if let Some(coin_id) = coin_id {
+            sql.push_str(" AND coin_id=?");
+            params.push(mysql::Value::from(coin_id));
+        }
+        sql.push_str(" ORDER BY created ASC");
+        let ret = mysql_pool.prep_exec(sql, params)?;
+
+        for row in ret {
+            match row {
+                Ok(row) => {
+                    let (id, portfolio_id, coin_id, amount, created): (i64, i64, String, f64, i64) =
+                        mysql::from_row(row);
+                    // the ledger takes over, states are merged into it by `merge_states`
+                    if txs.iter()
+                        .any(|tx| tx.portfolio_id == portfolio_id && tx.coin_id == coin_id)
+                    {
+                        continue;
+                    }
+                    let coin = worker_state.coins.iter().find(|&x| x.id == coin_id);
+                    if !coin.is_none() {
+                        states.push(UserCoin {
+                            id: id,
+                            portfolio_id: portfolio_id,
+                            coin_id: coin_id,
+                            amount: amount,
+                            created: created,
+                            coin: coin,
+                            derived: false,
+                        });
+                    }
+                }
+                Err(_) => (),
+            }
+        }
+        states.sort_by_key(|state| state.created);
+
+        Ok(merge_portfolios(states))
+    }
+
+    /// merge the states of the user's portfolios into the ledger as opening balance, deposit and
+    /// withdrawal transactions, return the number of transactions created
+    pub fn migrate_states(&self, mysql_pool: &Pool) -> Result<usize, E> {
+        let mut created_num = 0;
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                created_num = merge_states(&mut t, self.id, None, None, false)?;
+                t.commit()
+            })?;
+
+        Ok(created_num)
+    }
+
+    /// current value in usd of a portfolio, or of all portfolios if it's None
+    pub fn portfolio_value(
+        &self,
+        mysql_pool: &Pool,
+        worker_state: &worker::State,
+        portfolio: Option<i64>,
+    ) -> Result<f64, E> {
+        let mut user_states = self.states(mysql_pool, worker_state, None, portfolio)?;
+        user_states.reverse();
+        let mut got_coins = vec![];
+        let mut value = 0.0;
+        for state in user_states {
+            if !got_coins.contains(&state.coin_id) {
+                got_coins.push(state.coin_id.clone());
+                value += state.coin.map(|x| x.price_usd).unwrap_or(0.0) * state.amount;
+            }
+        }
+
+        Ok(value)
+    }
+
+    pub fn alerts(&self, mysql_pool: &Pool) -> Result<Vec<Alert>, E> {
+        Alert::find(mysql_pool, Some(self.id))
+    }
+
+    /// create or update an alert rule, return its id. An updated rule is armed again.
+    pub fn put_alert(&self, mysql_pool: &Pool, alert: &Alert) -> Result<i64, E> {
+        if let Some(portfolio_id) = alert.portfolio_id {
+            self.check_portfolio(mysql_pool, portfolio_id)?;
+        }
+        if alert.id > 0 {
+            let ret = mysql_pool.prep_exec(
+                "UPDATE alerts SET kind=?,coin_id=?,portfolio_id=?,currency=?,threshold=?,\
+                 cooldown=?,armed=1 WHERE id=? AND user_id=?",
+                (
+                    alert.kind.as_str(),
+                    &alert.coin_id,
+                    alert.portfolio_id,
+                    &alert.currency,
+                    alert.threshold,
+                    alert.cooldown,
+                    alert.id,
+                    self.id,
+                ),
+            )?;
+            // an update changing nothing doesn't count as affected either
+            if ret.affected_rows() == 0 {
+                self.check_alert(mysql_pool, alert.id)?;
+            }
+            Ok(alert.id)
+        } else {
+            let ret = mysql_pool.prep_exec(
+                "INSERT INTO alerts \
+                 (user_id,kind,coin_id,portfolio_id,currency,threshold,cooldown,armed,last_fired,created) \
+                 VALUES (?,?,?,?,?,?,?,1,0,?)",
+                (
+                    self.id,
+                    alert.kind.as_str(),
+                    &alert.coin_id,
+                    alert.portfolio_id,
+                    &alert.currency,
+                    alert.threshold,
+                    alert.cooldown,
+                    time::get_time().sec,
+                ),
+            )?;
+            Ok(ret.last_insert_id() as i64)
+        }
+    }
+
+    /// make sure the alert belongs to the user
+    fn check_alert(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
+        match mysql_pool
+            .prep_exec("SELECT id FROM alerts WHERE id=? AND user_id=?", (id, self.id))?
+            .next()
+        {
+            Some(row) => {
+                row?;
+                Ok(())
+            }
+            None => Err(E::RecordNotFound),
+        }
+    }
+
+    pub fn del_alert(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
+        let ret =
+            mysql_pool.prep_exec("DELETE FROM alerts WHERE user_id=? AND id=?", (self.id, id))?;
+        if ret.affected_rows() == 0 {
+            return Err(E::RecordNotFound);
+        }
+
+        Ok(())
+    }
+
+    pub fn webhooks(&self, mysql_pool: &Pool) -> Result<Vec<WebhookSub>, E> {
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            "SELECT id,url,secret,events,active,created FROM webhooks WHERE user_id=? ORDER BY id ASC",
+            (self.id,),
+        )? {
+            let (id, url, secret, events, active, created): (i64, String, String, String, bool, i64) =
+                mysql::from_row(row?);
+            data.push(WebhookSub {
+                id: id,
+                user_id: self.id,
+                url: url,
+                secret: secret,
+                events: events.split(',').map(|x| x.to_string()).collect(),
+                active: active,
+                created: created,
+            });
+        }
+
+        Ok(data)
+    }
+
+    /// create or update a webhook subscription, return its id. The secret is generated once
+    /// when it's created.
+    pub fn put_webhook(
+        &self,
+        mysql_pool: &Pool,
+        id: i64,
+        url: &str,
+        events: &[&str],
+        active: bool,
+        allow_private_hosts: bool,
+    ) -> Result<i64, E> {
+        if !(url.starts_with("http://") || url.starts_with("https://")) || events.is_empty()
+            || events.iter().any(|x| !webhook::EVENTS.contains(x))
+            || !(allow_private_hosts || utils::check_public_url(url).is_ok())
+        {
+            return Err(E::WebhookInvalid);
+        }
+        let events = events.join(",");
+        if id > 0 {
+            let ret = mysql_pool.prep_exec(
+                "UPDATE webhooks SET url=?,events=?,active=? WHERE id=? AND user_id=?",
+                (url, events, active, id, self.id),
+            )?;
+            if ret.affected_rows() == 0 {
+                self.webhook_secret(mysql_pool, id)?;
+            }
+            Ok(id)
+        } else {
+            let secret: String = rand::thread_rng().gen_ascii_chars().take(32).collect();
+            let ret = mysql_pool.prep_exec(
+                "INSERT INTO webhooks (user_id,url,secret,events,active,created) VALUES (?,?,?,?,?,?)",
+                (self.id, url, secret, events, active, time::get_time().sec),
+            )?;
+            Ok(ret.last_insert_id() as i64)
+        }
+    }
+
+    /// make sure the webhook belongs to the user and return its secret
+    fn webhook_secret(&self, mysql_pool: &Pool, id: i64) -> Result<String, E> {
+        match mysql_pool
+            .prep_exec(
+                "SELECT secret FROM webhooks WHERE id=? AND user_id=?",
+                (id, self.id),
+            )?
+            .next()
+        {
+            Some(row) => Ok(mysql::from_row(row?)),
+            None => Err(E::WebhookNotFound),
+        }
+    }
+
+    pub fn del_webhook(&self, mysql_pool: &Pool, id: i64) -> Result<(), E> {
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                t.prep_exec(
+                    "DELETE FROM webhook_deliveries WHERE webhook_id=? AND user_id=?",
+                    (id, self.id),
+                )?;
+                t.prep_exec(
+                    "DELETE FROM webhooks WHERE id=? AND user_id=?",
+                    (id, self.id),
+                )?;
+                t.commit()
+            })?;
+
+        Ok(())
+    }
+
+    /// latest deliveries of a webhook, newest first
+    pub fn webhook_deliveries(
+        &self,
+        mysql_pool: &Pool,
+        webhook_id: i64,
+        limit: i64,
+    ) -> Result<Vec<Value>, E> {
+        self.webhook_secret(mysql_pool, webhook_id)?;
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(
+            "SELECT id,event,payload,status,attempts,next_attempt,response_code,delivered,created \
+             FROM webhook_deliveries WHERE webhook_id=? ORDER BY id DESC LIMIT ?",
+            (webhook_id, limit),
+        )? {
+            let (
+                id,
+                event,
+                payload,
+                status,
+                attempts,
+                next_attempt,
+                response_code,
+                delivered,
+                created,
+            ): (i64, String, String, String, i64, i64, i64, i64, i64) = mysql::from_row(row?);
+            data.push(json!({
+                "id": id,
+                "event": event,
+                "payload": serde_json::from_str::<Value>(&payload).unwrap_or(Value::Null),
+                "status": status,
+                "attempts": attempts,
+                "next_attempt": next_attempt,
+                "response_code": response_code,
+                "delivered": delivered,
+                "created": created,
+            }));
+        }
+
+        Ok(data)
+    }
+}
+
+/// email and password login of a user. It's added to a user signed up by sms, or creates a
+/// user when the email is verified.
+pub struct EmailLogin;
+
+impl EmailLogin {
+    const VERIFY_EXPIRES_IN: i64 = 86400;
+
+    /// lowercase and validate an email address
+    pub fn normalize(email: &str) -> Result<String, E> {
+        let email = email.trim().to_lowercase();
+        if !Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$")?.is_match(&email) {
+            return Err(E::EmailInvalid);
+        }
+
+        Ok(email)
+    }
+
+    fn hash_token(token: &str) -> String {
+        let mut sh = Sha256::new();
+        sh.input_str(token);
+        sh.result_str()
+    }
+
+    /// register an unverified email login, of the user if it's not 0, and return the token
+    /// verifying it. An unverified email can be registered again.
+    pub fn register(
+        mysql_pool: &Pool,
+        email: &str,
+        password: &str,
+        name: &str,
+        user_id: i64,
+    ) -> Result<String, E> {
+        if password.chars().count() < 8 {
+            return Err(E::PasswordWeak);
+        }
+        let taken = mysql_pool
+            .prep_exec(
+                "SELECT email FROM email_logins WHERE verified=1 AND (email=? OR user_id=?)",
+                (email, user_id),
+            )?
+            .next();
+        if let Some(row) = taken {
+            let taken_email: String = mysql::from_row(row?);
+            return Err(if taken_email == email {
+                E::EmailTaken
+            } else {
+                E::EmailLoginExists
+            });
+        }
+        // scrypt with the recommended interactive parameters, the salt is kept in the hash
+        let password_hash = scrypt_simple(password, &ScryptParams::new(14, 8, 1))
+            .map_err(|e| E::Internal(format!("scrypt: {}", e)))?;
+        let mut rng = OsRng::new().map_err(|e| E::Internal(format!("rng: {}", e)))?;
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        let token = bytes.to_base64(URL_SAFE);
+        let now = time::get_time().sec;
+        mysql_pool.prep_exec(
+            "REPLACE INTO email_logins (email,user_id,name,password_hash,verified,verify_hash,\
+             verify_expires,err_times,failed,created) VALUES (?,?,?,?,0,?,?,0,0,?)",
+            (
+                email,
+                user_id,
+                name,
+                password_hash,
+                EmailLogin::hash_token(&token),
+                now + Self::VERIFY_EXPIRES_IN,
+                now,
+            ),
+        )?;
+
+        Ok(token)
+    }
+
+    /// verify the email by token, create the user if it's not linked to one yet, and return
+    /// (EMAIL, USER ID)
+    pub fn verify(mysql_pool: &Pool, token: &str) -> Result<(String, i64), E> {
+        let ret = mysql_pool
+            .prep_exec(
+                "SELECT email,user_id,name,verify_expires FROM email_logins \
+                 WHERE verify_hash=? AND verified=0",
+                (EmailLogin::hash_token(token),),
+            )?
+            .next();
+        let (email, user_id, name, verify_expires): (String, i64, String, i64) = match ret {
+            Some(row) => mysql::from_row(row?),
+            None => return Err(E::EmailTokenInvalid),
+        };
+        let now = time::get_time().sec;
+        if verify_expires < now {
+            return Err(E::EmailTokenInvalid);
+        }
+
+        let user_id = mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                let user_id = if user_id == 0 {
+                    let ret = t.prep_exec(
+                        "INSERT INTO users (name,mobile,email,notify_channel,created) \
+                         VALUES (?,'',?,'email',?)",
+                        (&name, &email, now),
+                    )?;
+                    ret.last_insert_id() as i64
+                } else {
+                    // becomes the notification address unless one was set
+                    t.prep_exec(
+                        "UPDATE users SET email=? WHERE id=? AND email=''",
+                        (&email, user_id),
+                    )?;
+                    user_id
+                };
+                t.prep_exec(
+                    "UPDATE email_logins SET user_id=?,verified=1,verify_hash='' WHERE email=?",
+                    (user_id, &email),
+                )?;
+                // other pending emails of the user
+                t.prep_exec(
+                    "DELETE FROM email_logins WHERE user_id=? AND verified=0",
+                    (user_id,),
+                )?;
+                t.commit().map(|_| user_id)
+            })?;
+
+        Ok((email, user_id))
+    }
+
+    /// check the password of the user's verified email login, for confirming dangerous actions
+    pub fn check_password(mysql_pool: &Pool, user_id: i64, password: &str) -> Result<(), E> {
+        let email: String = match mysql_pool
+            .prep_exec(
+                "SELECT email FROM email_logins WHERE user_id=? AND verified=1 LIMIT 1",
+                (user_id,),
+            )?
+            .next()
+        {
+            Some(row) => mysql::from_row(row?),
+            None => return Err(E::PasswordInvalid),
+        };
+        if EmailLogin::login(mysql_pool, &email, password)?.id != user_id {
+            return Err(E::PasswordInvalid);
+        }
+
+        Ok(())
+    }
+
+    /// check email and password, return the user
+    pub fn login(mysql_pool: &Pool, email: &str, password: &str) -> Result<User, E> {
+        let now = time::get_time().sec;
+        let ret = mysql_pool
+            .prep_exec(
+                "SELECT user_id,password_hash,verified,err_times,failed FROM email_logins \
+                 WHERE email=?",
+                (email,),
+            )?
+            .next();
+        let (user_id, password_hash, verified, err_times, failed): (
+            i64,
+            String,
+            bool,
+            i64,
+            i64,
+        ) = match ret {
+            Some(row) => mysql::from_row(row?),
+            None => return Err(E::PasswordInvalid),
+        };
+        if err_times >= 10 && failed + 600 > now {
+            return Err(E::LoginLimit);
+        }
+        if !scrypt_check(password, &password_hash).unwrap_or(false) {
+            mysql_pool.prep_exec(
+                "UPDATE email_logins SET err_times=IF(failed+600>?,err_times+1,1),failed=? \
+                 WHERE email=?",
+                (now, now, email),
+            )?;
+            return Err(E::PasswordInvalid);
+        }
+        // told only to who knows the password
+        if !verified {
+            return Err(E::EmailNotVerified);
+        }
+        mysql_pool.prep_exec(
+            "UPDATE email_logins SET err_times=0 WHERE email=?",
+            (email,),
+        )?;
+
+        User::find(mysql_pool, Some(user_id), None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Owner,
+    Editor,
+    Viewer,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "owner" => Some(Role::Owner),
+            "editor" => Some(Role::Editor),
+            "viewer" => Some(Role::Viewer),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Role::Owner => "owner",
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Portfolio {
+    pub id: i64,
+    /// owner's id
+    pub user_id: i64,
+    pub name: String,
+    /// the role of the user who fetched it
+    pub role: Role,
+    pub created: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TxKind {
+    Buy,
+    Sell,
+    Deposit,
+    Withdrawal,
+    /// move between wallets of the user, total amount doesn't change
+    Transfer,
+    /// fee paid in coin
+    Fee,
+    Airdrop,
+    Staking,
+    /// balance migrated from states
+    Opening,
+}
+
+impl TxKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "buy" => Some(TxKind::Buy),
+            "sell" => Some(TxKind::Sell),
+            "deposit" => Some(TxKind::Deposit),
+            "withdrawal" => Some(TxKind::Withdrawal),
+            "transfer" => Some(TxKind::Transfer),
+            "fee" => Some(TxKind::Fee),
+            "airdrop" => Some(TxKind::Airdrop),
+            "staking" => Some(TxKind::Staking),
+            "opening" => Some(TxKind::Opening),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TxKind::Buy => "buy",
+            TxKind::Sell => "sell",
+            TxKind::Deposit => "deposit",
+            TxKind::Withdrawal => "withdrawal",
+            TxKind::Transfer => "transfer",
+            TxKind::Fee => "fee",
+            TxKind::Airdrop => "airdrop",
+            TxKind::Staking => "staking",
+            TxKind::Opening => "opening",
+        }
+    }
+
+    /// how the kind changes the amount of holdings, 1, -1 or 0
+    pub fn sign(&self) -> f64 {
+        match *self {
+            TxKind::Buy | TxKind::Deposit | TxKind::Airdrop | TxKind::Staking | TxKind::Opening => {
+                1.0
+            }
+            TxKind::Sell | TxKind::Withdrawal | TxKind::Fee => -1.0,
+            TxKind::Transfer => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertKind {
+    PriceAbove,
+    PriceBelow,
+    /// percent_change_24h rises to the threshold
+    ChangeAbove,
+    /// percent_change_24h falls to the threshold, which is usually negative
+    ChangeBelow,
+    ValueAbove,
+    ValueBelow,
+}
+
+impl AlertKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "price_above" => Some(AlertKind::PriceAbove),
+            "price_below" => Some(AlertKind::PriceBelow),
+            "change_above" => Some(AlertKind::ChangeAbove),
+            "change_below" => Some(AlertKind::ChangeBelow),
+            "value_above" => Some(AlertKind::ValueAbove),
+            "value_below" => Some(AlertKind::ValueBelow),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AlertKind::PriceAbove => "price_above",
+            AlertKind::PriceBelow => "price_below",
+            AlertKind::ChangeAbove => "change_above",
+            AlertKind::ChangeBelow => "change_below",
+            AlertKind::ValueAbove => "value_above",
+            AlertKind::ValueBelow => "value_below",
+        }
+    }
+
+    /// watch a coin rather than a portfolio
+    pub fn on_coin(&self) -> bool {
+        match *self {
+            AlertKind::ValueAbove | AlertKind::ValueBelow => false,
+            _ => true,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match *self {
+            AlertKind::PriceAbove => "价格高于",
+            AlertKind::PriceBelow => "价格低于",
+            AlertKind::ChangeAbove => "24小时涨幅达到",
+            AlertKind::ChangeBelow => "24小时跌幅达到",
+            AlertKind::ValueAbove => "资产高于",
+            AlertKind::ValueBelow => "资产低于",
+        }
+    }
+}
+
+/// a rule fires once when its condition becomes true, then it's disarmed until the condition
+/// turns false again. A rearmed rule can't fire again within `cooldown` seconds.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub id: i64,
+    pub user_id: i64,
+    pub kind: AlertKind,
+    /// empty for portfolio value rules
+    pub coin_id: String,
+    /// portfolio watched by value rules, None for all portfolios
+    pub portfolio_id: Option<i64>,
+    /// currency of threshold for price and value rules
+    pub currency: String,
+    pub threshold: f64,
+    pub cooldown: i64,
+    pub armed: bool,
+    pub last_fired: i64,
+    pub created: i64,
+}
+
+impl Alert {
+    /// alert rules of a user, or of all users if it's None
+    pub fn find(mysql_pool: &Pool, user_id: Option<i64>) -> Result<Vec<Alert>, E> {
+        let mut sql = String::from(
+            "SELECT id,user_id,kind,coin_id,portfolio_id,currency,threshold,cooldown,armed,\
+             last_fired,created FROM alerts",
+        );
+        let mut params = vec![];
+        if let Some(user_id) = user_id {
+            sql.push_str(" WHERE user_id=?");
+            params.push(mysql::Value::from(user_id));
+        }
+        sql.push_str(" ORDER BY id ASC");
+        let mut data = vec![];
+        for row in mysql_pool.prep_exec(sql, params)? {
+            let (
+                id,
+                user_id,
+                kind,
+                coin_id,
+                portfolio_id,
+                currency,
+                threshold,
+                cooldown,
+                armed,
+                last_fired,
+                created,
+            ): (
+                i64,
+                i64,
+                String,
+                String,
+                Option<i64>,
+                String,
+                f64,
+                i64,
+                bool,
+                i64,
+                i64,
+            ) = mysql::from_row(row?);
+            let kind = match AlertKind::parse(&kind) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            data.push(Alert {
+                id: id,
+                user_id: user_id,
+                kind: kind,
+                coin_id: coin_id,
+                portfolio_id: portfolio_id,
+                currency: currency,
+                threshold: threshold,
+                cooldown: cooldown,
+                armed: armed,
+                last_fired: last_fired,
+                created: created,
+            });
+        }
+
+        Ok(data)
+    }
+
+    /// whether the condition holds for the watched value
+    pub fn hit(&self, value: f64) -> bool {
+        match self.kind {
+            AlertKind::PriceAbove | AlertKind::ChangeAbove | AlertKind::ValueAbove => {
+                value >= self.threshold
+            }
+            AlertKind::PriceBelow | AlertKind::ChangeBelow | AlertKind::ValueBelow => {
+                value <= self.threshold
+            }
+        }
+    }
+
+    /// whether the rule fires for the watched value at `now`
+    pub fn should_fire(&self, value: f64, now: i64) -> bool {
+        self.armed && self.hit(value) && now >= self.last_fired + self.cooldown
+    }
+
+    /// the rule fired, disarm it
+    pub fn fired(&mut self, mysql_pool: &Pool, now: i64) -> Result<(), E> {
+        self.armed = false;
+        self.last_fired = now;
+        mysql_pool.prep_exec(
+            "UPDATE alerts SET armed=0,last_fired=? WHERE id=?",
+            (now, self.id),
+        )?;
+
+        Ok(())
+    }
+
+    /// the rule can't be checked for now, keep it from firing without marking it fired
+    pub fn disarm(&mut self, mysql_pool: &Pool) -> Result<(), E> {
+        self.armed = false;
+        mysql_pool.prep_exec("UPDATE alerts SET armed=0 WHERE id=?", (self.id,))?;
+
+        Ok(())
+    }
+
+    /// the condition turned false, arm the rule again
+    pub fn rearm(&mut self, mysql_pool: &Pool) -> Result<(), E> {
+        self.armed = true;
+        mysql_pool.prep_exec("UPDATE alerts SET armed=1 WHERE id=?", (self.id,))?;
+
+        Ok(())
+    }
+
+    /// human readable condition, e.g. "BTC价格高于10000.00USD"
+    pub fn describe(&self, subject: &str) -> String {
+        match self.kind {
+            AlertKind::ChangeAbove | AlertKind::ChangeBelow => {
+                format!("{}{}{:.2}%", subject, self.kind.label(), self.threshold)
+            }
+            _ => format!(
+                "{}{}{:.2}{}",
+                subject,
+                self.kind.label(),
+                self.threshold,
+                self.currency
+            ),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "kind": self.kind.as_str(),
+            "coin_id": self.coin_id,
+            "portfolio_id": self.portfolio_id,
+            "currency": self.currency,
+            "threshold": self.threshold,
+            "cooldown": self.cooldown,
+            "armed": self.armed,
+            "last_fired": self.last_fired,
+            "created": self.created,
+        })
+    }
+}
+
+/// a webhook subscription, events are posted to url signed by secret
+#[derive(Debug)]
+pub struct WebhookSub {
+    pub id: i64,
+    pub user_id: i64,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created: i64,
+}
+
+impl WebhookSub {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "url": self.url,
+            "secret": self.secret,
+            "events": self.events,
+            "active": self.active,
+            "created": self.created,
+        })
+    }
+}
+
+/// a ledger entry of coin, amount is always positive and prices are unit prices in usd
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub id: i64,
+    pub portfolio_id: i64,
+    pub coin_id: String,
+    pub kind: TxKind,
+    pub amount: f64,
+    pub price_usd: f64,
+    pub fee_usd: f64,
+    /// the wallet coin is in, or moved out of for transfer
+    pub wallet: String,
+    /// the wallet coin is moved to, only for transfer
+    pub to_wallet: String,
+    pub created: i64,
+}
+
+impl Transaction {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "portfolio_id": self.portfolio_id,
+            "coin_id": self.coin_id,
+            "kind": self.kind.as_str(),
+            "amount": self.amount,
+            "price_usd": self.price_usd,
+            "fee_usd": self.fee_usd,
+            "wallet": self.wallet,
+            "to_wallet": self.to_wallet,
+            "created": self.created,
+        })
+    }
+}
+
+/// derive the amount timeline from transactions order by created time asc, one state per
+/// transaction which changes the amount
+pub fn ledger_states<'a>(
+    txs: &[Transaction],
+    worker_state: &'a worker::State,
+) -> Vec<UserCoin<'a>> {
+    // {(PORTFOLIO, COIN) => AMOUNT}
+    let mut amounts = HashMap::<(i64, &str), f64>::new();
+    let mut states = vec![];
+    for tx in txs.iter() {
+        if tx.kind.sign() == 0.0 {
+            continue;
+        }
+        let amount = {
+            let amount = amounts
+                .entry((tx.portfolio_id, tx.coin_id.as_str()))
+                .or_insert(0.0);
+            *amount += tx.kind.sign() * tx.amount;
+            *amount
+        };
+        let coin = worker_state.coins.iter().find(|&x| x.id == tx.coin_id);
+        if !coin.is_none() {
+            states.push(UserCoin {
+                id: tx.id,
+                portfolio_id: tx.portfolio_id,
+                coin_id: tx.coin_id.clone(),
+                amount: amount,
+                created: tx.created,
+                coin: coin,
+                derived: true,
+            });
+        }
+    }
+
+    states
+}
+
+/// merge states of the owner's portfolio, or of all portfolios if it's None, into the ledger,
+/// so that the snapshots are kept when the ledger takes over. Each state becomes the opening
+/// balance, or a deposit or withdrawal of its difference to the ledger amount at that time.
+/// With `ledger_only` coins without transactions are left alone. Merged states are marked and
+/// stay for the export. Return the number of transactions created.
+fn merge_states(
+    t: &mut mysql::Transaction,
+    owner_id: i64,
+    portfolio_id: Option<i64>,
+    coin_id: Option<&str>,
+    ledger_only: bool,
+) -> Result<usize, mysql::Error> {
+    let mut sql = String::from(
+        "SELECT s.id,s.portfolio_id,s.coin_id,s.amount,s.created FROM states s \
+         WHERE s.user_id=? AND s.migrated=0",
+    );
+    let mut params = vec![mysql::Value::from(owner_id)];
+    if let Some(portfolio_id) = portfolio_id {
+        sql.push_str(" AND s.portfolio_id=?");
+        params.push(mysql::Value::from(portfolio_id));
+    }
+    if let Some(coin_id) = coin_id {
+        sql.push_str(" AND s.coin_id=?");
+        params.push(mysql::Value::from(coin_id));
+    }
+    if ledger_only {
+        sql.push_str(
+            " AND EXISTS (SELECT 1 FROM transactions t WHERE t.user_id=s.user_id \
+             AND t.portfolio_id=s.portfolio_id AND t.coin_id=s.coin_id)",
+        );
+    }
+    sql.push_str(" ORDER BY s.portfolio_id ASC,s.coin_id ASC,s.created ASC,s.id ASC");
+    let mut rows = vec![];
+    for row in t.prep_exec(sql, params)? {
+        let row: (i64, i64, String, f64, i64) = mysql::from_row(row?);
+        rows.push(row);
+    }
+
+    let mut created_num = 0;
+    // [(CREATED, SIGNED AMOUNT)] of the ledger of the current portfolio and coin
+    let mut ledger = Vec::<(i64, f64)>::new();
+    let mut pair: Option<(i64, &str)> = None;
+    for &(id, portfolio_id, ref coin_id, amount, created) in rows.iter() {
+        if pair != Some((portfolio_id, coin_id.as_str())) {
+            ledger.clear();
+            for row in t.prep_exec(
+                "SELECT kind,amount,created FROM transactions \
+                 WHERE user_id=? AND portfolio_id=? AND coin_id=?",
+                (owner_id, portfolio_id, coin_id),
+            )? {
+                let (kind, amount, created): (String, f64, i64) = mysql::from_row(row?);
+                if let Some(kind) = TxKind::parse(&kind) {
+                    ledger.push((created, kind.sign() * amount));
+                }
+            }
+            pair = Some((portfolio_id, coin_id.as_str()));
+        }
+
+        // entries at the same second count as before the snapshot
+        let before: Vec<f64> = ledger
+            .iter()
+            .filter(|x| x.0 <= created)
+            .map(|x| x.1)
+            .collect();
+        let ledger_amount = before.iter().fold(0.0, |acc, x| acc + x);
+        let (kind, delta) = if before.is_empty() {
+            (TxKind::Opening, amount)
+        } else if amount >= ledger_amount {
+            (TxKind::Deposit, amount - ledger_amount)
+        } else {
+            (TxKind::Withdrawal, ledger_amount - amount)
+        };
+        if delta != 0.0 {
+            t.prep_exec(
+                "INSERT INTO transactions (user_id,portfolio_id,coin_id,kind,amount,created) \
+                 VALUES (?,?,?,?,?,?)",
+                (owner_id, portfolio_id, coin_id, kind.as_str(), delta, created),
+            )?;
+            fingerprint_entry(t)?;
+            ledger.push((created, kind.sign() * delta));
+            created_num += 1;
+        }
+        t.prep_exec("UPDATE states SET migrated=1 WHERE id=?", (id,))?;
+    }
+
+    Ok(created_num)
+}
+
+/// signed amounts of the ledger of a coin, locked until the transaction ends so that
+/// concurrent writes are checked one after another. [(CREATED, AMOUNT)]
+fn ledger_entries(
+    t: &mut mysql::Transaction,
+    owner_id: i64,
+    portfolio_id: i64,
+    coin_id: &str,
+) -> Result<Vec<(i64, f64)>, mysql::Error> {
+    let mut entries = vec![];
+    for row in t.prep_exec(
+        "SELECT kind,amount,created FROM transactions \
+         WHERE user_id=? AND portfolio_id=? AND coin_id=? FOR UPDATE",
+        (owner_id, portfolio_id, coin_id),
+    )? {
+        let (kind, amount, created): (String, f64, i64) = mysql::from_row(row?);
+        if let Some(kind) = TxKind::parse(&kind) {
+            entries.push((created, kind.sign() * amount));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// the lowest amount a ledger holds over time, 0 if it never goes negative. Entries at the same
+/// second count together.
+fn ledger_low(entries: &[(i64, f64)]) -> f64 {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|x| x.0);
+    let mut amount = 0.0;
+    let mut low = 0.0;
+    for (i, entry) in entries.iter().enumerate() {
+        amount += entry.1;
+        let last_of_second = entries.get(i + 1).map_or(true, |next| next.0 != entry.0);
+        if last_of_second && amount < low {
+            low = amount;
+        }
+    }
+
+    low
+}
+
+/// a write must not make a ledger go negative, one which did already, like an imported
+/// partial history, must not get worse
+fn check_overdraw(low_before: f64, low_after: f64) -> Result<(), E> {
+    if low_after < -1e-9 && low_after < low_before - 1e-9 {
+        return Err(E::TransactionInvalid);
+    }
+
+    Ok(())
+}
+
+/// random fingerprint of a ledger entry which wasn't imported, unique across instances so
+/// that a restored export never takes the place of another entry
+fn new_fingerprint() -> String {
+    let mut sh = Sha1::new();
+    sh.input_str(&Uuid::new_v4().simple().to_string());
+    sh.result_str()
+}
+
+/// give the ledger entry just inserted its fingerprint, which export and restore keep
+fn fingerprint_entry(t: &mut mysql::Transaction) -> Result<(), mysql::Error> {
+    t.prep_exec(
+        "UPDATE transactions SET import_hash=? WHERE id=LAST_INSERT_ID()",
+        (new_fingerprint(),),
+    )?;
+
+    Ok(())
+}
+
+/// fingerprint of an exported ledger entry. Exports made before fingerprints were exported
+/// have the id, it's hashed with the content of the entry so that the same entry gets the same
+/// fingerprint on any instance while entries of other instances with the same id don't.
+fn restore_fingerprint(item: &Value) -> Result<String, E> {
+    if let Some(fingerprint) = item["fingerprint"].as_str() {
+        if fingerprint.len() == 40 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(fingerprint.to_lowercase());
+        }
+    }
+    let id = match item["id"].as_i64() {
+        Some(id) if id > 0 => id,
+        _ => return Err(E::ImportInvalid("transaction without fingerprint".into())),
+    };
+    let mut sh = Sha1::new();
+    sh.input_str(&format!(
+        "restore|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+        id,
+        item["coin_id"].as_str().unwrap_or(""),
+        item["kind"].as_str().unwrap_or(""),
+        item["amount"].as_f64().unwrap_or(0.0),
+        item["price_usd"].as_f64().unwrap_or(0.0),
+        item["fee_usd"].as_f64().unwrap_or(0.0),
+        item["wallet"].as_str().unwrap_or(""),
+        item["to_wallet"].as_str().unwrap_or(""),
+        item["created"].as_i64().unwrap_or(0),
+    ));
+
+    Ok(sh.result_str())
+}
+
+/// merge states left over from before the ledger of their coins into it, run at startup
+pub fn merge_leftover_states(mysql_pool: &Pool) -> Result<usize, E> {
+    let mut created_num = 0;
+    let mut owners = vec![];
+    for row in mysql_pool.prep_exec(
+        "SELECT DISTINCT s.user_id FROM states s WHERE s.migrated=0 AND EXISTS \
+         (SELECT 1 FROM transactions t WHERE t.user_id=s.user_id \
+         AND t.portfolio_id=s.portfolio_id AND t.coin_id=s.coin_id)",
+        (),
+    )? {
+        let owner_id: i64 = mysql::from_row(row?);
+        owners.push(owner_id);
+    }
+    for owner_id in owners {
+        mysql_pool
+            .start_transaction(false, None, None)
+            .and_then(|mut t| {
+                created_num += merge_states(&mut t, owner_id, None, None, true)?;
+                t.commit()
+            })?;
+    }
+
+    Ok(created_num)
+}
+
+/// sum up timelines of the same coin in different portfolios, states are order by created
+/// time asc. Amount of each state becomes the sum of the latest amounts of all portfolios.
+pub fn merge_portfolios(states: Vec<UserCoin>) -> Vec<UserCoin> {
+    // {(COIN, PORTFOLIO) => AMOUNT}
+    let mut amounts = BTreeMap::<(String, i64), f64>::new();
+    let mut merged = vec![];
+    for mut state in states.into_iter() {
+        amounts.insert((state.coin_id.clone(), state.portfolio_id), state.amount);
+        state.amount = amounts
+            .iter()
+            .filter(|&(key, _)| key.0 == state.coin_id)
+            .fold(0.0, |acc, (_, amount)| acc + amount);
+        merged.push(state);
+    }
+
+    merged
+}
+
+#[derive(Debug, Clone)]
 pub struct UserCoin<'a> {
     pub id: i64,
+    pub portfolio_id: i64,
     pub coin_id: String,
     pub amount: f64,
     pub created: i64,
     pub coin: Option<&'a worker::Coin>,
+    /// derived from transactions, `id` is the transaction id rather than states id
+    pub derived: bool,
+}
+
+/// Daily exchange rates of a currency between two timestamps, so historical values can be
+/// converted with the rate that was in effect at that time instead of today's.
+#[derive(Debug)]
+pub struct RateSeries {
+    pub currency: String,
+    /// used when there is no rate recorded at all, normally today's rate
+    pub fallback: f64,
+    /// {ASC DAY TIMESTAMP => RATE}
+    days: BTreeMap<i64, f64>,
+}
+
+impl RateSeries {
+    pub fn load(
+        mysql_pool: &Pool,
+        currency: &str,
+        origin_ts: i64,
+        end_ts: i64,
+        fallback: f64,
+    ) -> Result<Self, E> {
+        let mut series = RateSeries {
+            currency: currency.to_string(),
+            fallback: fallback,
+            days: BTreeMap::new(),
+        };
+        if currency == "USD" {
+            return Ok(series);
+        }
+
+        // including the last day before origin
+        for row in mysql_pool.prep_exec(
+            "SELECT day,rate FROM rates WHERE currency=? AND day<=? AND day>=\
+             (SELECT IFNULL(MAX(day),0) FROM rates WHERE currency=? AND day<=?) \
+             ORDER BY day ASC",
+            (currency, end_ts, currency, origin_ts),
+        )? {
+            let (day, rate): (i64, f64) = mysql::from_row(row?);
+            series.days.insert(day, rate);
+        }
+
+        Ok(series)
+    }
+
+    /// the rate in effect at `ts`, or the earliest one if `ts` is before all recorded days
+    pub fn at(&self, ts: i64) -> f64 {
+        match self.days.range(..ts + 1).next_back() {
+            Some((_, &rate)) => rate,
+            None => self.days.values().next().cloned().unwrap_or(self.fallback),
+        }
+    }
+}
+
+/// usd price of a coin recorded nearest to `ts` within a day, None if there is none
+pub fn coin_price_at(mysql_pool: &Pool, coin_id: &str, ts: i64) -> Result<Option<f64>, E> {
+    let row = mysql_pool
+        .prep_exec(
+            "SELECT price_usd FROM prices WHERE coin_id=? AND created BETWEEN ? AND ? \
+             ORDER BY ABS(created-?) ASC LIMIT 1",
+            (coin_id, ts - 86400, ts + 86400, ts),
+        )?
+        .next();
+    match row {
+        Some(row) => {
+            let price_usd: f64 = mysql::from_row(row?);
+            Ok(Some(price_usd))
+        }
+        None => Ok(None),
+    }
 }
 
 pub const POINTS_NUM: i64 = 100;
 
+/// coin price and amount history, the price is converted by the rate of each bucket
 pub fn coin_history(
     mysql_pool: &Pool,
     coin_id: &String,
     origin_ts: i64,
     end_ts: i64,
     states: &Vec<(i64, f64)>, // [(ASC TIMESTAMP, AMOUNT)]
+    rates: &RateSeries,
 ) -> Result<BTreeMap<i64, (f64, f64)>, E> {
     let mut bucket_size = (end_ts - origin_ts) / POINTS_NUM;
     bucket_size = if bucket_size > 0 { bucket_size } else { 1 };
@@ -527,8 +3085,181 @@ pub fn coin_history(
         if points.contains_key(&point_time) {
             pre_point = points[&point_time];
         }
-        full_points.insert(point_time, pre_point);
+        full_points.insert(point_time, (pre_point.0 * rates.at(point_time), pre_point.1));
     }
 
     Ok(full_points)
 }
+
+/// portfolio value history of states order by created time asc, values are in `currency`
+/// whose rate to usd today is `rate`. [(ASC TIMESTAMP, VALUE)]
+pub fn portfolio_history(
+    mysql_pool: &Pool,
+    user_states: &[UserCoin],
+    currency: &str,
+    rate: f64,
+) -> Result<Vec<(i64, f64)>, E> {
+    let end_ts = time::get_time().sec;
+    let mut origin_ts = 0i64;
+    // all states group by coin type and map to state points {COIN => [(ASC TIMESTAMP, AMOUNT)]}
+    let mut coin_to_states = HashMap::<String, Vec<(i64, f64)>>::new();
+    for state in user_states.iter() {
+        if origin_ts == 0i64 {
+            origin_ts = state.created;
+        }
+
+        if !coin_to_states.contains_key(&state.coin_id) {
+            coin_to_states.insert(state.coin_id.clone(), vec![]);
+        }
+        let vec = coin_to_states.get_mut(&state.coin_id).unwrap();
+        vec.push((state.created, state.amount));
+    }
+
+    let rates = RateSeries::load(mysql_pool, currency, origin_ts, end_ts, rate)?;
+    // {ASC TIMESTAMP => (TIMESTAMP, VALUE)}
+    let mut mix_points = BTreeMap::<i64, (i64, f64)>::new();
+    // each type of coin
+    for (coin_id, states) in coin_to_states {
+        // get the coin historical points among timestamp window
+        // {ASC TIMESTAMP => (PRICE, AMOUNT)}
+        let coin_points =
+            coin_history(mysql_pool, &coin_id, origin_ts, end_ts, &states, &rates)?;
+        for (ts, item) in coin_points {
+            let value = item.0 * item.1;
+            if !mix_points.contains_key(&ts) {
+                mix_points.insert(ts, (ts, value));
+            } else {
+                let exist_item = mix_points.get_mut(&ts).unwrap();
+                exist_item.1 = exist_item.1 + value;
+            }
+        }
+    }
+
+    let ret: Vec<(i64, f64)> = mix_points.values().cloned().collect();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use error::E;
+    use notify::Channel;
+    use super::{check_overdraw, ledger_low, new_fingerprint, restore_fingerprint, User};
+
+    fn user(channel: Channel) -> User {
+        User {
+            id: 1,
+            name: "jon".to_string(),
+            mobile: "+8613800000000".to_string(),
+            currency: User::DEFAULT_CURRENCY.to_string(),
+            email: "Jon@Example.com".to_string(),
+            webhook_url: "https://example.com/hook".to_string(),
+            notify_channel: channel,
+            digest: None,
+            timezone: User::DEFAULT_TIMEZONE.to_string(),
+            locale: User::LOCALES[0].to_string(),
+            created: 0,
+        }
+    }
+
+    fn channels(user: &User, verified_emails: &[String]) -> Vec<Channel> {
+        user.code_recipients(verified_emails)
+            .iter()
+            .map(|x| x.channel)
+            .collect()
+    }
+
+    #[test]
+    fn codes_go_to_verified_email_or_sms() {
+        let verified = vec!["jon@example.com".to_string()];
+        assert_eq!(
+            channels(&user(Channel::Email), &verified),
+            vec![Channel::Email, Channel::Sms]
+        );
+        // an unverified email still gets other notifications
+        assert_eq!(channels(&user(Channel::Email), &[]), vec![Channel::Sms]);
+        assert_eq!(user(Channel::Email).recipients()[0].channel, Channel::Email);
+        // never a webhook
+        assert_eq!(channels(&user(Channel::Webhook), &verified), vec![Channel::Sms]);
+    }
+
+    #[test]
+    fn restored_entries_keep_their_fingerprint() {
+        let fingerprint = new_fingerprint();
+        assert_eq!(fingerprint.len(), 40);
+        assert_ne!(fingerprint, new_fingerprint());
+        let item = json!({
+            "id": 7,
+            "coin_id": "bitcoin",
+            "kind": "buy",
+            "amount": 1.0,
+            "created": 100,
+            "fingerprint": fingerprint.to_uppercase(),
+        });
+        assert_eq!(restore_fingerprint(&item).unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn colliding_ids_of_other_instances_are_told_apart() {
+        // old exports without fingerprints, the same id on two instances
+        let ours = json!({"id": 7, "coin_id": "bitcoin", "kind": "buy", "amount": 1.0, "created": 100});
+        let theirs = json!({"id": 7, "coin_id": "ethereum", "kind": "buy", "amount": 2.0, "created": 200});
+        assert_ne!(restore_fingerprint(&ours).unwrap(), restore_fingerprint(&theirs).unwrap());
+        // restoring the same export again finds the same entries
+        assert_eq!(restore_fingerprint(&ours).unwrap(), restore_fingerprint(&ours.clone()).unwrap());
+        // identical entries are still different entries
+        let mut twin = ours.clone();
+        twin["id"] = json!(8);
+        assert_ne!(restore_fingerprint(&ours).unwrap(), restore_fingerprint(&twin).unwrap());
+    }
+
+    #[test]
+    fn entries_without_fingerprint_or_id_are_invalid() {
+        let item = json!({"coin_id": "bitcoin", "kind": "buy", "amount": 1.0, "created": 100});
+        match restore_fingerprint(&item) {
+            Err(E::ImportInvalid(_)) => (),
+            ret => panic!("unexpected {:?}", ret),
+        }
+        let item = json!({"id": 0, "fingerprint": "abc", "coin_id": "bitcoin"});
+        assert!(restore_fingerprint(&item).is_err());
+    }
+
+    #[test]
+    fn ledger_low_walks_entries_in_time() {
+        // given out of order, entries at the same second count together
+        let entries = vec![(300, -1.0), (100, 2.0), (200, -1.5), (200, 0.5)];
+        assert_eq!(ledger_low(&entries), 0.0);
+        assert_eq!(ledger_low(&[(100, 1.0), (200, -2.0), (300, 5.0)]), -1.0);
+    }
+
+    #[test]
+    fn back_dated_withdrawals_are_rejected() {
+        // deposit 2 at 100, withdraw 2 at 300
+        let mut entries = vec![(100, 2.0), (300, -2.0)];
+        let low = ledger_low(&entries);
+        // another withdrawal of 1 at 200 leaves 1 then, but the later one overdraws
+        entries.push((200, -1.0));
+        assert!(check_overdraw(low, ledger_low(&entries)).is_err());
+    }
+
+    #[test]
+    fn edited_inflows_are_rejected() {
+        let before = vec![(100, 2.0), (300, -2.0)];
+        let low = ledger_low(&before);
+        // the deposit edited down to 1
+        assert!(check_overdraw(low, ledger_low(&[(100, 1.0), (300, -2.0)])).is_err());
+        // the deposit edited into a withdrawal
+        assert!(check_overdraw(low, ledger_low(&[(100, -2.0), (300, -2.0)])).is_err());
+        // moved after the withdrawal
+        assert!(check_overdraw(low, ledger_low(&[(400, 2.0), (300, -2.0)])).is_err());
+        // still covering it
+        assert!(check_overdraw(low, ledger_low(&[(100, 3.0), (300, -2.0)])).is_ok());
+    }
+
+    #[test]
+    fn overdrawn_ledgers_can_be_fixed() {
+        // an imported history selling more than it bought
+        let low = ledger_low(&[(100, 1.0), (200, -3.0)]);
+        assert!(check_overdraw(low, ledger_low(&[(100, 2.0), (200, -3.0)])).is_ok());
+        assert!(check_overdraw(low, ledger_low(&[(100, 0.5), (200, -3.0)])).is_err());
+    }
+}
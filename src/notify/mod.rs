@@ -0,0 +1,310 @@
+//! Notifications to users. A notification is delivered through the channel the user prefers,
+//! falling back to the next recipient when the channel is not configured or sending fails.
+//! Notifiers are built from Rocket.toml, sms by `ali_sms_*`, email by `smtp_*`, and webhook
+//! is always available.
+
+use std::error::Error;
+use std::sync::mpsc::Receiver;
+use rocket::Config;
+use serde_json::Value;
+
+mod sms;
+mod smtp;
+mod webhook;
+
+pub use self::sms::AliSms;
+pub use self::smtp::Smtp;
+pub use self::webhook::Webhook;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Sms,
+    Email,
+    Webhook,
+}
+
+impl Channel {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sms" => Some(Channel::Sms),
+            "email" => Some(Channel::Email),
+            "webhook" => Some(Channel::Webhook),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Channel::Sms => "sms",
+            Channel::Email => "email",
+            Channel::Webhook => "webhook",
+        }
+    }
+}
+
+/// where to deliver, address is a mobile, an email address or a url depends on channel
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub channel: Channel,
+    pub address: String,
+}
+
+impl Recipient {
+    pub fn new(channel: Channel, address: &str) -> Self {
+        Recipient {
+            channel: channel,
+            address: address.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    /// login verification code
+    Code { code: String },
+    /// an alert rule fired
+    Alert { name: String, rule: String },
+    /// scheduled summary of holdings, `details` are lines of the full report
+    Digest {
+        name: String,
+        period: String,
+        value: String,
+        change: String,
+        details: Vec<String>,
+    },
+    /// link verifying an email login
+    Verify { link: String },
+}
+
+impl Message {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Message::Code { .. } => "code",
+            Message::Alert { .. } => "alert",
+            Message::Digest { .. } => "digest",
+            Message::Verify { .. } => "verify",
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        match *self {
+            Message::Code { .. } => "yield助手登录验证码".to_string(),
+            Message::Alert { ref rule, .. } => format!("yield助手提醒：{}", rule),
+            Message::Digest { ref period, .. } => format!("yield助手{}", period),
+            Message::Verify { .. } => "yield助手邮箱验证".to_string(),
+        }
+    }
+
+    /// plain text body for email
+    pub fn text(&self) -> String {
+        match *self {
+            Message::Code { ref code } => {
+                format!("您的验证码是{}，10分钟内有效，请勿告诉他人。", code)
+            }
+            Message::Alert { ref name, ref rule } => {
+                format!("{}您好，您设置的提醒已触发：{}。", name, rule)
+            }
+            Message::Digest {
+                ref name,
+                ref period,
+                ref details,
+                ..
+            } => format!("{}您好，以下是您的资产{}：\n\n{}\n", name, period, details.join("\n")),
+            Message::Verify { ref link } => {
+                format!("请在24小时内打开以下链接完成邮箱验证：\n\n{}\n", link)
+            }
+        }
+    }
+
+    /// params of sms template and payload of webhook
+    pub fn to_json(&self) -> Value {
+        match *self {
+            Message::Code { ref code } => json!({ "code": code }),
+            Message::Alert { ref name, ref rule } => json!({ "name": name, "rule": rule }),
+            Message::Digest {
+                ref name,
+                ref period,
+                ref value,
+                ref change,
+                ..
+            } => json!({
+                "name": name,
+                "period": period,
+                "value": value,
+                "change": change,
+            }),
+            Message::Verify { ref link } => json!({ "link": link }),
+        }
+    }
+}
+
+/// a message and its recipients in order of preference
+#[derive(Debug)]
+pub struct Notification {
+    pub recipients: Vec<Recipient>,
+    pub message: Message,
+}
+
+pub trait Notifier: Send {
+    fn channel(&self) -> Channel;
+
+    fn send(&self, address: &str, message: &Message) -> Result<(), Box<Error>>;
+}
+
+pub struct Dispatcher {
+    notifiers: Vec<Box<Notifier>>,
+}
+
+impl Dispatcher {
+    pub fn new(notifiers: Vec<Box<Notifier>>) -> Self {
+        Dispatcher {
+            notifiers: notifiers,
+        }
+    }
+
+    /// build the notifiers configured in Rocket.toml
+    pub fn from_config(config: &Config) -> Self {
+        let mut notifiers: Vec<Box<Notifier>> = vec![];
+        if let Ok(key_id) = config.get_str("ali_sms_key_id") {
+            notifiers.push(Box::new(AliSms::new(
+                key_id,
+                config.get_str("ali_sms_key_secret").unwrap_or(""),
+                config.get_str("ali_sms_sign_name").unwrap_or(AliSms::SIGN_NAME),
+                config
+                    .get_str("ali_sms_code_template")
+                    .unwrap_or(AliSms::CODE_TEMPLATE),
+                config.get_str("ali_sms_intl_code_template").ok(),
+                config.get_str("ali_sms_alert_template").ok(),
+                config.get_str("ali_sms_digest_template").ok(),
+            )));
+        }
+        if let Ok(host) = config.get_str("smtp_host") {
+            notifiers.push(Box::new(Smtp::new(
+                host,
+                config.get_int("smtp_port").unwrap_or(25) as u16,
+                config.get_str("smtp_from").unwrap_or("noreply@yield.watch"),
+                config.get_str("smtp_username").ok(),
+                config.get_str("smtp_password").ok(),
+            )));
+        }
+        notifiers.push(Box::new(Webhook::new(
+            config.get_int("webhook_timeout").unwrap_or(10) as u64,
+            config.get_bool("webhook_allow_private_hosts").unwrap_or(false),
+        )));
+
+        Dispatcher::new(notifiers)
+    }
+
+    /// try recipients one by one until the message is sent
+    pub fn deliver(&self, notification: &Notification) -> Result<Channel, Box<Error>> {
+        let mut last_err: Box<Error> = From::from("no recipient");
+        for recipient in notification.recipients.iter() {
+            let notifier = match self.notifiers
+                .iter()
+                .find(|x| x.channel() == recipient.channel)
+            {
+                Some(notifier) => notifier,
+                None => {
+                    last_err = From::from(format!("{} is not configured", recipient.channel.as_str()));
+                    continue;
+                }
+            };
+            match notifier.send(&recipient.address, &notification.message) {
+                Ok(_) => return Ok(recipient.channel),
+                Err(e) => {
+                    warn!(
+                        "Error while sending {} by {}: {}",
+                        notification.message.kind(),
+                        recipient.channel.as_str(),
+                        e
+                    );
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// deliver notifications from the channel until all senders are dropped
+    pub fn run(&self, rx: Receiver<Notification>) {
+        for notification in rx.iter() {
+            if let Err(e) = self.deliver(&notification) {
+                error!("Notification {} dropped: {}", notification.message.kind(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::base64::{ToBase64, MIME};
+    use stub;
+    use super::{Channel, Dispatcher, Message, Notification, Notifier, Recipient, Smtp, Webhook};
+
+    fn code() -> Message {
+        Message::Code {
+            code: "4821".to_string(),
+        }
+    }
+
+    #[test]
+    fn code_goes_to_email_through_smtp() {
+        let (port, messages) = stub::smtp();
+        let mut notifiers: Vec<Box<Notifier>> = vec![];
+        notifiers.push(Box::new(Smtp::new("127.0.0.1", port, "noreply@yield.watch", None, None)));
+        let dispatcher = Dispatcher::new(notifiers);
+
+        let channel = dispatcher
+            .deliver(&Notification {
+                recipients: vec![
+                    Recipient::new(Channel::Email, "jon@example.com"),
+                    Recipient::new(Channel::Sms, "+8613800000000"),
+                ],
+                message: code(),
+            })
+            .unwrap();
+        assert_eq!(channel, Channel::Email);
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "jon@example.com");
+        assert!(messages[0].1.contains("To: jon@example.com\r\n"));
+        assert!(messages[0].1.contains(&code().text().as_bytes().to_base64(MIME)));
+    }
+
+    #[test]
+    fn code_is_never_posted_to_webhooks() {
+        let (url, requests) = stub::http(|_| (200, "{}".to_string()));
+        let mut notifiers: Vec<Box<Notifier>> = vec![];
+        notifiers.push(Box::new(Webhook::new(5, true)));
+        let dispatcher = Dispatcher::new(notifiers);
+
+        assert!(
+            dispatcher
+                .deliver(&Notification {
+                    recipients: vec![Recipient::new(Channel::Webhook, &url)],
+                    message: code(),
+                })
+                .is_err()
+        );
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn webhook_refuses_private_hosts() {
+        let (url, requests) = stub::http(|_| (200, "{}".to_string()));
+        let alert = Message::Alert {
+            name: "jon".to_string(),
+            rule: "BTC价格高于10000.00USD".to_string(),
+        };
+
+        assert!(Webhook::new(5, false).send(&url, &alert).is_err());
+        assert!(requests.lock().unwrap().is_empty());
+
+        Webhook::new(5, true).send(&url, &alert).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].0.starts_with("POST / "));
+        assert!(requests[0].1.contains("\"kind\":\"alert\""));
+    }
+}
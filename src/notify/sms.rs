@@ -0,0 +1,85 @@
+use std::error::Error;
+use alisms::{self, SmsBody};
+use super::{Channel, Message, Notifier};
+
+/// Aliyun sms, every kind of message needs its own template. Numbers out of mainland china
+/// need international templates, only login codes are sent to them.
+pub struct AliSms {
+    key_id: String,
+    key_secret: String,
+    sign_name: String,
+    code_template: String,
+    intl_code_template: Option<String>,
+    /// with ${name} and ${rule} params
+    alert_template: Option<String>,
+    /// with ${name}, ${period}, ${value} and ${change} params
+    digest_template: Option<String>,
+}
+
+impl AliSms {
+    pub const SIGN_NAME: &'static str = "yield助手";
+    pub const CODE_TEMPLATE: &'static str = "SMS_123673246";
+
+    pub fn new(
+        key_id: &str,
+        key_secret: &str,
+        sign_name: &str,
+        code_template: &str,
+        intl_code_template: Option<&str>,
+        alert_template: Option<&str>,
+        digest_template: Option<&str>,
+    ) -> Self {
+        AliSms {
+            key_id: key_id.to_string(),
+            key_secret: key_secret.to_string(),
+            sign_name: sign_name.to_string(),
+            code_template: code_template.to_string(),
+            intl_code_template: intl_code_template.map(|x| x.to_string()),
+            alert_template: alert_template.map(|x| x.to_string()),
+            digest_template: digest_template.map(|x| x.to_string()),
+        }
+    }
+
+    fn template(&self, address: &str, message: &Message) -> Option<&str> {
+        if !address.starts_with("+86") {
+            return match *message {
+                Message::Code { .. } => self.intl_code_template.as_ref().map(|x| x.as_str()),
+                _ => None,
+            };
+        }
+        match *message {
+            Message::Code { .. } => Some(&self.code_template),
+            Message::Alert { .. } => self.alert_template.as_ref().map(|x| x.as_str()),
+            Message::Digest { .. } => self.digest_template.as_ref().map(|x| x.as_str()),
+            // emails are verified by email only
+            Message::Verify { .. } => None,
+        }
+    }
+}
+
+impl Notifier for AliSms {
+    fn channel(&self) -> Channel {
+        Channel::Sms
+    }
+
+    fn send(&self, address: &str, message: &Message) -> Result<(), Box<Error>> {
+        let template_code = match self.template(address, message) {
+            Some(x) => x.to_string(),
+            None => return Err(From::from(format!("no sms template for {}", message.kind()))),
+        };
+        alisms::sms_api(SmsBody {
+            key_id: self.key_id.clone(),
+            key_secret: self.key_secret.clone(),
+            sign_name: self.sign_name.clone(),
+            template_code: template_code,
+            // mainland numbers go without country code, others with it but no plus sign
+            phone_numbers: if address.starts_with("+86") {
+                address[3..].to_string()
+            } else {
+                address.trim_left_matches('+').to_string()
+            },
+            template_param: message.to_json().to_string(),
+            out_id: "".to_string(),
+        })
+    }
+}
@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use rustc_serialize::base64::{ToBase64, MIME, STANDARD};
+use time;
+use super::{Channel, Message, Notifier};
+
+/// plain smtp client, enough for a relay on the same network or a local sink in development.
+/// AUTH LOGIN is used when username is given.
+pub struct Smtp {
+    host: String,
+    port: u16,
+    from: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl Smtp {
+    pub fn new(
+        host: &str,
+        port: u16,
+        from: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Self {
+        Smtp {
+            host: host.to_string(),
+            port: port,
+            from: from.to_string(),
+            username: username.map(|x| x.to_string()),
+            password: password.map(|x| x.to_string()),
+        }
+    }
+
+    /// rfc 5322 message with utf-8 subject and base64 body
+    fn compose(&self, to: &str, message: &Message) -> String {
+        let date = time::strftime("%a, %d %b %Y %H:%M:%S +0000", &time::now_utc()).unwrap();
+        format!(
+            "From: {from}\r\n\
+             To: {to}\r\n\
+             Subject: =?UTF-8?B?{subject}?=\r\n\
+             Date: {date}\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=UTF-8\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {body}\r\n",
+            from = self.from,
+            to = to,
+            subject = message.subject().as_bytes().to_base64(STANDARD),
+            date = date,
+            body = message.text().as_bytes().to_base64(MIME),
+        )
+    }
+}
+
+/// read a possibly multiline reply and make sure its code is expected
+fn expect(reader: &mut BufRead, code: &str) -> Result<(), Box<Error>> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(From::from("smtp connection closed"));
+        }
+        if !line.starts_with(code) {
+            return Err(From::from(format!("smtp error: {}", line.trim_right())));
+        }
+        // "250-..." is followed by more lines, "250 ..." is the last one
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+fn command(
+    stream: &mut TcpStream,
+    reader: &mut BufRead,
+    line: &str,
+    code: &str,
+) -> Result<(), Box<Error>> {
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\r\n")?;
+    expect(reader, code)
+}
+
+impl Notifier for Smtp {
+    fn channel(&self) -> Channel {
+        Channel::Email
+    }
+
+    fn send(&self, address: &str, message: &Message) -> Result<(), Box<Error>> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(30)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        expect(&mut reader, "220")?;
+        command(&mut stream, &mut reader, "EHLO yield.watch", "250")?;
+        if let (&Some(ref username), &Some(ref password)) = (&self.username, &self.password) {
+            command(&mut stream, &mut reader, "AUTH LOGIN", "334")?;
+            command(&mut stream, &mut reader, &username.as_bytes().to_base64(STANDARD), "334")?;
+            command(&mut stream, &mut reader, &password.as_bytes().to_base64(STANDARD), "235")?;
+        }
+        command(&mut stream, &mut reader, &format!("MAIL FROM:<{}>", self.from), "250")?;
+        command(&mut stream, &mut reader, &format!("RCPT TO:<{}>", address), "25")?;
+        command(&mut stream, &mut reader, "DATA", "354")?;
+        // base64 lines never start with a dot, so the data needs no dot stuffing
+        stream.write_all(self.compose(address, message).as_bytes())?;
+        command(&mut stream, &mut reader, ".", "250")?;
+        command(&mut stream, &mut reader, "QUIT", "221")
+    }
+}
@@ -0,0 +1,52 @@
+use std::error::Error;
+use time;
+use utils;
+use super::{Channel, Message, Notifier};
+
+/// post the message as json to the url the user configured, which must resolve to public
+/// addresses unless `allow_private_hosts`
+pub struct Webhook {
+    timeout: u64,
+    allow_private_hosts: bool,
+}
+
+impl Webhook {
+    pub fn new(timeout: u64, allow_private_hosts: bool) -> Self {
+        Webhook {
+            timeout: timeout,
+            allow_private_hosts: allow_private_hosts,
+        }
+    }
+}
+
+impl Notifier for Webhook {
+    fn channel(&self) -> Channel {
+        Channel::Webhook
+    }
+
+    fn send(&self, address: &str, message: &Message) -> Result<(), Box<Error>> {
+        // login codes are never posted to webhooks
+        if let Message::Code { .. } = *message {
+            return Err(From::from("codes are not sent by webhook"));
+        }
+        let payload = json!({
+            "kind": message.kind(),
+            "subject": message.subject(),
+            "text": message.text(),
+            "data": message.to_json(),
+            "created": time::get_time().sec,
+        });
+        let (status, _) = utils::post_json(
+            address,
+            &payload,
+            &[],
+            Some(self.timeout),
+            !self.allow_private_hosts,
+        )?;
+        if status < 200 || status >= 300 {
+            return Err(From::from(format!("webhook responded {}", status)));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+use error::E;
+
+/// a country where sms login is available
+#[derive(Debug)]
+pub struct Country {
+    /// calling code
+    pub code: u16,
+    pub iso: &'static str,
+    /// digits of national mobile numbers
+    min_len: usize,
+    max_len: usize,
+    /// leading digits of mobile numbers, empty for any
+    prefixes: &'static [&'static str],
+    /// leading digits of numbers which are rejected even if a prefix matches
+    excluded: &'static [&'static str],
+    /// codes sent to a number in 24 hours
+    pub daily_limit: i64,
+    /// codes sent to all numbers of the country in an hour, 0 for no limit
+    pub hourly_limit: i64,
+}
+
+/// area codes of the North American Numbering Plan which are not US mobile numbers. The
+/// Caribbean ones are billed as international and a favorite of sms pumping.
+const NANP_EXCLUDED: &'static [&'static str] = &[
+    // Canada
+    "204", "226", "236", "249", "250", "257", "263", "289", "306", "343", "354", "365", "367",
+    "368", "382", "387", "403", "416", "418", "428", "431", "437", "438", "450", "460", "468",
+    "474", "506", "514", "519", "548", "579", "581", "584", "587", "600", "604", "613", "622",
+    "639", "647", "672", "683", "705", "709", "742", "753", "778", "780", "782", "807", "819",
+    "825", "867", "873", "879", "902", "905", "942",
+    // Caribbean and Bermuda
+    "242", "246", "264", "268", "284", "345", "441", "473", "649", "658", "664", "721", "758",
+    "767", "784", "809", "829", "849", "868", "869", "876",
+    // premium, toll free and other non-geographic services
+    "500", "521", "522", "523", "524", "525", "526", "527", "528", "529", "533", "544", "566",
+    "577", "588", "700", "710", "800", "822", "833", "844", "855", "866", "877", "880", "881",
+    "882", "883", "884", "885", "886", "887", "888", "889", "900",
+    // N11 service codes
+    "211", "311", "411", "511", "611", "711", "811", "911",
+];
+
+/// countries are opened one by one, numbers of others are rejected so that nobody can pump
+/// sms to premium numbers through us
+const COUNTRIES: &'static [Country] = &[
+    Country {
+        code: 86,
+        iso: "CN",
+        min_len: 11,
+        max_len: 11,
+        prefixes: &["1"],
+        excluded: &[],
+        daily_limit: 10,
+        hourly_limit: 0,
+    },
+    Country {
+        code: 852,
+        iso: "HK",
+        min_len: 8,
+        max_len: 8,
+        prefixes: &["4", "5", "6", "7", "9"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 200,
+    },
+    Country {
+        code: 853,
+        iso: "MO",
+        min_len: 8,
+        max_len: 8,
+        prefixes: &["6"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 100,
+    },
+    Country {
+        code: 886,
+        iso: "TW",
+        min_len: 9,
+        max_len: 9,
+        prefixes: &["9"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 200,
+    },
+    Country {
+        code: 65,
+        iso: "SG",
+        min_len: 8,
+        max_len: 8,
+        prefixes: &["8", "9"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 100,
+    },
+    Country {
+        code: 1,
+        iso: "US",
+        min_len: 10,
+        max_len: 10,
+        // area codes start with 2-9
+        prefixes: &["2", "3", "4", "5", "6", "7", "8", "9"],
+        excluded: NANP_EXCLUDED,
+        daily_limit: 5,
+        hourly_limit: 200,
+    },
+    Country {
+        code: 44,
+        iso: "GB",
+        min_len: 10,
+        max_len: 10,
+        prefixes: &["7"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 100,
+    },
+    Country {
+        code: 81,
+        iso: "JP",
+        min_len: 10,
+        max_len: 10,
+        prefixes: &["70", "80", "90"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 100,
+    },
+    Country {
+        code: 82,
+        iso: "KR",
+        min_len: 9,
+        max_len: 10,
+        prefixes: &["1"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 100,
+    },
+    Country {
+        code: 60,
+        iso: "MY",
+        min_len: 9,
+        max_len: 10,
+        prefixes: &["1"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 100,
+    },
+    Country {
+        code: 61,
+        iso: "AU",
+        min_len: 9,
+        max_len: 9,
+        prefixes: &["4"],
+        excluded: &[],
+        daily_limit: 5,
+        hourly_limit: 100,
+    },
+];
+
+impl Country {
+    pub fn find(code: u16) -> Option<&'static Country> {
+        COUNTRIES.iter().find(|x| x.code == code)
+    }
+}
+
+/// mobile number of a supported country
+#[derive(Debug, Clone)]
+pub struct Phone {
+    pub country: &'static Country,
+    /// without trunk prefix
+    pub national: String,
+}
+
+impl Phone {
+    /// parse "+44 7700 900123", "0044 7700 900123", or a national number of the default
+    /// country like "07700 900123"
+    pub fn parse(input: &str, default_country: u16) -> Result<Self, E> {
+        let input: String = input
+            .chars()
+            .filter(|&c| !(c.is_whitespace() || c == '-' || c == '(' || c == ')'))
+            .collect();
+        let (international, digits) = if input.starts_with('+') {
+            (true, &input[1..])
+        } else if input.starts_with("00") {
+            (true, &input[2..])
+        } else {
+            (false, &input[..])
+        };
+        // E.164 has 15 digits at most
+        if digits.is_empty() || digits.len() > 15 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(E::SmsMobileInvalid);
+        }
+
+        let (country, national) = if international {
+            // calling codes are prefix-free
+            let country = (1..4)
+                .filter_map(|n| digits.get(..n))
+                .filter_map(|code| code.parse::<u16>().ok())
+                .filter_map(Country::find)
+                .next()
+                .ok_or(E::SmsCountryNotSupported)?;
+            (country, &digits[country.code.to_string().len()..])
+        } else {
+            let country = Country::find(default_country).ok_or(E::SmsCountryNotSupported)?;
+            (country, digits.trim_left_matches('0'))
+        };
+        if national.len() < country.min_len || national.len() > country.max_len
+            || !(country.prefixes.is_empty()
+                || country.prefixes.iter().any(|x| national.starts_with(x)))
+            || country.excluded.iter().any(|x| national.starts_with(x))
+        {
+            return Err(E::SmsMobileInvalid);
+        }
+        // the exchange after a NANP area code is 2-9XX too, 976 ones are premium and 555 ones
+        // are fictional or directory services
+        if country.code == 1 {
+            let exchange = &national[3..6];
+            if exchange < "200" || &exchange[1..] == "11" || exchange == "555"
+                || exchange == "976"
+            {
+                return Err(E::SmsMobileInvalid);
+            }
+        }
+
+        Ok(Phone {
+            country: country,
+            national: national.to_string(),
+        })
+    }
+
+    /// "+8613800000000", the form stored in `users.mobile` and `sms.mobile`
+    pub fn e164(&self) -> String {
+        format!("+{}{}", self.country.code, self.national)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Phone;
+
+    #[test]
+    fn parse_international_and_national_numbers() {
+        let phone = Phone::parse("+44 7700 900123", 86).unwrap();
+        assert_eq!(phone.country.iso, "GB");
+        assert_eq!(phone.e164(), "+447700900123");
+        assert_eq!(Phone::parse("0044 7700 900123", 86).unwrap().e164(), "+447700900123");
+        assert_eq!(Phone::parse("138-0000-0000", 86).unwrap().e164(), "+8613800000000");
+        assert!(Phone::parse("+44 20 7946 0000", 86).is_err());
+        assert!(Phone::parse("+7 912 345 6789", 86).is_err());
+    }
+
+    #[test]
+    fn us_accepts_only_us_numbers() {
+        let phone = Phone::parse("+1 (212) 245-6789", 86).unwrap();
+        assert_eq!(phone.country.iso, "US");
+        assert_eq!(phone.e164(), "+12122456789");
+        assert!(Phone::parse("+1 787 245 6789", 86).is_ok());
+        for number in [
+            // Caribbean, Canada and Bermuda
+            "+1 876 245 6789",
+            "+1 809 245 6789",
+            "+1 268 245 6789",
+            "+1 416 245 6789",
+            "+1 441 245 6789",
+            // premium and toll free
+            "+1 900 245 6789",
+            "+1 212 976 6789",
+            "+1 800 245 6789",
+            // not a number
+            "+1 212 555 0100",
+            "+1 212 045 6789",
+            "+1 411 245 6789",
+            "+1 112 245 6789",
+        ].iter()
+        {
+            assert!(Phone::parse(number, 86).is_err(), "{}", number);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Cost basis and profit of holdings. Lots are matched first in, first out: a sell consumes
+//! the oldest bought lots, the lots left open make up the cost basis of current holdings.
+//! Coin received without a price (price_usd 0) makes a lot of unknown cost, which is kept out
+//! of the cost basis and profit rather than counted as free.
+
+use std::collections::VecDeque;
+use models::{Transaction, TxKind};
+
+#[derive(Debug, Default)]
+pub struct Pnl {
+    /// current amount of holdings
+    pub amount: f64,
+    /// average unit cost of open lots
+    pub avg_cost_usd: f64,
+    /// cost of open lots (FIFO)
+    pub cost_basis_usd: f64,
+    /// profit of sold lots (FIFO)
+    pub realized_usd: f64,
+    /// profit of open lots at current price
+    pub unrealized_usd: f64,
+    /// current value of holdings
+    pub value_usd: f64,
+    /// holdings not covered by any priced lot, their cost is unknown and excluded from profit
+    pub uncovered_amount: f64,
+    /// amount sold beyond the open lots or out of unpriced lots, its cost is unknown so its
+    /// proceeds are not realized
+    pub unmatched_sold_amount: f64,
+}
+
+impl Pnl {
+    /// add up another coin's numbers, used for the whole portfolio
+    pub fn merge(&mut self, other: &Pnl) {
+        self.cost_basis_usd += other.cost_basis_usd;
+        self.realized_usd += other.realized_usd;
+        self.unrealized_usd += other.unrealized_usd;
+        self.value_usd += other.value_usd;
+    }
+}
+
+/// profit of a coin from its transactions order by created time asc, `amount` is the
+/// current holdings (from states), `price_usd` is the current price
+pub fn coin_pnl(txs: &[Transaction], amount: Option<f64>, price_usd: f64) -> Pnl {
+    // [(AMOUNT, UNIT COST)], the cost is None if unknown
+    let mut lots = VecDeque::<(f64, Option<f64>)>::new();
+    let mut realized = 0.0;
+    let mut unmatched_sold = 0.0;
+
+    for tx in txs.iter() {
+        match tx.kind {
+            // income is valued at the price when received
+            TxKind::Buy | TxKind::Deposit | TxKind::Airdrop | TxKind::Staking | TxKind::Opening => {
+                let unit_cost = if tx.price_usd > 0.0 {
+                    Some((tx.amount * tx.price_usd + tx.fee_usd) / tx.amount)
+                } else {
+                    None
+                };
+                lots.push_back((tx.amount, unit_cost));
+            }
+            TxKind::Sell => {
+                let (matched, cost) = consume(&mut lots, tx.amount);
+                // only the part sold out of priced lots makes a profit, the fee is shared
+                let proceeds = (tx.amount * tx.price_usd - tx.fee_usd) * matched / tx.amount;
+                realized += proceeds - cost;
+                unmatched_sold += tx.amount - matched;
+            }
+            // coin gone without proceeds, its cost is dropped along with it
+            TxKind::Withdrawal | TxKind::Fee => {
+                consume(&mut lots, tx.amount);
+            }
+            TxKind::Transfer => (),
+        }
+    }
+
+    let lots_amount = lots.iter().fold(0.0, |acc, lot| acc + lot.0);
+    let amount = amount.unwrap_or(lots_amount);
+    // holdings may be less than the open lots if some were moved out without a sell record,
+    // then keep the newest lots as the oldest are the first out
+    let mut covered = 0.0;
+    let mut cost_basis = 0.0;
+    let mut taken = 0.0;
+    for lot in lots.iter().rev() {
+        if taken >= amount {
+            break;
+        }
+        let used = (amount - taken).min(lot.0);
+        taken += used;
+        if let Some(unit_cost) = lot.1 {
+            covered += used;
+            cost_basis += used * unit_cost;
+        }
+    }
+
+    Pnl {
+        amount: amount,
+        avg_cost_usd: if covered > 0.0 {
+            cost_basis / covered
+        } else {
+            0.0
+        },
+        cost_basis_usd: cost_basis,
+        realized_usd: realized,
+        unrealized_usd: covered * price_usd - cost_basis,
+        value_usd: amount * price_usd,
+        uncovered_amount: amount - covered,
+        unmatched_sold_amount: unmatched_sold,
+    }
+}
+
+/// take `amount` out of the oldest lots, return the amount priced lots covered and its cost
+fn consume(lots: &mut VecDeque<(f64, Option<f64>)>, amount: f64) -> (f64, f64) {
+    let mut remain = amount;
+    let mut matched = 0.0;
+    let mut cost = 0.0;
+    while remain > 0.0 {
+        let lot = match lots.front_mut() {
+            Some(lot) => lot,
+            // took more than received, the rest has no known cost
+            None => break,
+        };
+        let used = remain.min(lot.0);
+        if let Some(unit_cost) = lot.1 {
+            matched += used;
+            cost += used * unit_cost;
+        }
+        lot.0 -= used;
+        remain -= used;
+        if lot.0 <= 0.0 {
+            lots.pop_front();
+        }
+    }
+
+    (matched, cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use models::{Transaction, TxKind};
+    use super::coin_pnl;
+
+    fn tx(kind: TxKind, amount: f64, price_usd: f64, fee_usd: f64) -> Transaction {
+        Transaction {
+            id: 0,
+            portfolio_id: 0,
+            coin_id: "bitcoin".to_string(),
+            kind: kind,
+            amount: amount,
+            price_usd: price_usd,
+            fee_usd: fee_usd,
+            wallet: String::new(),
+            to_wallet: String::new(),
+            created: 0,
+        }
+    }
+
+    #[test]
+    fn sell_matches_oldest_lots_first() {
+        let txs = vec![
+            tx(TxKind::Buy, 1.0, 100.0, 0.0),
+            tx(TxKind::Buy, 1.0, 200.0, 0.0),
+            tx(TxKind::Sell, 1.0, 300.0, 0.0),
+        ];
+        let pnl = coin_pnl(&txs, None, 400.0);
+
+        assert_eq!(pnl.amount, 1.0);
+        assert_eq!(pnl.realized_usd, 200.0);
+        assert_eq!(pnl.cost_basis_usd, 200.0);
+        assert_eq!(pnl.avg_cost_usd, 200.0);
+        assert_eq!(pnl.unrealized_usd, 200.0);
+        assert_eq!(pnl.value_usd, 400.0);
+        assert_eq!(pnl.uncovered_amount, 0.0);
+        assert_eq!(pnl.unmatched_sold_amount, 0.0);
+    }
+
+    #[test]
+    fn sell_splits_partial_lots() {
+        let txs = vec![
+            tx(TxKind::Buy, 2.0, 100.0, 10.0),
+            tx(TxKind::Buy, 2.0, 200.0, 0.0),
+            tx(TxKind::Sell, 3.0, 300.0, 30.0),
+        ];
+        let pnl = coin_pnl(&txs, None, 300.0);
+
+        // sold 2 of the first lot at 105 and 1 of the second at 200
+        assert_eq!(pnl.realized_usd, 870.0 - 410.0);
+        // 1 of the second lot is left
+        assert_eq!(pnl.amount, 1.0);
+        assert_eq!(pnl.cost_basis_usd, 200.0);
+        assert_eq!(pnl.unrealized_usd, 100.0);
+        assert_eq!(pnl.unmatched_sold_amount, 0.0);
+    }
+
+    #[test]
+    fn oversell_realizes_only_the_covered_amount() {
+        let txs = vec![
+            tx(TxKind::Buy, 1.0, 100.0, 0.0),
+            tx(TxKind::Sell, 4.0, 300.0, 40.0),
+        ];
+        let pnl = coin_pnl(&txs, None, 300.0);
+
+        // 1 covered by the lot, proceeds (1200 - 40) / 4 less its cost 100
+        assert_eq!(pnl.realized_usd, 190.0);
+        assert_eq!(pnl.unmatched_sold_amount, 3.0);
+        assert_eq!(pnl.amount, 0.0);
+        assert_eq!(pnl.cost_basis_usd, 0.0);
+
+        // later buys are not eaten by the earlier oversell
+        let mut txs = txs;
+        txs.push(tx(TxKind::Buy, 1.0, 200.0, 0.0));
+        let pnl = coin_pnl(&txs, None, 300.0);
+        assert_eq!(pnl.amount, 1.0);
+        assert_eq!(pnl.cost_basis_usd, 200.0);
+    }
+
+    #[test]
+    fn unpriced_inflows_have_unknown_cost() {
+        let txs = vec![
+            tx(TxKind::Opening, 2.0, 0.0, 0.0),
+            tx(TxKind::Buy, 1.0, 100.0, 0.0),
+            tx(TxKind::Sell, 1.0, 300.0, 0.0),
+        ];
+        let pnl = coin_pnl(&txs, None, 200.0);
+
+        // the sell consumed half of the unpriced opening balance, nothing is realized
+        assert_eq!(pnl.realized_usd, 0.0);
+        assert_eq!(pnl.unmatched_sold_amount, 1.0);
+        assert_eq!(pnl.amount, 2.0);
+        assert_eq!(pnl.cost_basis_usd, 100.0);
+        assert_eq!(pnl.avg_cost_usd, 100.0);
+        assert_eq!(pnl.unrealized_usd, 100.0);
+        assert_eq!(pnl.uncovered_amount, 1.0);
+        assert_eq!(pnl.value_usd, 400.0);
+    }
+
+    #[test]
+    fn holdings_beyond_lots_are_uncovered() {
+        let txs = vec![tx(TxKind::Buy, 1.0, 100.0, 0.0)];
+        let pnl = coin_pnl(&txs, Some(3.0), 150.0);
+
+        assert_eq!(pnl.cost_basis_usd, 100.0);
+        assert_eq!(pnl.uncovered_amount, 2.0);
+        assert_eq!(pnl.unrealized_usd, 50.0);
+        assert_eq!(pnl.value_usd, 450.0);
+    }
+}
@@ -0,0 +1,209 @@
+use std::error::Error;
+use serde_json::Value;
+use time;
+
+use provider::{MarketDataProvider, PricePoint};
+use utils;
+use worker::Coin;
+
+/// coingecko v3 `/coins/markets` and `/coins/{id}/market_chart/range` api. The base url is
+/// configurable, so it can be pointed to a stub server which replays recorded responses.
+pub struct CoinGecko {
+    pub base_url: String,
+    /// pages of `/coins/markets` fetched on every refresh, the long tail is never priced
+    pub max_pages: usize,
+}
+
+impl CoinGecko {
+    pub const BASE_URL: &'static str = "https://api.coingecko.com/api/v3";
+    const PER_PAGE: usize = 250;
+    /// top 1000 coins by market cap
+    pub const MAX_PAGES: usize = 4;
+
+    pub fn new(base_url: &str, max_pages: usize) -> Self {
+        CoinGecko {
+            base_url: base_url.trim_right_matches('/').to_string(),
+            max_pages: max_pages,
+        }
+    }
+
+    /// parse one item of `/coins/markets` array
+    pub fn coin_from_json(j: &Value) -> Coin {
+        Coin {
+            no: 0,
+            name: j["name"].as_str().unwrap_or("").into(),
+            symbol: j["symbol"].as_str().unwrap_or("").to_uppercase(),
+            id: j["id"].as_str().unwrap_or("").into(),
+            rank: j["market_cap_rank"].as_i64().unwrap_or(0),
+            available_supply: j["circulating_supply"].as_f64().unwrap_or(0.),
+            total_supply: j["total_supply"].as_f64().unwrap_or(0.),
+            max_supply: j["max_supply"].as_f64().unwrap_or(0.),
+            price_btc: 0.,
+            price_usd: j["current_price"].as_f64().unwrap_or(0.),
+            volume_usd: j["total_volume"].as_f64().unwrap_or(0.),
+            market_cap_usd: j["market_cap"].as_f64().unwrap_or(0.),
+            percent_change_1h: j["price_change_percentage_1h_in_currency"]
+                .as_f64()
+                .unwrap_or(0.),
+            percent_change_24h: j["price_change_percentage_24h"].as_f64().unwrap_or(0.),
+            percent_change_7d: j["price_change_percentage_7d_in_currency"]
+                .as_f64()
+                .unwrap_or(0.),
+            // markets are requested in usd only
+            price_cny: 0.,
+            volume_cny: 0.,
+            market_cap_cny: 0.,
+            last_updated: j["last_updated"]
+                .as_str()
+                .and_then(|s| time::strptime(s.get(..19)?, "%Y-%m-%dT%H:%M:%S").ok())
+                .map(|tm| tm.to_timespec().sec)
+                .unwrap_or(0),
+        }
+    }
+
+    /// parse a `market_chart/range` response, which is a bunch of [MS TIMESTAMP, VALUE] arrays
+    pub fn points_from_json(json: &Value) -> Vec<PricePoint> {
+        let mut points = vec![];
+        let prices = match json["prices"].as_array() {
+            Some(prices) => prices,
+            None => return points,
+        };
+
+        for (idx, price) in prices.iter().enumerate() {
+            let p_usd = price[1].as_f64().unwrap_or(0.0);
+            if p_usd == 0f64 {
+                continue;
+            }
+            points.push(PricePoint {
+                created: (price[0].as_f64().unwrap_or(0.0) / 1000.0) as i64,
+                price_usd: p_usd,
+                volume_usd: json["total_volumes"][idx][1].as_f64().unwrap_or(0.0),
+                price_btc: 0.0,
+                price_platform: None,
+            });
+        }
+
+        points
+    }
+}
+
+impl MarketDataProvider for CoinGecko {
+    fn tickers(&self) -> Result<Vec<Coin>, Box<Error>> {
+        let mut page = 1;
+        let mut coins = vec![];
+        loop {
+            let url = format!(
+                "{}/coins/markets?vs_currency=usd&order=market_cap_desc&per_page={}&page={}&price_change_percentage=1h,24h,7d",
+                self.base_url,
+                Self::PER_PAGE,
+                page
+            );
+            let ret = utils::request_json(&url, None)?;
+            let rows = match ret.as_array() {
+                Some(rows) => rows,
+                None => return Err(From::from("unexpected coins/markets response")),
+            };
+            for row in rows.iter() {
+                coins.push(Self::coin_from_json(row));
+            }
+            if rows.len() < Self::PER_PAGE || page >= self.max_pages {
+                break;
+            }
+            page = page + 1;
+        }
+
+        Ok(coins)
+    }
+
+    fn history(&self, coin_id: &str, start: i64, end: i64) -> Result<Vec<PricePoint>, Box<Error>> {
+        let json = utils::request_json(
+            &format!(
+                "{}/coins/{}/market_chart/range?vs_currency=usd&from={}&to={}",
+                self.base_url,
+                utils::query_quote(coin_id),
+                start,
+                end
+            ),
+            None,
+        )?;
+
+        Ok(Self::points_from_json(&json))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use serde_json::{self, Value};
+    use provider::MarketDataProvider;
+    use stub;
+    use super::CoinGecko;
+
+    fn fixture(name: &str) -> String {
+        let mut body = String::new();
+        File::open(format!("fixtures/coingecko/{}", name))
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        body
+    }
+
+    #[test]
+    fn tickers_from_recorded_markets() {
+        let body = fixture("markets.json");
+        let (url, requests) = stub::http(move |_| (200, body.clone()));
+
+        let coins = CoinGecko::new(&url, CoinGecko::MAX_PAGES).tickers().unwrap();
+        assert_eq!(coins.len(), 2);
+        assert_eq!(coins[0].id, "bitcoin");
+        assert_eq!(coins[0].symbol, "BTC");
+        assert_eq!(coins[0].rank, 1);
+        assert_eq!(coins[0].price_usd, 6458.12);
+        assert_eq!(coins[0].last_updated, 1539331471);
+        assert_eq!(coins[1].max_supply, 0.);
+        // a short page is the last one
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].0.contains("/coins/markets?vs_currency=usd&"));
+        assert!(requests[0].0.contains("&page=1&"));
+    }
+
+    #[test]
+    fn tickers_stop_at_max_pages() {
+        let row = serde_json::from_str::<Value>(&fixture("markets.json")).unwrap()[0].clone();
+        let page = Value::Array(vec![row; CoinGecko::PER_PAGE]).to_string();
+        let (url, requests) = stub::http(move |_| (200, page.clone()));
+
+        let coins = CoinGecko::new(&url, 2).tickers().unwrap();
+        assert_eq!(coins.len(), 2 * CoinGecko::PER_PAGE);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn tickers_reject_unexpected_response() {
+        let (url, _) = stub::http(|_| (200, "{\"error\":\"rate limited\"}".to_string()));
+
+        assert!(CoinGecko::new(&url, 1).tickers().is_err());
+    }
+
+    #[test]
+    fn history_from_recorded_market_chart() {
+        let body = fixture("market_chart_range.json");
+        let (url, requests) = stub::http(move |_| (200, body.clone()));
+
+        let points = CoinGecko::new(&url, 1)
+            .history("bitcoin", 1514764800, 1514775600)
+            .unwrap();
+        // samples without a price are skipped
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0].created, 1514764800);
+        assert_eq!(points[0].price_usd, 13657.2);
+        assert_eq!(points[0].volume_usd, 10291200000.);
+        assert_eq!(points[2].created, 1514775600);
+        assert_eq!(points[2].volume_usd, 9876511234.);
+        assert!(requests.lock().unwrap()[0].0.contains(
+            "/coins/bitcoin/market_chart/range?vs_currency=usd&from=1514764800&to=1514775600"
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+use std::error::Error;
+use serde_json::{self, Value};
+
+use provider::{MarketDataProvider, PricePoint};
+use utils;
+use worker::Coin;
+
+/// coinmarketcap v2 ticker and graphs2 api
+pub struct CoinMarketCap;
+
+impl CoinMarketCap {
+    pub fn new() -> Self {
+        CoinMarketCap
+    }
+
+    /// parse one item of the v2 ticker `data` array
+    pub fn coin_from_json(j: &Value) -> Coin {
+        let usd_quote = &j["quotes"]["USD"];
+        let cny_quote = &j["quotes"]["CNY"];
+
+        Coin {
+            no: j["id"].as_i64().unwrap_or(0),
+            name: j["name"].as_str().unwrap().into(),
+            symbol: j["symbol"].as_str().unwrap().into(),
+            id: j["website_slug"].as_str().unwrap().into(),
+            rank: j["rank"].as_i64().unwrap(),
+            available_supply: j["circulating_supply"].as_f64().unwrap_or(0.),
+            total_supply: j["total_supply"].as_f64().unwrap_or(0.),
+            max_supply: j["max_supply"].as_f64().unwrap_or(0.),
+            price_btc: 0.,
+            price_usd: usd_quote["price"].as_f64().unwrap_or(0.),
+            volume_usd: usd_quote["volume_24h"].as_f64().unwrap_or(0.),
+            market_cap_usd: usd_quote["market_cap"].as_f64().unwrap_or(0.),
+            percent_change_1h: usd_quote["percent_change_1h"].as_f64().unwrap_or(0.),
+            percent_change_24h: usd_quote["percent_change_24h"].as_f64().unwrap_or(0.),
+            percent_change_7d: usd_quote["percent_change_7d"].as_f64().unwrap_or(0.),
+            price_cny: cny_quote["price"].as_f64().unwrap_or(0.),
+            volume_cny: cny_quote["volume_24h"].as_f64().unwrap_or(0.),
+            market_cap_cny: cny_quote["market_cap"].as_f64().unwrap_or(0.),
+            last_updated: j["last_updated"].as_i64().unwrap_or(0),
+        }
+    }
+
+    /// parse a graphs2 response, which is a bunch of [MS TIMESTAMP, VALUE] arrays
+    pub fn points_from_json(json: &Value) -> Vec<PricePoint> {
+        let mut points = vec![];
+        let prices = match json["price_usd"].as_array() {
+            Some(prices) => prices,
+            None => return points,
+        };
+
+        for (idx, price_usd) in prices.iter().enumerate() {
+            let p_usd = price_usd[1].as_f64().unwrap_or(0.0);
+            if p_usd == 0f64 {
+                continue;
+            }
+            points.push(PricePoint {
+                created: (price_usd[0].as_u64().unwrap_or(0) / 1000) as i64,
+                price_usd: p_usd,
+                volume_usd: json["volume_usd"][idx][1].as_f64().unwrap_or(0.0),
+                price_btc: json["price_btc"][idx][1].as_f64().unwrap_or(0.0),
+                price_platform: if json["price_platform"].is_array() {
+                    Some(json["price_platform"][idx][1].as_f64().unwrap_or(0.0))
+                } else {
+                    None
+                },
+            });
+        }
+
+        points
+    }
+}
+
+impl MarketDataProvider for CoinMarketCap {
+    fn tickers(&self) -> Result<Vec<Coin>, Box<Error>> {
+        let mut start = 1;
+        let limit = 100;
+        let mut coins = vec![];
+        loop {
+            let url = format!("https://api.coinmarketcap.com/v2/ticker/?convert=CNY&start={}&limit={}&sort=id&structure=array", start, limit);
+            let ret = utils::request_json(&url, None)?;
+            if ret["data"] == serde_json::Value::Null {
+                break;
+            }
+            for row in ret["data"].as_array().unwrap().iter() {
+                coins.push(Self::coin_from_json(row));
+            }
+            start = start + limit;
+        }
+
+        Ok(coins)
+    }
+
+    fn history(&self, coin_id: &str, start: i64, end: i64) -> Result<Vec<PricePoint>, Box<Error>> {
+        let json = utils::request_json(
+            &format!(
+                "https://graphs2.coinmarketcap.com/currencies/{}/{}/{}/", // graphs.coinmarketcap.com
+                coin_id,
+                start * 1000,
+                end * 1000
+            ),
+            None,
+        )?;
+
+        Ok(Self::points_from_json(&json))
+    }
+}
@@ -0,0 +1,54 @@
+//! Market data providers. A provider knows how to list the tickers of all coins and how to
+//! fetch the price history of a single coin, so the worker loops don't care which upstream
+//! api the numbers come from. The provider is selected by `market_provider` in Rocket.toml.
+
+use std::error::Error;
+use rocket::Config;
+use worker::Coin;
+
+mod coinmarketcap;
+mod coingecko;
+mod replay;
+
+pub use self::coinmarketcap::CoinMarketCap;
+pub use self::coingecko::CoinGecko;
+pub use self::replay::Replay;
+
+/// one historical price sample of a coin, maps to a row of `prices` table
+#[derive(Debug)]
+pub struct PricePoint {
+    pub created: i64,
+    pub price_usd: f64,
+    pub volume_usd: f64,
+    pub price_btc: f64,
+    pub price_platform: Option<f64>,
+}
+
+pub trait MarketDataProvider: Send + Sync {
+    /// list the tickers of all coins
+    fn tickers(&self) -> Result<Vec<Coin>, Box<Error>>;
+
+    /// fetch the price history of a coin between `start` and `end` (unix seconds)
+    fn history(&self, coin_id: &str, start: i64, end: i64) -> Result<Vec<PricePoint>, Box<Error>>;
+}
+
+/// build the provider named by `market_provider` in config, default to coinmarketcap
+pub fn from_config(config: &Config) -> Result<Box<MarketDataProvider>, String> {
+    match config.get_str("market_provider").unwrap_or("coinmarketcap") {
+        "coinmarketcap" => Ok(Box::new(CoinMarketCap::new())),
+        "coingecko" => Ok(Box::new(CoinGecko::new(
+            config
+                .get_str("coingecko_url")
+                .unwrap_or(CoinGecko::BASE_URL),
+            config
+                .get_int("coingecko_max_pages")
+                .map(|x| x.max(1) as usize)
+                .unwrap_or(CoinGecko::MAX_PAGES),
+        ))),
+        "replay" => Ok(Box::new(Replay::new(
+            config.get_str("replay_dir").unwrap_or("fixtures/replay"),
+            config.get_bool("replay_shift").unwrap_or(true),
+        ))),
+        name => Err(format!("unknown market provider: {}", name)),
+    }
+}
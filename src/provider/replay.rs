@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde_json::{self, Value};
+use time;
+
+use provider::{CoinMarketCap, MarketDataProvider, PricePoint};
+use worker::Coin;
+
+/// Replay recorded coinmarketcap responses from a directory, no network is needed:
+///
+/// - `ticker/*.json`: v2 ticker responses, replayed in file name order, one file per
+///   `tickers()` call and start over after the last one
+/// - `graphs/<coin_id>.json`: graphs2 response of the coin
+///
+/// With `shift` on, the timestamps of every graph are moved forward so its last point is the
+/// time the provider started, so the recorded history always looks recent.
+pub struct Replay {
+    pub dir: PathBuf,
+    pub shift: bool,
+    started: i64,
+    next_ticker: Mutex<usize>,
+}
+
+impl Replay {
+    pub fn new(dir: &str, shift: bool) -> Self {
+        Replay {
+            dir: PathBuf::from(dir),
+            shift: shift,
+            started: time::get_time().sec,
+            next_ticker: Mutex::new(0),
+        }
+    }
+
+    fn read_json(path: &Path) -> Result<Value, Box<Error>> {
+        let file = File::open(path)
+            .map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// recorded ticker files sorted by name
+    fn ticker_files(&self) -> Result<Vec<PathBuf>, Box<Error>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(self.dir.join("ticker"))? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        Ok(files)
+    }
+}
+
+impl MarketDataProvider for Replay {
+    fn tickers(&self) -> Result<Vec<Coin>, Box<Error>> {
+        let files = self.ticker_files()?;
+        if files.is_empty() {
+            return Err(From::from(format!(
+                "no recorded ticker in {}",
+                self.dir.join("ticker").display()
+            )));
+        }
+        let path = {
+            let mut next = self.next_ticker.lock().unwrap();
+            let path = files[*next % files.len()].clone();
+            *next = (*next + 1) % files.len();
+            path
+        };
+        debug!("Replay: {}", path.display());
+
+        let json = Self::read_json(&path)?;
+        // either a whole v2 ticker response or just its data array
+        let rows = match json["data"].as_array().or(json.as_array()) {
+            Some(rows) => rows.clone(),
+            None => vec![],
+        };
+
+        Ok(rows.iter().map(CoinMarketCap::coin_from_json).collect())
+    }
+
+    fn history(&self, coin_id: &str, start: i64, end: i64) -> Result<Vec<PricePoint>, Box<Error>> {
+        let path = self.dir.join("graphs").join(format!("{}.json", coin_id));
+        if !path.exists() {
+            // no recording of this coin, same as an empty response
+            return Ok(vec![]);
+        }
+        let mut points = CoinMarketCap::points_from_json(&Self::read_json(&path)?);
+
+        if self.shift {
+            let last = points.iter().map(|p| p.created).max().unwrap_or(self.started);
+            let offset = self.started - last;
+            for point in points.iter_mut() {
+                point.created = point.created + offset;
+            }
+        }
+        points.retain(|p| p.created > start && p.created <= end);
+
+        Ok(points)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use provider::MarketDataProvider;
+    use super::Replay;
+
+    const DIR: &'static str = "fixtures/replay";
+    /// first and last sample of the recorded graphs, 6 hours apart
+    const FIRST: i64 = 1535414400;
+    const LAST: i64 = 1538006400;
+    const STEP: i64 = 21600;
+
+    #[test]
+    fn tickers_rotate_through_recordings() {
+        let replay = Replay::new(DIR, false);
+
+        let first = replay.tickers().unwrap();
+        assert_eq!(first.len(), 3);
+        assert_eq!(first[0].id, "bitcoin");
+        assert_eq!(first[0].symbol, "BTC");
+        assert_eq!(first[0].price_usd, 6520.3);
+        assert_eq!(first[0].last_updated, 1538006400);
+        assert_eq!(first[2].id, "litecoin");
+        let second = replay.tickers().unwrap();
+        assert_eq!(second[0].price_usd, 6546.38);
+        assert_eq!(second[0].last_updated, 1538006700);
+        // start over after the last one
+        let third = replay.tickers().unwrap();
+        assert_eq!(third[0].price_usd, 6520.3);
+    }
+
+    #[test]
+    fn tickers_need_recordings() {
+        assert!(Replay::new("fixtures/missing", false).tickers().is_err());
+    }
+
+    #[test]
+    fn history_excludes_start_and_includes_end() {
+        let replay = Replay::new(DIR, false);
+
+        let points = replay.history("bitcoin", FIRST, FIRST + 2 * STEP).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].created, FIRST + STEP);
+        assert_eq!(points[0].price_usd, 6569.02);
+        assert_eq!(points[1].created, FIRST + 2 * STEP);
+        assert_eq!(points[1].price_usd, 6616.64);
+
+        let all = replay.history("ethereum", 0, LAST).unwrap();
+        assert_eq!(all.len(), 121);
+        assert_eq!(all[0].created, FIRST);
+        assert_eq!(all[0].price_btc, 0.03395549);
+        assert_eq!(all[0].volume_usd, 8856000.0);
+    }
+
+    #[test]
+    fn history_of_unrecorded_coin_is_empty() {
+        let points = Replay::new(DIR, false).history("dogecoin", 0, LAST).unwrap();
+        assert!(points.is_empty());
+    }
+
+    #[test]
+    fn shift_moves_the_last_sample_to_start_time() {
+        let started = 1600000000;
+        let replay = Replay {
+            started: started,
+            ..Replay::new(DIR, true)
+        };
+
+        let points = replay.history("bitcoin", 0, started).unwrap();
+        assert_eq!(points.len(), 121);
+        assert_eq!(points[0].created, started - (LAST - FIRST));
+        assert_eq!(points[0].price_usd, 6520.3);
+        assert_eq!(points[120].created, started);
+        assert_eq!(points[120].price_usd, 6275.47);
+        // the recorded times are out of range now
+        assert!(replay.history("bitcoin", FIRST - 1, LAST).unwrap().is_empty());
+        // the filter applies to shifted times
+        let recent = replay.history("bitcoin", started - STEP, started).unwrap();
+        assert_eq!(recent.len(), 1);
+    }
+}
@@ -0,0 +1,165 @@
+//! Local servers standing in for upstream apis and mail relays in tests. They bind an
+//! ephemeral port on 127.0.0.1 and record what they received, so a test can assert on both
+//! sides. Market data for tests is built here too.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use worker::{Coin, State};
+
+/// (REQUEST LINE, BODY) of the requests received by a stub
+pub type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+/// answer every http request with the status and json body returned by `respond(PATH)`,
+/// return the base url of the stub
+pub fn http<F>(respond: F) -> (String, Requests)
+where
+    F: Fn(&str) -> (u16, String) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests: Requests = Arc::new(Mutex::new(vec![]));
+    let received = requests.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => break,
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_right().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if line.starts_with("content-length:") {
+                    content_length = line[15..].trim().parse().unwrap_or(0);
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let path = request_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or("")
+                .to_string();
+            received.lock().unwrap().push((
+                request_line.trim_right().to_string(),
+                String::from_utf8_lossy(&body).into_owned(),
+            ));
+            let (status, body) = respond(&path);
+            write!(
+                stream,
+                "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            ).unwrap();
+        }
+    });
+
+    (format!("http://{}", addr), requests)
+}
+
+/// (RCPT TO ADDRESS, DATA) of the messages received by a smtp stub
+pub type Messages = Arc<Mutex<Vec<(String, String)>>>;
+
+/// accept smtp sessions like a local sink, return its port
+pub fn smtp() -> (u16, Messages) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let messages: Messages = Arc::new(Mutex::new(vec![]));
+    let received = messages.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => break,
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 stub\r\n").unwrap();
+            let mut rcpt = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+                let line = line.trim_right();
+                if line.to_uppercase().starts_with("RCPT TO:") {
+                    rcpt = line[8..].trim_matches(|c| c == '<' || c == '>').to_string();
+                    stream.write_all(b"250 ok\r\n").unwrap();
+                } else if line.eq_ignore_ascii_case("DATA") {
+                    stream.write_all(b"354 go ahead\r\n").unwrap();
+                    let mut data = String::new();
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 || line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&line);
+                    }
+                    received.lock().unwrap().push((rcpt.clone(), data));
+                    stream.write_all(b"250 queued\r\n").unwrap();
+                } else if line.eq_ignore_ascii_case("QUIT") {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    // EHLO and MAIL FROM
+                    stream.write_all(b"250 ok\r\n").unwrap();
+                }
+            }
+        }
+    });
+
+    (port, messages)
+}
+
+/// a coin of the market data with only what tests look at
+pub fn coin(id: &str, symbol: &str, rank: i64, price_usd: f64) -> Coin {
+    Coin {
+        id: id.to_string(),
+        name: id.to_string(),
+        symbol: symbol.to_string(),
+        rank: rank,
+        price_usd: price_usd,
+        price_btc: 0.0,
+        volume_usd: 0.0,
+        market_cap_usd: 0.0,
+        available_supply: 0.0,
+        total_supply: 0.0,
+        max_supply: 0.0,
+        percent_change_1h: 0.0,
+        percent_change_24h: 0.0,
+        percent_change_7d: 0.0,
+        last_updated: 0,
+        price_cny: 0.0,
+        volume_cny: 0.0,
+        market_cap_cny: 0.0,
+        no: rank,
+    }
+}
+
+/// market data of bitcoin, ethereum and tether, with usd and cny rates
+pub fn state() -> State {
+    let mut rates = HashMap::new();
+    rates.insert("USD".to_string(), 1.0);
+    rates.insert("CNY".to_string(), 6.5);
+    State {
+        rates: rates,
+        coins: vec![
+            coin("bitcoin", "BTC", 1, 40000.0),
+            coin("ethereum", "ETH", 2, 2000.0),
+            coin("tether", "USDT", 3, 1.0),
+        ],
+    }
+}
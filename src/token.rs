@@ -0,0 +1,291 @@
+//! Access tokens. A token is `v2.{key id}.{base64 session id}.{expires}.{base64 mac}`, the
+//! mac is HMAC-SHA256 of everything before it, so a token can't be forged or altered without
+//! the key. Access tokens are short-lived, clients get new ones with refresh tokens. Keys are
+//! loaded from `token_keys` in Rocket.toml and new tokens are signed with `token_key_id`.
+//! Retired keys can stay in `token_keys` to verify tokens issued before a rotation. Tokens
+//! without expiry, `v1.{key id}.{base64 session id}.{base64 mac}` and the legacy AES ones,
+//! are accepted until `legacy_tokens_until`, 30 days after startup if it's not configured.
+
+use std::collections::HashMap;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::md5::Md5;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rocket::Config;
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use time;
+use error::E;
+use utils;
+
+const VERSION: &'static str = "v2";
+/// key of legacy tokens, MD5 of it was used as the AES key
+const LEGACY_KEY: &'static str = "j0n";
+/// the key of Rocket.toml.sample, a server must not start with it
+const SAMPLE_SECRET: &'static str = "replace-with-a-random-secret-of-at-least-32-bytes";
+/// legacy tokens are accepted this long after startup unless `legacy_tokens_until` is set
+const LEGACY_GRACE_SECS: i64 = 2592000;
+
+pub struct TokenKeys {
+    /// {KEY ID => SECRET}
+    keys: HashMap<String, Vec<u8>>,
+    /// id of the key signing new tokens
+    current: String,
+    /// legacy tokens are rejected after it
+    legacy_until: i64,
+}
+
+impl TokenKeys {
+    pub fn new(keys: HashMap<String, Vec<u8>>, current: &str, legacy_until: i64) -> Result<Self, String> {
+        if !keys.contains_key(current) {
+            return Err(format!("token key {} is not found in token_keys", current));
+        }
+        if current.contains('.') {
+            return Err("token key id can't contain '.'".to_string());
+        }
+        Ok(TokenKeys {
+            keys: keys,
+            current: current.to_string(),
+            legacy_until: legacy_until,
+        })
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let table = config
+            .get_table("token_keys")
+            .map_err(|_| "token_keys is not configured".to_string())?;
+        let mut keys = HashMap::new();
+        for (kid, secret) in table.iter() {
+            let secret = secret
+                .as_str()
+                .ok_or(format!("token key {} should be a string", kid))?;
+            if secret.len() < 32 {
+                return Err(format!("token key {} should be at least 32 bytes", kid));
+            }
+            if secret == SAMPLE_SECRET {
+                return Err(format!("token key {} is the sample secret, replace it", kid));
+            }
+            keys.insert(kid.clone(), secret.as_bytes().to_vec());
+        }
+        let current = config
+            .get_str("token_key_id")
+            .map_err(|_| "token_key_id is not configured".to_string())?;
+        let legacy_until = config
+            .get_int("legacy_tokens_until")
+            .unwrap_or(time::get_time().sec + LEGACY_GRACE_SECS);
+
+        TokenKeys::new(keys, current, legacy_until)
+    }
+
+    fn mac(secret: &[u8], signed: &str) -> Vec<u8> {
+        let mut hmac = Hmac::new(Sha256::new(), secret);
+        hmac.input(signed.as_bytes());
+        hmac.result().code().to_vec()
+    }
+
+    /// sign session id into an access token expiring at `expires` with the current key
+    pub fn sign(&self, sess_id: &str, expires: i64) -> String {
+        let signed = format!(
+            "{}.{}.{}.{}",
+            VERSION,
+            self.current,
+            sess_id.as_bytes().to_base64(URL_SAFE),
+            expires
+        );
+        let mac = TokenKeys::mac(&self.keys[&self.current], &signed);
+
+        format!("{}.{}", signed, mac.to_base64(URL_SAFE))
+    }
+
+    /// verify access token and return the session id in it
+    pub fn verify(&self, access_token: &str) -> Result<String, E> {
+        let parts_num = if access_token.starts_with("v2.") {
+            4
+        } else if access_token.starts_with("v1.") {
+            3
+        } else {
+            return self.verify_legacy(access_token);
+        };
+        let pos = access_token.rfind('.').ok_or(E::AccessTokenInvalid)?;
+        let (signed, mac) = (&access_token[..pos], &access_token[pos + 1..]);
+        let parts: Vec<&str> = signed.split('.').collect();
+        if parts.len() != parts_num {
+            return Err(E::AccessTokenInvalid);
+        }
+        let secret = self.keys.get(parts[1]).ok_or(E::AccessTokenInvalid)?;
+        let mac = mac.from_base64().map_err(|_| E::AccessTokenInvalid)?;
+        if !fixed_time_eq(&mac, &TokenKeys::mac(secret, signed)) {
+            return Err(E::AccessTokenInvalid);
+        }
+        let now = time::get_time().sec;
+        if parts_num == 3 {
+            // v1 tokens never expire by themselves
+            if now > self.legacy_until {
+                return Err(E::AccessTokenInvalid);
+            }
+        } else {
+            let expires = parts[3]
+                .parse::<i64>()
+                .map_err(|_| E::AccessTokenInvalid)?;
+            if now > expires {
+                return Err(E::AccessTokenExpired);
+            }
+        }
+        let sess_id = parts[2].from_base64().map_err(|_| E::AccessTokenInvalid)?;
+
+        String::from_utf8(sess_id).map_err(|_| E::AccessTokenInvalid)
+    }
+
+    /// AES-CBC encrypted session id issued before signed tokens
+    fn verify_legacy(&self, access_token: &str) -> Result<String, E> {
+        if time::get_time().sec > self.legacy_until {
+            return Err(E::AccessTokenInvalid);
+        }
+        let mut sh = Md5::new();
+        sh.input_str(LEGACY_KEY);
+        let key = sh.result_str();
+        let enc = access_token
+            .from_base64()
+            .map_err(|_| E::AccessTokenInvalid)?;
+        let dec = utils::decrypt(&enc, &key.as_bytes()).map_err(|_| E::AccessTokenInvalid)?;
+        let sess_id = String::from_utf8(dec).map_err(|_| E::AccessTokenInvalid)?;
+
+        Ok(sess_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crypto::{aes, blockmodes, buffer};
+    use crypto::buffer::WriteBuffer;
+    use crypto::digest::Digest;
+    use crypto::md5::Md5;
+    use rustc_serialize::base64::{ToBase64, STANDARD, URL_SAFE};
+    use time;
+    use error::E;
+    use super::{TokenKeys, LEGACY_KEY};
+
+    const SESS_ID: &'static str = "0b8f8f4e-5c2a-4a39-9a7e-2f4d8c1b6e10";
+    const OLD_SECRET: &'static [u8] = b"an-old-secret-of-at-least-32-bytes!!";
+    const NEW_SECRET: &'static [u8] = b"a-new-secret-of-at-least-32-bytes!!!";
+
+    fn keys(ids: &[&str], current: &str, legacy_until: i64) -> TokenKeys {
+        let mut keys = HashMap::new();
+        for &id in ids.iter() {
+            let secret = if id == "1" { OLD_SECRET } else { NEW_SECRET };
+            keys.insert(id.to_string(), secret.to_vec());
+        }
+        TokenKeys::new(keys, current, legacy_until).unwrap()
+    }
+
+    fn later() -> i64 {
+        time::get_time().sec + 3600
+    }
+
+    fn earlier() -> i64 {
+        time::get_time().sec - 3600
+    }
+
+    fn v1_token(kid: &str, secret: &[u8]) -> String {
+        let signed = format!("v1.{}.{}", kid, SESS_ID.as_bytes().to_base64(URL_SAFE));
+        let mac = TokenKeys::mac(secret, &signed);
+        format!("{}.{}", signed, mac.to_base64(URL_SAFE))
+    }
+
+    fn legacy_token() -> String {
+        let mut sh = Md5::new();
+        sh.input_str(LEGACY_KEY);
+        let key = sh.result_str();
+        let mut encryptor = aes::cbc_encryptor(
+            aes::KeySize::KeySize256,
+            key.as_bytes(),
+            &[0; 16],
+            blockmodes::PkcsPadding,
+        );
+        let mut out = [0u8; 64];
+        let len = {
+            let mut read = buffer::RefReadBuffer::new(SESS_ID.as_bytes());
+            let mut write = buffer::RefWriteBuffer::new(&mut out);
+            encryptor.encrypt(&mut read, &mut write, true).unwrap();
+            write.position()
+        };
+        out[..len].to_base64(STANDARD)
+    }
+
+    fn is_invalid(ret: Result<String, E>) -> bool {
+        match ret {
+            Err(E::AccessTokenInvalid) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let keys = keys(&["1"], "1", 0);
+        let token = keys.sign(SESS_ID, later());
+        assert!(token.starts_with("v2.1."));
+        assert_eq!(keys.verify(&token).unwrap(), SESS_ID);
+    }
+
+    #[test]
+    fn tampered_tokens_are_invalid() {
+        let keys = keys(&["1"], "1", 0);
+        let token = keys.sign(SESS_ID, later());
+        // another mac
+        let pos = token.rfind('.').unwrap();
+        let forged = format!("{}.{}", &token[..pos], [0u8; 32].to_base64(URL_SAFE));
+        assert!(is_invalid(keys.verify(&forged)));
+        // a later expiry under the same mac
+        let parts: Vec<&str> = token.split('.').collect();
+        let extended = format!("v2.1.{}.{}.{}", parts[2], later() + 86400, parts[4]);
+        assert!(is_invalid(keys.verify(&extended)));
+        assert!(is_invalid(keys.verify("v2.1.abc")));
+    }
+
+    #[test]
+    fn unknown_key_ids_are_invalid() {
+        let token = keys(&["2"], "2", 0).sign(SESS_ID, later());
+        assert!(is_invalid(keys(&["1"], "1", 0).verify(&token)));
+    }
+
+    #[test]
+    fn retired_keys_still_verify() {
+        let old = keys(&["1"], "1", 0).sign(SESS_ID, later());
+        let keys = keys(&["1", "2"], "2", 0);
+        assert!(keys.sign(SESS_ID, later()).starts_with("v2.2."));
+        assert_eq!(keys.verify(&old).unwrap(), SESS_ID);
+    }
+
+    #[test]
+    fn expired_tokens_are_told_apart() {
+        let keys = keys(&["1"], "1", 0);
+        match keys.verify(&keys.sign(SESS_ID, earlier())) {
+            Err(E::AccessTokenExpired) => (),
+            ret => panic!("unexpected {:?}", ret),
+        }
+    }
+
+    #[test]
+    fn legacy_tokens_are_accepted_until_legacy_until() {
+        let v1 = v1_token("1", OLD_SECRET);
+        let aes = legacy_token();
+
+        let keys_before = keys(&["1"], "1", later());
+        assert_eq!(keys_before.verify(&v1).unwrap(), SESS_ID);
+        assert_eq!(keys_before.verify(&aes).unwrap(), SESS_ID);
+
+        let keys_after = keys(&["1"], "1", earlier());
+        assert!(is_invalid(keys_after.verify(&v1)));
+        assert!(is_invalid(keys_after.verify(&aes)));
+
+        // a v1 token still needs a valid mac
+        assert!(is_invalid(keys_before.verify(&v1_token("1", NEW_SECRET))));
+    }
+
+    #[test]
+    fn current_key_must_exist() {
+        assert!(TokenKeys::new(HashMap::new(), "1", 0).is_err());
+    }
+}
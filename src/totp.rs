@@ -0,0 +1,91 @@
+use hmac_sha1::hmac_sha1;
+use utils;
+
+/// seconds of a time step
+pub const STEP: i64 = 30;
+/// digits of a code
+pub const DIGITS: u32 = 6;
+/// steps of clock drift accepted before and after now
+const SKEW: i64 = 1;
+
+/// RFC 4226 one-time password of the counter
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut msg = [0u8; 8];
+    for i in 0..8 {
+        msg[i] = (counter >> (56 - i * 8)) as u8;
+    }
+    let hash = hmac_sha1(secret, &msg);
+    // dynamic truncation
+    let offset = (hash[19] & 0xf) as usize;
+    let bin = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+
+    bin % 10u32.pow(DIGITS)
+}
+
+/// RFC 6238 check of a code around `now`, return the time step it belongs to
+pub fn verify(secret: &[u8], code: &str, now: i64) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let step = now / STEP;
+
+    (step - SKEW..step + SKEW + 1).find(|&s| s >= 0 && hotp(secret, s as u64) == code)
+}
+
+/// key uri read by authenticator apps, usually shown as a qr code
+pub fn uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = utils::query_quote(issuer),
+        account = utils::query_quote(account),
+        secret = utils::base32_encode(secret),
+        digits = DIGITS,
+        period = STEP,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hotp, verify, STEP};
+
+    const SECRET: &'static [u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc4226() {
+        let codes = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, &code) in codes.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), code, "counter {}", counter);
+        }
+    }
+
+    #[test]
+    fn verify_matches_rfc6238() {
+        // the SHA1 vectors with 8 digits, a 6 digits code is the last 6 of them
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for &(now, code) in vectors.iter() {
+            assert_eq!(verify(SECRET, code, now), Some(now / STEP), "time {}", now);
+        }
+    }
+
+    #[test]
+    fn verify_accepts_one_step_of_drift() {
+        assert_eq!(verify(SECRET, "287082", 89), Some(1));
+        assert_eq!(verify(SECRET, "287082", 0), Some(1));
+        assert_eq!(verify(SECRET, "287082", 90), None);
+        assert_eq!(verify(SECRET, "94287082", 59), None);
+        assert_eq!(verify(SECRET, "28708a", 59), None);
+    }
+}
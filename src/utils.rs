@@ -1,6 +1,7 @@
 extern crate futures;
 extern crate hyper;
 extern crate hyper_tls;
+extern crate native_tls;
 extern crate serde_json;
 extern crate tokio_core;
 extern crate toml;
@@ -8,19 +9,25 @@ extern crate toml;
 use std::io::{self, Read};
 use std::fs::File;
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::time::Duration;
-use self::futures::{Future, Stream};
+use self::futures::{future, Future, Stream};
 use self::futures::future::Either;
-use self::hyper::Client;
+use self::hyper::{Client, Method, Request, Uri};
+use self::hyper::client::Service;
+use self::hyper::header::ContentType;
 use self::hyper_tls::HttpsConnector;
-use self::tokio_core::reactor::{Core, Timeout};
+use self::native_tls::TlsConnector;
+use self::tokio_core::net::TcpStream;
+use self::tokio_core::reactor::{Core, Handle, Timeout};
 use self::serde_json::Value as Json;
 use self::toml::Value as Toml;
 use crypto::{aes, blockmodes, buffer, symmetriccipher};
 use crypto::buffer::{ReadBuffer, WriteBuffer};
 
 pub fn request_json(url: &str, timeout: Option<u64>) -> Result<Json, Box<Error>> {
-    println!("Request: {}", url);
+    // the query string may carry api keys and signatures
+    debug!("Request: {}", url.split('?').next().unwrap_or(""));
     let mut core = Core::new()?;
     let handle = core.handle();
     let timeout = Timeout::new(Duration::from_secs(timeout.unwrap_or(60u64)), &handle)?;
@@ -29,7 +36,7 @@ pub fn request_json(url: &str, timeout: Option<u64>) -> Result<Json, Box<Error>>
         .build(&handle);
 
     let get = client.get(url.parse()?).and_then(|res| {
-        println!("Response: {}", res.status());
+        debug!("Response: {}", res.status());
 
         res.body().concat2().and_then(move |body| {
             serde_json::from_slice(&body).map_err(|_| {
@@ -56,6 +63,163 @@ pub fn request_json(url: &str, timeout: Option<u64>) -> Result<Json, Box<Error>>
 
     core.run(work).map_err(From::from)
 }
+
+/// connects to the given addresses in order whatever the host of the uri resolves to by then,
+/// so a host can't pass the check of its addresses and then point somewhere else. Tls still
+/// verifies the certificate against the host, and the Host header comes from the uri.
+struct PinnedConnector {
+    addrs: Vec<SocketAddr>,
+    handle: Handle,
+}
+
+impl Service for PinnedConnector {
+    type Request = Uri;
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+    fn call(&self, _uri: Uri) -> Self::Future {
+        let mut connect: Self::Future = Box::new(future::err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no address to connect",
+        )));
+        for &addr in self.addrs.iter() {
+            let handle = self.handle.clone();
+            connect = Box::new(connect.or_else(move |_| TcpStream::connect(&addr, &handle)));
+        }
+        connect
+    }
+}
+
+/// post json with extra headers, return the response status and body. The host is resolved
+/// once, with `public_only` all its addresses must be public (see `resolve_public_url`) and
+/// the request goes to exactly those.
+pub fn post_json(
+    url: &str,
+    data: &Json,
+    headers: &[(&str, String)],
+    timeout: Option<u64>,
+    public_only: bool,
+) -> Result<(u16, String), Box<Error>> {
+    let addrs = if public_only {
+        resolve_public_url(url)?
+    } else {
+        resolve_url(url)?.1
+    };
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let timeout = Timeout::new(Duration::from_secs(timeout.unwrap_or(60u64)), &handle)?;
+    let connector = PinnedConnector {
+        addrs: addrs,
+        handle: handle.clone(),
+    };
+    let tls = TlsConnector::builder()?.build()?;
+    let client = Client::configure()
+        .connector(HttpsConnector::from((connector, tls)))
+        .build(&handle);
+
+    let mut req = Request::new(Method::Post, url.parse()?);
+    req.headers_mut().set(ContentType::json());
+    for &(name, ref value) in headers.iter() {
+        req.headers_mut().set_raw(name.to_string(), value.clone());
+    }
+    req.set_body(data.to_string());
+
+    let post = client.request(req).and_then(|res| {
+        let status = res.status().as_u16();
+
+        res.body()
+            .concat2()
+            .map(move |body| (status, String::from_utf8_lossy(&body).into_owned()))
+    });
+
+    let work = post.select2(timeout)
+        .map_err(|res| match res {
+            Either::A((post_error, _timeout)) => post_error,
+            Either::B((timeout_error, _post)) => From::from(timeout_error),
+        })
+        .and_then(|res| match res {
+            Either::A((got, _timeout)) => Ok(got),
+            Either::B((_timeout_error, _post)) => Err(hyper::Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Client timed out while connecting",
+            ))),
+        });
+
+    core.run(work).map_err(From::from)
+}
+
+/// whether the address is on the public internet, private, loopback, link-local (where cloud
+/// metadata services live), shared and reserved ranges are not
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast()
+                || ip.is_documentation() || ip.is_unspecified() || ip.is_multicast()
+                || octets[0] == 0
+                // 100.64.0.0/10 carrier-grade nat, also aliyun metadata 100.100.100.200
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+                // 192.0.0.0/24 protocol assignments, 198.18.0.0/15 benchmarking
+                || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+                || (octets[0] == 198 && octets[1] & 0xfe == 18)
+                || octets[0] >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                // unique local fc00::/7, link-local fe80::/10 and site-local fec0::/10
+                || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+                || first & 0xffc0 == 0xfec0)
+                // v4-mapped and v4-compatible addresses are checked as v4
+                && ip.to_ipv4().map_or(true, |ip| is_public_ip(&IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// make sure a url given by users is http(s) and all addresses of its host are public, so
+/// that it can't be used to reach our internal network. Check it when the url is saved,
+/// requests are checked by `post_json` against the addresses they connect to.
+pub fn check_public_url(url: &str) -> Result<(), String> {
+    resolve_public_url(url).map(|_| ())
+}
+
+/// addresses of the host of a http(s) url, like `check_public_url` all must be public
+pub fn resolve_public_url(url: &str) -> Result<Vec<SocketAddr>, String> {
+    let (host, addrs) = resolve_url(url)?;
+    if host == "localhost" || host.ends_with(".localhost") || host.ends_with(".internal") {
+        return Err(format!("host is not allowed: {}", host));
+    }
+    match addrs.iter().find(|addr| !is_public_ip(&addr.ip())) {
+        Some(addr) => Err(format!("{} resolves to a non-public address {}", url, addr.ip())),
+        None => Ok(addrs),
+    }
+}
+
+/// lowercase host of a http(s) url and its addresses
+fn resolve_url(url: &str) -> Result<(String, Vec<SocketAddr>), String> {
+    let uri: Uri = url.parse().map_err(|_| format!("invalid url: {}", url))?;
+    let default_port = match uri.scheme() {
+        Some("http") => 80,
+        Some("https") => 443,
+        _ => return Err(format!("not a http url: {}", url)),
+    };
+    let host = uri.host()
+        .ok_or(format!("no host in url: {}", url))?
+        .trim_left_matches('[')
+        .trim_right_matches(']')
+        .to_lowercase();
+    let addrs: Vec<SocketAddr> = (host.as_str(), uri.port().unwrap_or(default_port))
+        .to_socket_addrs()
+        .map_err(|e| format!("can't resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("can't resolve {}", host));
+    }
+
+    Ok((host, addrs))
+}
+
 #[allow(dead_code)]
 pub fn toml2json(toml: Toml) -> Json {
     match toml {
@@ -117,29 +281,48 @@ pub fn rfc3986_encode(str: &str, full_url: bool) -> String {
     })
 }
 
-pub fn encrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
-    let mut encryptor: Box<symmetriccipher::Encryptor> = aes::cbc_encryptor(
-        aes::KeySize::KeySize256,
-        key,
-        &[0; 16],
-        blockmodes::PkcsPadding,
-    );
+const BASE32_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
 
-    let mut final_result = Vec::<u8>::new();
-    let mut buffer = [0; 4096];
-    let mut read_buffer = buffer::RefReadBuffer::new(data);
-    let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
+/// rfc4648 base32 without padding, the encoding of totp secrets
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in data.iter() {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            out.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
 
-    loop {
-        let result = try!(encryptor.encrypt(&mut read_buffer, &mut write_buffer, true));
-        final_result.extend(write_buffer.take_read_buffer().take_remaining());
-        match result {
-            buffer::BufferResult::BufferUnderflow => break,
-            buffer::BufferResult::BufferOverflow => {}
+    out
+}
+
+/// decode rfc4648 base32, case insensitive and padding is optional
+pub fn base32_decode(str: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for ch in str.trim_right_matches('=').chars() {
+        let value = match ch.to_ascii_uppercase() {
+            c @ 'A'...'Z' => c as u32 - 'A' as u32,
+            c @ '2'...'7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            out.push((buffer >> (bits - 8)) as u8);
+            bits -= 8;
         }
     }
 
-    Ok(final_result)
+    Some(out)
 }
 
 pub fn decrypt(
@@ -169,3 +352,82 @@ pub fn decrypt(
 
     Ok(final_result)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::{base32_decode, base32_encode, check_public_url, is_public_ip};
+
+    #[test]
+    fn base32_matches_rfc4648() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for &(data, encoded) in vectors.iter() {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded), Some(data.as_bytes().to_vec()));
+        }
+        // padding and lower case as authenticator apps may show them
+        assert_eq!(base32_decode("mzxw6ytboi======"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        let data: Vec<u8> = (0..256).map(|x| x as u8).collect();
+        for len in 0..data.len() {
+            assert_eq!(base32_decode(&base32_encode(&data[..len])), Some(data[..len].to_vec()));
+        }
+    }
+
+    fn public(ip: &str) -> bool {
+        is_public_ip(&ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:10.0.0.1",
+            "::ffff:127.0.0.1",
+        ].iter()
+        {
+            assert!(!public(ip), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "93.184.216.34", "2606:4700:4700::1111"].iter() {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn urls_of_internal_hosts_are_rejected() {
+        for url in [
+            "http://127.0.0.1:8000/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/hook",
+            "http://10.0.0.8/hook",
+            "http://metadata.google.internal/computeMetadata/v1/",
+            "ftp://8.8.8.8/hook",
+        ].iter()
+        {
+            assert!(check_public_url(url).is_err(), "{}", url);
+        }
+        assert!(check_public_url("https://8.8.8.8/hook").is_ok());
+    }
+}
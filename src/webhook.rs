@@ -0,0 +1,173 @@
+//! Outgoing webhooks. Events are queued as deliveries of every subscription interested in
+//! them, then posted by the delivery worker. A payload is signed with the subscription's
+//! secret by HMAC-SHA256 over "{timestamp}.{body}" and the signature is sent in the
+//! `X-Yield-Signature` header. Failed deliveries are retried with exponential backoff. The url
+//! is resolved again before each attempt and refused unless all its addresses are public, the
+//! request then connects to exactly those addresses, so a host changed to point into the local
+//! network isn't posted to. Only the status code of the response is kept.
+
+use std::error::Error;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use mysql::{self, Pool};
+use serde_json::{self, Value};
+use time;
+use error::E;
+use utils;
+
+pub const HOLDINGS_CHANGED: &'static str = "holdings.changed";
+pub const ALERT_FIRED: &'static str = "alert.fired";
+pub const COINS_REFRESHED: &'static str = "coins.refreshed";
+
+pub const EVENTS: [&'static str; 3] = [HOLDINGS_CHANGED, ALERT_FIRED, COINS_REFRESHED];
+
+/// a delivery is given up after this many attempts
+const MAX_ATTEMPTS: i64 = 8;
+/// delay of the first retry, doubled after each failure
+const RETRY_BASE_SECS: i64 = 30;
+/// deliveries posted per cycle of the worker
+const BATCH_SIZE: i64 = 50;
+/// delivered and failed deliveries are kept for 30 days
+const RETENTION_SECS: i64 = 2592000;
+
+/// hex encoded HMAC-SHA256 of "{timestamp}.{body}"
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(timestamp.to_string().as_bytes());
+    hmac.input(b".");
+    hmac.input(body.as_bytes());
+    hmac.result()
+        .code()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// queue an event for the subscriptions of a user, or of all users if it's None.
+/// Return the number of deliveries queued.
+pub fn emit(mysql_pool: &Pool, user_id: Option<i64>, event: &str, data: Value) -> Result<u64, E> {
+    let now = time::get_time().sec;
+    let payload = json!({
+        "event": event,
+        "created": now,
+        "data": data,
+    }).to_string();
+    let mut sql = String::from(
+        "INSERT INTO webhook_deliveries \
+         (webhook_id,user_id,event,payload,status,attempts,next_attempt,created) \
+         SELECT id,user_id,?,?,'pending',0,?,? FROM webhooks \
+         WHERE active=1 AND FIND_IN_SET(?,events)",
+    );
+    let mut params = vec![
+        mysql::Value::from(event),
+        mysql::Value::from(payload),
+        mysql::Value::from(now),
+        mysql::Value::from(now),
+        mysql::Value::from(event),
+    ];
+    if let Some(user_id) = user_id {
+        sql.push_str(" AND user_id=?");
+        params.push(mysql::Value::from(user_id));
+    }
+    let ret = mysql_pool.prep_exec(sql, params)?;
+
+    Ok(ret.affected_rows())
+}
+
+/// status of a delivery after an attempt got `code` (0 if the request failed) and the time of
+/// the next attempt, `attempts` includes this one
+fn outcome(code: u16, attempts: i64, timestamp: i64) -> (&'static str, i64) {
+    let status = if code >= 200 && code < 300 {
+        "delivered"
+    } else if attempts >= MAX_ATTEMPTS {
+        "failed"
+    } else {
+        "pending"
+    };
+    // 30s, 1m, 2m, 4m ... between retries
+    (status, timestamp + RETRY_BASE_SECS * (1 << (attempts - 1)))
+}
+
+/// post the deliveries which are due, return the number of them
+pub fn deliver_pending(
+    mysql_pool: &Pool,
+    timeout: u64,
+    allow_private_hosts: bool,
+) -> Result<usize, Box<Error>> {
+    let now = time::get_time().sec;
+    let mut due = vec![];
+    for row in mysql_pool.prep_exec(
+        "SELECT d.id,d.event,d.payload,d.attempts,w.url,w.secret FROM webhook_deliveries d \
+         INNER JOIN webhooks w ON w.id=d.webhook_id \
+         WHERE d.status='pending' AND d.next_attempt<=? ORDER BY d.next_attempt ASC LIMIT ?",
+        (now, BATCH_SIZE),
+    )? {
+        let item: (i64, String, String, i64, String, String) = mysql::from_row(row?);
+        due.push(item);
+    }
+
+    for &(id, ref event, ref payload, attempts, ref url, ref secret) in due.iter() {
+        let timestamp = time::get_time().sec;
+        let headers = [
+            ("X-Yield-Event", event.clone()),
+            ("X-Yield-Delivery", id.to_string()),
+            ("X-Yield-Timestamp", timestamp.to_string()),
+            (
+                "X-Yield-Signature",
+                format!("sha256={}", sign(secret, timestamp, payload)),
+            ),
+        ];
+        let data: Value = serde_json::from_str(payload)?;
+        let code = utils::post_json(url, &data, &headers, Some(timeout), !allow_private_hosts)
+            .map(|(code, _)| code)
+            .unwrap_or(0);
+        let attempts = attempts + 1;
+        let (status, next_attempt) = outcome(code, attempts, timestamp);
+        mysql_pool.prep_exec(
+            "UPDATE webhook_deliveries SET status=?,attempts=?,next_attempt=?,response_code=?,\
+             delivered=? WHERE id=?",
+            (status, attempts, next_attempt, code, timestamp, id),
+        )?;
+    }
+
+    Ok(due.len())
+}
+
+/// purge finished deliveries past the retention, return the number of them
+pub fn sweep(mysql_pool: &Pool) -> Result<u64, E> {
+    let ret = mysql_pool.prep_exec(
+        "DELETE FROM webhook_deliveries WHERE status IN ('delivered','failed') AND created<?",
+        (time::get_time().sec - RETENTION_SECS,),
+    )?;
+
+    Ok(ret.affected_rows())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{outcome, sign, MAX_ATTEMPTS};
+
+    #[test]
+    fn sign_is_hmac_sha256_of_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", 1700000000, r#"{"event":"alert.fired"}"#),
+            "ee09a56a801037692221a8d186b6bb8b918b960f86677f32c3085cc856e1f832"
+        );
+        assert_ne!(
+            sign("whsec_test", 1700000001, r#"{"event":"alert.fired"}"#),
+            sign("whsec_test", 1700000000, r#"{"event":"alert.fired"}"#)
+        );
+    }
+
+    #[test]
+    fn retries_back_off_until_max_attempts() {
+        assert_eq!(outcome(200, 1, 1000).0, "delivered");
+        assert_eq!(outcome(204, MAX_ATTEMPTS, 1000).0, "delivered");
+        assert_eq!(outcome(0, 1, 1000), ("pending", 1030));
+        assert_eq!(outcome(500, 2, 1000), ("pending", 1060));
+        assert_eq!(outcome(302, 3, 1000), ("pending", 1120));
+        assert_eq!(outcome(404, MAX_ATTEMPTS - 1, 1000).0, "pending");
+        assert_eq!(outcome(404, MAX_ATTEMPTS, 1000).0, "failed");
+    }
+}
@@ -1,11 +1,17 @@
 use std::error::Error;
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use serde_json;
 use mysql::{self, Pool, Value};
 use time;
 use utils;
+use provider::MarketDataProvider;
+use models::{Alert, AlertKind, SmsFactory, User};
+use notify::Message;
+use digest;
+use webhook;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coin {
     pub id: String,
     pub name: String,
@@ -28,46 +34,23 @@ pub struct Coin {
     pub no: i64,
 }
 
-impl Coin {
-    fn from_json(j: &serde_json::Value) -> Coin {
-        let usd_quote = &j["quotes"]["USD"];
-        let cny_quote = &j["quotes"]["CNY"];
-
-        Coin {
-            no: j["id"].as_i64().unwrap_or(0),
-            name: j["name"].as_str().unwrap().into(),
-            symbol: j["symbol"].as_str().unwrap().into(),
-            id: j["website_slug"].as_str().unwrap().into(),
-            rank: j["rank"].as_i64().unwrap(),
-            available_supply: j["circulating_supply"].as_f64().unwrap_or(0.),
-            total_supply: j["total_supply"].as_f64().unwrap_or(0.),
-            max_supply: j["max_supply"].as_f64().unwrap_or(0.),
-            price_btc: 0.,
-            price_usd: usd_quote["price"].as_f64().unwrap_or(0.),
-            volume_usd: usd_quote["volume_24h"].as_f64().unwrap_or(0.),
-            market_cap_usd: usd_quote["market_cap"].as_f64().unwrap_or(0.),
-            percent_change_1h: usd_quote["percent_change_1h"].as_f64().unwrap_or(0.),
-            percent_change_24h: usd_quote["percent_change_24h"].as_f64().unwrap_or(0.),
-            percent_change_7d: usd_quote["percent_change_7d"].as_f64().unwrap_or(0.),
-            price_cny: cny_quote["price"].as_f64().unwrap_or(0.),
-            volume_cny: cny_quote["volume_24h"].as_f64().unwrap_or(0.),
-            market_cap_cny: cny_quote["market_cap"].as_f64().unwrap_or(0.),
-            last_updated: j["last_updated"].as_i64().unwrap_or(0),
-        }
-    }
-}
+/// fiat currencies which values can be displayed in
+pub const CURRENCIES: [&'static str; 5] = ["USD", "CNY", "EUR", "JPY", "HKD"];
 
+#[derive(Clone)]
 pub struct State {
-    pub usd2cny_rate: f64,
+    /// {CURRENCY => UNITS OF 1 USD}
+    pub rates: HashMap<String, f64>,
     pub coins: Vec<Coin>,
 }
 
 impl State {
     pub fn init(mysql_pool: &Pool) -> State {
         let mut state = State {
-            usd2cny_rate: 0.0,
+            rates: HashMap::new(),
             coins: vec![],
         };
+        state.rates.insert("USD".to_string(), 1.0);
         let ret = mysql_pool
             .prep_exec("SELECT k,v FROM _cache WHERE k IN ('coins','rates')", ())
             .unwrap();
@@ -75,12 +58,12 @@ impl State {
             let (k, v): (String, String) = mysql::from_row(row.unwrap());
             let value: serde_json::Value = serde_json::from_str(v.as_str()).unwrap();
             match k.as_str() {
-                "coins" => for row in value.as_array().unwrap().iter() {
-                    let item = Coin::from_json(row);
-                    state.coins.push(item);
+                // the cache holds coins normalized by the market provider
+                "coins" => if let Ok(coins) = serde_json::from_value(value) {
+                    state.coins = coins;
                 },
                 "rates" => {
-                    state.usd2cny_rate = value["USD_CNY"]["val"].as_f64().unwrap();
+                    state.rates.extend(parse_rates(&value));
                 }
                 _ => (),
             }
@@ -88,45 +71,91 @@ impl State {
 
         state
     }
+
+    /// units of `currency` per 1 USD
+    pub fn rate(&self, currency: &str) -> Option<f64> {
+        self.rates.get(currency).cloned()
+    }
+
+    /// the top ranked coin with symbol, symbols are not unique among coins
+    pub fn coin_by_symbol(&self, symbol: &str) -> Option<&Coin> {
+        self.coins
+            .iter()
+            .filter(|x| x.symbol.eq_ignore_ascii_case(symbol))
+            .min_by_key(|x| if x.rank > 0 { x.rank } else { i64::max_value() })
+    }
+}
+
+/// parse {"USD_CNY": {"val": 6.8}, ...} into {CURRENCY => RATE}
+fn parse_rates(value: &serde_json::Value) -> HashMap<String, f64> {
+    let mut rates = HashMap::new();
+    for currency in CURRENCIES.iter() {
+        if let Some(rate) = value[format!("USD_{}", currency)]["val"].as_f64() {
+            rates.insert(currency.to_string(), rate);
+        }
+    }
+
+    rates
 }
 
 pub fn refresh_rates(pool: &Pool, lock: &Arc<RwLock<State>>) -> Result<(), Box<Error>> {
-    // fetch exchange rate
-    let value = utils::request_json("http://free.currencyconverterapi.com/api/v5/convert?q=USD_CNY&compact=y", None)?;
+    // fetch exchange rates, the free api accepts only 2 pairs per request
+    let pairs: Vec<String> = CURRENCIES
+        .iter()
+        .filter(|&&c| c != "USD")
+        .map(|c| format!("USD_{}", c))
+        .collect();
+    let mut value = json!({});
+    for chunk in pairs.chunks(2) {
+        let ret = utils::request_json(
+            &format!(
+                "http://free.currencyconverterapi.com/api/v5/convert?q={}&compact=y",
+                chunk.join(",")
+            ),
+            None,
+        )?;
+        for pair in chunk.iter() {
+            value[pair] = ret[pair].clone();
+        }
+    }
 
+    let rates = parse_rates(&value);
+    let now = time::get_time().sec;
+    // keep the rate of the day as a time series
+    let day = now / 86400 * 86400;
+    for (currency, rate) in rates.iter() {
+        pool.prep_exec(
+            "REPLACE INTO rates (currency,day,rate) VALUES (?,?,?)",
+            (currency, day, rate),
+        )?;
+    }
     {
         let mut state = lock.write().unwrap();
-        (*state).usd2cny_rate = value["USD_CNY"]["val"].as_f64().unwrap();
+        (*state).rates.extend(rates);
     }
     pool.prep_exec(
         "REPLACE INTO _cache (k,v,created) VALUES (?,?,?)",
-        ("rates", value.to_string(), time::get_time().sec),
+        ("rates", value.to_string(), now),
     )?;
 
     Ok(())
 }
 
-pub fn refresh_coins(pool: &Pool, lock: &Arc<RwLock<State>>) -> Result<(), Box<Error>> {
-    let mut start = 1;
-    let limit = 100;
-    let mut value = Vec::<serde_json::Value>::new();
-    loop {
-        let url = format!("https://api.coinmarketcap.com/v2/ticker/?convert=CNY&start={}&limit={}&sort=id&structure=array", start, limit);
-        let ret = utils::request_json(&url, None)?;
-        if ret["data"] == serde_json::Value::Null {
-            break;
-        }
-        value.extend(ret["data"].as_array().unwrap().iter().cloned());
-        start = start + limit;
+pub fn refresh_coins(
+    pool: &Pool,
+    lock: &Arc<RwLock<State>>,
+    provider: &MarketDataProvider,
+) -> Result<(), Box<Error>> {
+    let data = provider.tickers()?;
+    if data.is_empty() {
+        return Ok(());
     }
 
     let mut sql_string = String::from(
         "INSERT INTO coins (id,name,symbol,rank,available_supply,total_supply,max_supply,no) VALUES ",
     );
-    let mut data: Vec<Coin> = vec![];
     let mut params = vec![];
-    for row in value.iter() {
-        let item = Coin::from_json(row);
+    for item in data.iter() {
         sql_string.push_str("(?,?,?,?,?,?,?,?),");
         // Vec store only similar type, so we wrap the raw type with mysql Value
         params.push(Value::from(item.id.clone()));
@@ -137,7 +166,6 @@ pub fn refresh_coins(pool: &Pool, lock: &Arc<RwLock<State>>) -> Result<(), Box<E
         params.push(Value::from(item.total_supply));
         params.push(Value::from(item.max_supply));
         params.push(Value::from(item.no));
-        data.push(item);
     }
     sql_string.pop();
     sql_string.push_str(
@@ -153,19 +181,150 @@ pub fn refresh_coins(pool: &Pool, lock: &Arc<RwLock<State>>) -> Result<(), Box<E
     );
 
     pool.prep_exec(sql_string, params)?;
+    let count = data.len();
+    let cache = serde_json::to_string(&data)?;
     {
         let mut state = lock.write().unwrap();
         (*state).coins = data;
     }
     pool.prep_exec(
         "REPLACE INTO _cache (k,v,created) VALUES (?,?,?)",
-        ("coins", json!(value).to_string(), time::get_time().sec),
+        ("coins", cache, time::get_time().sec),
     )?;
+    webhook::emit(
+        pool,
+        None,
+        webhook::COINS_REFRESHED,
+        json!({
+            "count": count,
+        }),
+    ).map_err(|e| format!("{:?}", e))?;
 
     Ok(())
 }
 
-pub fn refresh_prices(pool: &Pool) -> Result<u64, Box<Error>> {
+/// evaluate alert rules against the refreshed coins, and notify users of the ones fired
+pub fn check_alerts(
+    pool: &Pool,
+    lock: &Arc<RwLock<State>>,
+    sms_fac: &SmsFactory,
+) -> Result<(), Box<Error>> {
+    // a copy of prices, so the refreshing workers are not blocked by the queries of rules
+    let state = lock.read().unwrap().clone();
+    if state.coins.is_empty() {
+        return Ok(());
+    }
+    let now = time::get_time().sec;
+    let mut users = HashMap::<i64, User>::new();
+    for mut alert in Alert::find(pool, None).map_err(|e| format!("{:?}", e))? {
+        if !users.contains_key(&alert.user_id) {
+            match User::find(pool, Some(alert.user_id), None) {
+                Ok(user) => users.insert(alert.user_id, user),
+                Err(_) => continue,
+            };
+        }
+        let user = &users[&alert.user_id];
+        let rate = match state.rate(&alert.currency) {
+            Some(rate) => rate,
+            None => continue,
+        };
+        let (subject, value) = if alert.kind.on_coin() {
+            let coin = match state.coins.iter().find(|x| x.id == alert.coin_id) {
+                Some(coin) => coin,
+                None => continue,
+            };
+            let value = match alert.kind {
+                AlertKind::ChangeAbove | AlertKind::ChangeBelow => coin.percent_change_24h,
+                _ => coin.price_usd * rate,
+            };
+            (coin.symbol.clone(), value)
+        } else {
+            // a portfolio may be deleted or unshared since the rule was created, then the rule
+            // stays disarmed until the user can see the portfolio again
+            if let Some(portfolio_id) = alert.portfolio_id {
+                if user.check_portfolio(pool, portfolio_id).is_err() {
+                    if alert.armed {
+                        alert.disarm(pool).map_err(|e| format!("{:?}", e))?;
+                    }
+                    continue;
+                }
+            }
+            let value = match user.portfolio_value(pool, &state, alert.portfolio_id) {
+                Ok(value) => value * rate,
+                Err(_) => continue,
+            };
+            (String::new(), value)
+        };
+
+        if !alert.hit(value) {
+            if !alert.armed {
+                alert.rearm(pool).map_err(|e| format!("{:?}", e))?;
+            }
+        } else if alert.should_fire(value, now) {
+            info!("Alert {} fired for user {}: {}", alert.id, user.id, value);
+            sms_fac
+                .send(
+                    user.recipients(),
+                    Message::Alert {
+                        name: user.name.clone(),
+                        rule: alert.describe(&subject),
+                    },
+                )
+                .map_err(|e| format!("{:?}", e))?;
+            alert.fired(pool, now).map_err(|e| format!("{:?}", e))?;
+            webhook::emit(
+                pool,
+                Some(user.id),
+                webhook::ALERT_FIRED,
+                json!({
+                    "alert": alert.to_json(),
+                    "value": value,
+                }),
+            ).map_err(|e| format!("{:?}", e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// send digests which are due, the worker runs every hour
+pub fn send_digests(
+    pool: &Pool,
+    lock: &Arc<RwLock<State>>,
+    sms_fac: &SmsFactory,
+) -> Result<(), Box<Error>> {
+    // a copy of prices, so the refreshing workers are not blocked while digests are built
+    let state = lock.read().unwrap().clone();
+    if state.coins.is_empty() {
+        return Ok(());
+    }
+    let now = time::get_time().sec;
+    for (user_id, period) in User::digest_due(pool, now).map_err(|e| format!("{:?}", e))? {
+        let user = match User::find(pool, Some(user_id), None) {
+            Ok(user) => user,
+            Err(_) => continue,
+        };
+        match digest::build(pool, &user, &state, period) {
+            Ok(Some(digest)) => {
+                // left due, so it's retried in the next run
+                if let Err(e) = sms_fac.send(user.recipients(), digest.to_message(&user)) {
+                    warn!("Error while sending digest of user {}: {:?}", user.id, e);
+                    continue;
+                }
+            }
+            Ok(None) => (),
+            Err(e) => {
+                error!("Error while building digest of user {}: {:?}", user.id, e);
+                continue;
+            }
+        }
+        user.digest_sent(pool, now).map_err(|e| format!("{:?}", e))?;
+    }
+
+    Ok(())
+}
+
+pub fn refresh_prices(pool: &Pool, provider: &MarketDataProvider) -> Result<u64, Box<Error>> {
     // fetch the specific coin historical price
     let mut result = pool.prep_exec(
         "SELECT t1.id,t1.last_updated,t2.max_updated \
@@ -185,17 +344,9 @@ pub fn refresh_prices(pool: &Pool) -> Result<u64, Box<Error>> {
     let now = time::get_time().sec;
     // the interval between requests should be large than 7 seconds
     if now - max_updated >= 7 {
-        println!("Fetching {} between {} and {}", id, last_updated, now);
-        let start = last_updated * 1000;
-        let end = now * 1000;
+        info!("Fetching {} between {} and {}", id, last_updated, now);
         // only fetch the historical data since last fetching
-        let json = match utils::request_json(
-            &format!(
-                "https://graphs2.coinmarketcap.com/currencies/{}/{}/{}/", // graphs.coinmarketcap.com
-                id, start, end
-            ),
-            None,
-        ) {
+        let points = match provider.history(&id, last_updated, now) {
             Ok(v) => v,
             Err(e) => {
                 // request failed, skip for next cycle
@@ -210,28 +361,17 @@ pub fn refresh_prices(pool: &Pool) -> Result<u64, Box<Error>> {
         );
         let mut params = vec![];
 
-        for (idx, price_usd) in json["price_usd"].as_array().unwrap().iter().enumerate() {
+        for point in points.iter() {
             sql_string.push_str("(?,?,?,?,?,?),");
-
-            let ts = price_usd[0].as_u64().unwrap() / 1000;
-            let p_usd = price_usd[1].as_f64().unwrap();
-            if p_usd == 0f64 {
-                continue;
-            }
-            let p_btc = json["price_btc"][idx][1].as_f64().unwrap_or(0.0);
-            let v_usd = json["volume_usd"][idx][1].as_f64().unwrap_or(0.0);
-            let p_platform = if json["price_platform"].is_array() {
-                Value::from(json["price_platform"][idx][1].as_f64().unwrap_or(0.0))
-            } else {
-                Value::NULL
-            };
-
             params.push(Value::from(&id));
-            params.push(Value::from(p_usd));
-            params.push(Value::from(v_usd));
-            params.push(Value::from(p_btc));
-            params.push(p_platform);
-            params.push(Value::from(ts));
+            params.push(Value::from(point.price_usd));
+            params.push(Value::from(point.volume_usd));
+            params.push(Value::from(point.price_btc));
+            params.push(match point.price_platform {
+                Some(p) => Value::from(p),
+                None => Value::NULL,
+            });
+            params.push(Value::from(point.created));
         }
         sql_string.pop();
 
@@ -251,6 +391,6 @@ pub fn refresh_prices(pool: &Pool) -> Result<u64, Box<Error>> {
     if sleep_secs < 0 {
         sleep_secs = 0;
     }
-    println!("Waiting {} secs more", sleep_secs);
+    debug!("Waiting {} secs more", sleep_secs);
     Ok(sleep_secs as u64)
 }